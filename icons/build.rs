@@ -21,6 +21,14 @@ const ICONS: &[(&str, &str)] = &[
     ("door", "delapouite/door.svg"),
     ("zombie", "delapouite/shambling-zombie.svg"),
     ("shop", "delapouite/shop.svg"),
+    ("torch", "lorc/torch.svg"),
+    ("lantern", "lorc/lantern-flame.svg"),
+    ("bowman", "delapouite/bowman.svg"),
+    ("wizard", "delapouite/wizard-face.svg"),
+    ("bow", "lorc/pocket-bow.svg"),
+    ("sling", "lorc/sling.svg"),
+    ("arrows", "lorc/quiver.svg"),
+    ("stones", "delapouite/stone-pile.svg"),
 ];
 
 #[derive(Deserialize)]
@@ -112,6 +112,32 @@ struct StuffDescription {
     aoe: Option<u32>,
     value: Option<u32>,
     slow: Option<u16>,
+    light: Option<i32>,
+    ammo: Option<u32>,
+    resistant: Option<String>,
+    vulnerable: Option<String>,
+    immune: Option<String>,
+    evasion: Option<i32>,
+    armor_penetration: Option<i32>,
+    crit_range: Option<i32>,
+    crit_multiplier: Option<i32>,
+    faction: Option<String>,
+    ai_state: Option<String>,
+    flee_hp: Option<i32>,
+    stealth: Option<i32>,
+    regen: Option<i32>,
+}
+
+/// comma separated list of damage types
+fn damage_types(list: Option<&str>) -> String {
+    let types = list
+        .into_iter()
+        .flat_map(|l| l.split(','))
+        .map(|ty| ty.trim())
+        .filter(|ty| !ty.is_empty())
+        .map(|ty| format!("DamageType::{ty}"))
+        .join(", ");
+    format!("&[{types}]")
 }
 
 fn optional_stuff<T>(
@@ -145,6 +171,8 @@ fn stuff_descriptors(sheet: calamine::Range<calamine::Data>) -> String {
         assert!(!tags.contains_key(&row.tag), "Duplicate tag: {}", row.tag);
         tags.insert(row.tag, i);
         writeln!(body, "icon: Icon(\"{}\"),", row.icon).unwrap();
+        // lights are tinted by the color of their source
+        let light_color = row.color.clone().unwrap_or_else(|| "white".to_owned());
         optional_stuff(
             "name",
             |x| format!("Name(\"{}\".to_string())", x),
@@ -179,14 +207,14 @@ fn stuff_descriptors(sheet: calamine::Range<calamine::Data>) -> String {
         );
         optional_stuff(
             "defense",
-            |x| format!("Defense::new({})", x),
-            row.defense,
+            |(d, e)| format!("Defense::new({d}, {e})"),
+            row.defense.map(|d| (d, row.evasion.unwrap_or(0))),
             &mut body,
         );
         optional_stuff("heal", |x| format!("Heal::new({})", x), row.heal, &mut body);
         optional_stuff(
             "ranged",
-            |(p, s, r)| format!("Ranged{{ power: {}, skill: {}, range: {} }}", p, s, r),
+            |(p, r, s)| format!("Ranged{{ power: {}, skill: {}, range: {} }}", p, s, r),
             row.ranged_power.and_then(|p| {
                 row.ranged_range
                     .and_then(|r| row.ranged_skill.map(|s| (r, s)))
@@ -202,6 +230,73 @@ fn stuff_descriptors(sheet: calamine::Range<calamine::Data>) -> String {
         );
         optional_stuff("value", |x| format!("CoinValue({x})"), row.value, &mut body);
         optional_stuff("slow", |x| format!("Slow({x})"), row.slow, &mut body);
+        optional_stuff(
+            "light",
+            |x| format!("LightSource{{ radius: {x}, color: \"{light_color}\" }}"),
+            row.light,
+            &mut body,
+        );
+        optional_stuff(
+            "ammo",
+            |x| format!("Ammo{{ count: {x} }}"),
+            row.ammo,
+            &mut body,
+        );
+        let has_resistances =
+            row.resistant.is_some() || row.vulnerable.is_some() || row.immune.is_some();
+        optional_stuff(
+            "resistances",
+            |_| {
+                format!(
+                    "Resistances {{ resistant: {}, vulnerable: {}, immune: {} }}",
+                    damage_types(row.resistant.as_deref()),
+                    damage_types(row.vulnerable.as_deref()),
+                    damage_types(row.immune.as_deref()),
+                )
+            },
+            has_resistances.then_some(()),
+            &mut body,
+        );
+        let mut mods = String::new();
+        if let Some(x) = row.armor_penetration {
+            write!(mods, "armor_penetration: {x},").unwrap();
+        }
+        if let Some(x) = row.crit_range {
+            write!(mods, "crit_range: {x},").unwrap();
+        }
+        if let Some(x) = row.crit_multiplier {
+            write!(mods, "crit_multiplier: {x},").unwrap();
+        }
+        optional_stuff(
+            "attack_mods",
+            |m| format!("AttackMods {{ {m} ..AttackMods::DEFAULT }}"),
+            (!mods.is_empty()).then_some(mods),
+            &mut body,
+        );
+        optional_stuff(
+            "faction",
+            |x| format!("Faction::{x}"),
+            row.faction,
+            &mut body,
+        );
+        optional_stuff(
+            "ai_behaviour",
+            |x| {
+                format!(
+                    "AiBehaviour {{ initial: AiState::{x}, flee_hp: {} }}",
+                    row.flee_hp.unwrap_or(0)
+                )
+            },
+            row.ai_state,
+            &mut body,
+        );
+        optional_stuff(
+            "stealth",
+            |x| format!("Stealth({x})"),
+            row.stealth,
+            &mut body,
+        );
+        optional_stuff("regen", |x| format!("Regen({x})"), row.regen, &mut body);
         writeln!(body, "}}),").unwrap();
     }
 
@@ -229,8 +324,148 @@ pub enum StuffTag {{
     payload
 }
 
+#[derive(Debug)]
+struct PrefabDesc {
+    name: String,
+    level: u32,
+    weight: i32,
+    legend: HashMap<char, String>,
+    rows: Vec<String>,
+}
+
+impl PrefabDesc {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            level: 1,
+            weight: 10,
+            legend: HashMap::new(),
+            rows: Vec::new(),
+        }
+    }
+
+    fn cell(&self, glyph: char) -> String {
+        match glyph {
+            '#' => "PrefabCell::Stuff(StuffTag::Wall)".to_owned(),
+            '+' => "PrefabCell::Stuff(StuffTag::Door)".to_owned(),
+            '.' => "PrefabCell::Floor".to_owned(),
+            'E' => "PrefabCell::Enemy".to_owned(),
+            'I' => "PrefabCell::Item".to_owned(),
+            c => match self.legend.get(&c) {
+                Some(tag) => format!("PrefabCell::Stuff(StuffTag::{tag})"),
+                None => panic!("Prefab {}: unknown glyph {c:?}", self.name),
+            },
+        }
+    }
+
+    fn is_solid(&self, glyph: char) -> bool {
+        glyph == '#' || self.legend.get(&glyph).is_some_and(|tag| tag == "Wall")
+    }
+
+    /// every walkable cell must be reachable from the edge of the prefab
+    fn validate(&self) {
+        let height = self.rows.len();
+        assert!(height > 0, "Prefab {} has no layout", self.name);
+        let width = self.rows[0].chars().count();
+        let cells = self
+            .rows
+            .iter()
+            .map(|row| row.chars().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert!(
+            cells.iter().all(|row| row.len() == width),
+            "Prefab {}: all rows must have the same length",
+            self.name
+        );
+        let mut reached = vec![vec![false; width]; height];
+        let mut todo = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                let edge = x == 0 || y == 0 || x == width - 1 || y == height - 1;
+                if edge && !self.is_solid(cells[y][x]) {
+                    reached[y][x] = true;
+                    todo.push((x, y));
+                }
+            }
+        }
+        while let Some((x, y)) = todo.pop() {
+            for (nx, ny) in [
+                (x.wrapping_sub(1), y),
+                (x + 1, y),
+                (x, y.wrapping_sub(1)),
+                (x, y + 1),
+            ] {
+                if nx < width && ny < height && !reached[ny][nx] && !self.is_solid(cells[ny][nx]) {
+                    reached[ny][nx] = true;
+                    todo.push((nx, ny));
+                }
+            }
+        }
+        for y in 0..height {
+            for x in 0..width {
+                assert!(
+                    reached[y][x] || self.is_solid(cells[y][x]),
+                    "Prefab {}: cell ({x}, {y}) is not reachable from the edge",
+                    self.name
+                );
+            }
+        }
+    }
+}
+
+fn read_prefabs(source: &str) -> String {
+    let mut prefabs = Vec::<PrefabDesc>::new();
+    for (i, line) in source.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with("//") {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            prefabs.push(PrefabDesc::new(name));
+            continue;
+        }
+        let prefab = prefabs
+            .last_mut()
+            .unwrap_or_else(|| panic!("prefabs.txt:{}: expected a [name] header", i + 1));
+        match line.split_once(" = ") {
+            Some(("level", value)) => prefab.level = value.parse().expect("Invalid level"),
+            Some(("weight", value)) => prefab.weight = value.parse().expect("Invalid weight"),
+            Some((glyph, tag)) if glyph.chars().count() == 1 => {
+                prefab
+                    .legend
+                    .insert(glyph.chars().next().unwrap(), tag.to_owned());
+            }
+            Some((key, _)) => panic!("prefabs.txt:{}: unknown option {key}", i + 1),
+            None => prefab.rows.push(line.to_owned()),
+        }
+    }
+
+    let mut payload = "pub const PREFABS: &[Prefab] = &[\n".to_owned();
+    let pl = &mut payload;
+    for prefab in &prefabs {
+        prefab.validate();
+        writeln!(
+            pl,
+            "Prefab {{ name: \"{}\", level: {}, weight: {}, width: {}, height: {}, cells: &[",
+            prefab.name,
+            prefab.level,
+            prefab.weight,
+            prefab.rows[0].chars().count(),
+            prefab.rows.len(),
+        )
+        .unwrap();
+        for glyph in prefab.rows.iter().flat_map(|row| row.chars()) {
+            writeln!(pl, "{},", prefab.cell(glyph)).unwrap();
+        }
+        writeln!(pl, "]}},").unwrap();
+    }
+    writeln!(pl, "];").unwrap();
+    payload
+}
+
 fn main() {
     println!("cargo:rerun-if-changed=assets/game-config.xlsx");
+    println!("cargo:rerun-if-changed=assets/prefabs.txt");
 
     let xls_file = env::var("CARGO_MANIFEST_DIR").unwrap();
     let xls_file = Path::new(&xls_file).join("assets/game-config.xlsx");
@@ -259,7 +494,11 @@ fn main() {
 
     let stuff = stuff_descriptors(stuff);
 
-    let payload = format!("{enemy_weights}\n{item_weights}\n{stuff}\n{room_weights}");
+    let prefabs = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("assets/prefabs.txt");
+    let prefabs = fs::read_to_string(prefabs).expect("Failed to read prefabs");
+    let prefabs = read_prefabs(&prefabs);
+
+    let payload = format!("{enemy_weights}\n{item_weights}\n{stuff}\n{room_weights}\n{prefabs}");
 
     fs::write(out_root.join("game_config_gen.rs"), payload).unwrap();
 }
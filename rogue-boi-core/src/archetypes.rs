@@ -1,12 +1,13 @@
 use cecs::prelude::*;
-use serde::Serialize;
 use serde_json::json;
-use wasm_bindgen::JsValue;
 
 use crate::{
     Stuff,
     components::*,
-    game_config::{insert_default_components, insert_default_transient_components},
+    game_config::{
+        get_attack_mods, get_faction, get_stealth, insert_default_components,
+        insert_default_transient_components,
+    },
     grid::Grid,
     math::Vec2,
 };
@@ -42,16 +43,17 @@ pub fn register_persistent_components(
         .with_component::<Heal>()
         .with_component::<Ranged>()
         .with_component::<Aoe>()
-        .with_component::<ConfusedAi>()
         .with_component::<Level>()
         .with_component::<Exp>()
         .with_component::<Equipment>()
         .with_component::<Defense>()
-        .with_component::<Poisoned>()
+        .with_component::<StatusEffects>()
         .with_component::<CoinValue>()
         .with_component::<CoinPouch>()
         .with_component::<Shop>()
         .with_component::<Slow>()
+        .with_component::<Ammo>()
+        .with_component::<AiState>()
 }
 
 fn insert_transient_components_for_entity(cmd: &mut cecs::commands::EntityCommands, tag: StuffTag) {
@@ -69,6 +71,10 @@ fn insert_transient_components_for_entity(cmd: &mut cecs::commands::EntityComman
         StuffTag::Door => {
             cmd.insert_bundle((StaticStuff, Opaque, StaticVisibility));
         }
+        StuffTag::Torch => {
+            // mounted on the wall
+            cmd.insert_bundle((StaticStuff, Opaque, StaticVisibility));
+        }
         StuffTag::Tombstone => {
             cmd.insert_bundle((StaticStuff,));
         }
@@ -82,7 +88,9 @@ fn insert_transient_components_for_entity(cmd: &mut cecs::commands::EntityComman
         | StuffTag::Warlord
         | StuffTag::Goblin
         | StuffTag::Zombie
-        | StuffTag::Minotaur => {
+        | StuffTag::Minotaur
+        | StuffTag::GoblinArcher
+        | StuffTag::Shaman => {
             cmd.insert_bundle((Ai, PathCache::default(), Velocity::default()));
         }
         StuffTag::LeatherArmor | StuffTag::ChainMailArmor => {
@@ -91,7 +99,27 @@ fn insert_transient_components_for_entity(cmd: &mut cecs::commands::EntityComman
         StuffTag::Sword | StuffTag::RareDagger | StuffTag::RareSword | StuffTag::Dagger => {
             cmd.insert_bundle((Item, EquipmentType::Weapon, StaticVisibility));
         }
-        StuffTag::HpPotion => {
+        StuffTag::Bow => {
+            cmd.insert_bundle((
+                Item,
+                EquipmentType::Ranged,
+                StaticVisibility,
+                Launcher {
+                    ammo: StuffTag::Arrows,
+                },
+            ));
+        }
+        StuffTag::Sling => {
+            cmd.insert_bundle((
+                Item,
+                EquipmentType::Ranged,
+                StaticVisibility,
+                Launcher {
+                    ammo: StuffTag::SlingStones,
+                },
+            ));
+        }
+        StuffTag::HpPotion | StuffTag::Lantern | StuffTag::Arrows | StuffTag::SlingStones => {
             cmd.insert_bundle((Item, StaticVisibility));
         }
         StuffTag::FireBallScroll => {
@@ -138,6 +166,7 @@ pub fn init_entity<'a>(
     match tag {
         StuffTag::Stairs => {}
         StuffTag::Tombstone => {}
+        StuffTag::Torch => {}
         StuffTag::Shop => {
             cmd.insert_bundle((Shop::new(8),));
         }
@@ -157,13 +186,17 @@ pub fn init_entity<'a>(
         StuffTag::Door => {
             cmd.insert_bundle((icon("door"), Color("#d4dfd7".into()), StaticStuff));
         }
-        StuffTag::Gargoyle | StuffTag::Goblin | StuffTag::Troll | StuffTag::Orc => {
+        StuffTag::Gargoyle
+        | StuffTag::Goblin
+        | StuffTag::GoblinArcher
+        | StuffTag::Troll
+        | StuffTag::Orc => {
             cmd.insert_bundle((Leash {
                 origin: pos,
                 radius: 20,
             },));
         }
-        StuffTag::Zombie | StuffTag::Warlord | StuffTag::Minotaur => {
+        StuffTag::Zombie | StuffTag::Warlord | StuffTag::Minotaur | StuffTag::Shaman => {
             cmd.insert_bundle((Leash {
                 origin: pos,
                 radius: 40,
@@ -181,12 +214,17 @@ pub fn init_entity<'a>(
         | StuffTag::ConfusionScroll
         | StuffTag::WardScroll
         | StuffTag::SlowScroll
+        | StuffTag::Lantern
+        | StuffTag::Bow
+        | StuffTag::Sling
+        | StuffTag::Arrows
+        | StuffTag::SlingStones
         | StuffTag::FireBallScroll => {}
     }
     cmd
 }
 
-pub type StuffToJsQuery<'a> = QuerySet<(
+pub type StuffToJsonQuery<'a> = QuerySet<(
     // q0
     Query<'a, (&'a Icon, Option<&'a Color>)>,
     // q1
@@ -221,11 +259,24 @@ pub type StuffToJsQuery<'a> = QuerySet<(
             Option<&'a Color>,
             Option<&'a Defense>,
             Option<&'a CoinValue>,
+            Option<&'a StatusEffects>,
+            Option<&'a Resistances>,
+            Option<&'a AiState>,
         ),
         With<Ai>,
     >,
     // q3
-    Query<'a, (&'a Icon, &'a Melee, &'a Hp, &'a Defense), With<PlayerTag>>,
+    Query<
+        'a,
+        (
+            &'a Icon,
+            &'a Melee,
+            &'a Hp,
+            &'a Defense,
+            Option<&'a StatusEffects>,
+        ),
+        With<PlayerTag>,
+    >,
     // q4
     Query<'a, (&'a Icon, Option<&'a Name>, Option<&'a Description>)>,
     // q5
@@ -245,9 +296,25 @@ pub type StuffToJsQuery<'a> = QuerySet<(
     crate::ItemPropsQ<'a>,
 )>;
 
-pub fn stuff_to_js(id: EntityId, tag: StuffTag, query: &StuffToJsQuery) -> JsValue {
-    let payload = match tag {
-        StuffTag::Door | StuffTag::Stairs | StuffTag::Tombstone => {
+/// Active effects with their remaining turns
+fn status_effects_json(effects: Option<&StatusEffects>) -> serde_json::Value {
+    effects
+        .into_iter()
+        .flat_map(|effects| effects.iter())
+        .map(|effect| {
+            json! {{
+                "kind": effect.kind,
+                "name": effect.kind.desc().name,
+                "duration": effect.duration,
+                "power": effect.power,
+            }}
+        })
+        .collect()
+}
+
+pub fn stuff_to_json(id: EntityId, tag: StuffTag, query: &StuffToJsonQuery) -> serde_json::Value {
+    match tag {
+        StuffTag::Door | StuffTag::Stairs | StuffTag::Tombstone | StuffTag::Torch => {
             let q = query.q4();
             let (icon, name, desc) = q.fetch(id).unwrap();
             json! {{
@@ -260,7 +327,7 @@ pub fn stuff_to_js(id: EntityId, tag: StuffTag, query: &StuffToJsQuery) -> JsVal
         }
         StuffTag::Player => {
             let q = query.q3();
-            let (icon, melee, hp, defense) = q.fetch(id).unwrap();
+            let (icon, melee, hp, defense, effects) = q.fetch(id).unwrap();
             json! {{
                 "id": id,
                 "tag": tag,
@@ -269,7 +336,8 @@ pub fn stuff_to_js(id: EntityId, tag: StuffTag, query: &StuffToJsQuery) -> JsVal
                 "icon": icon.0,
                 "hp": hp,
                 "melee": melee,
-                "defense": defense
+                "defense": defense,
+                "statusEffects": status_effects_json(effects),
             }}
         }
         StuffTag::Wall => {
@@ -310,10 +378,24 @@ pub fn stuff_to_js(id: EntityId, tag: StuffTag, query: &StuffToJsQuery) -> JsVal
         | StuffTag::Orc
         | StuffTag::Warlord
         | StuffTag::Zombie
-        | StuffTag::Minotaur => {
+        | StuffTag::Minotaur
+        | StuffTag::GoblinArcher
+        | StuffTag::Shaman => {
             let q = query.q2();
-            let (icon, name, ranged, melee, hp, description, color, defense, value) =
-                q.fetch(id).unwrap();
+            let (
+                icon,
+                name,
+                ranged,
+                melee,
+                hp,
+                description,
+                color,
+                defense,
+                value,
+                effects,
+                resistances,
+                ai_state,
+            ) = q.fetch(id).unwrap();
             json! {{
                 "id": id,
                 "name": name.0,
@@ -328,6 +410,11 @@ pub fn stuff_to_js(id: EntityId, tag: StuffTag, query: &StuffToJsQuery) -> JsVal
                 "creature": true,
                 "defense": defense,
                 "value": value,
+                "statusEffects": status_effects_json(effects),
+                "resistances": resistances,
+                "attackMods": get_attack_mods(tag),
+                "faction": get_faction(tag),
+                "aiState": ai_state,
             }}
         }
         StuffTag::HpPotion
@@ -342,6 +429,11 @@ pub fn stuff_to_js(id: EntityId, tag: StuffTag, query: &StuffToJsQuery) -> JsVal
         | StuffTag::PoisonScroll
         | StuffTag::WardScroll
         | StuffTag::SlowScroll
+        | StuffTag::Lantern
+        | StuffTag::Bow
+        | StuffTag::Sling
+        | StuffTag::Arrows
+        | StuffTag::SlingStones
         | StuffTag::FireBallScroll => {
             let q = query.q1();
             let (icon, name, desc, ranged, heal, melee, pos, color, defense, eq_ty, value, slow) =
@@ -374,9 +466,9 @@ pub fn stuff_to_js(id: EntityId, tag: StuffTag, query: &StuffToJsQuery) -> JsVal
                 "defense": defense,
                 "value": value,
                 "slow": slow,
+                "attackMods": get_attack_mods(tag),
+                "stealth": get_stealth(tag),
             }}
         }
-    };
-    let serializer = serde_wasm_bindgen::Serializer::json_compatible();
-    payload.serialize(&serializer).unwrap()
+    }
 }
@@ -0,0 +1,118 @@
+//! Attack rolls and damage resolution
+//!
+//! An attack rolls a d20, every point of skill above the defender's evasion adds [SKILL_STEP] to
+//! the roll, the attack hits if the total reaches [DIE]. A natural 1 always misses, a natural 20
+//! always hits. High rolls are critical hits, weapons may widen the critical range.
+use rand::RngExt;
+use tracing::debug;
+
+use crate::components::{AttackMods, DamageType, Defense, Resistances};
+
+pub const DIE: i32 = 20;
+/// Roll bonus of a point of skill over the defender's evasion
+pub const SKILL_STEP: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RollOutcome {
+    Miss,
+    Hit,
+    Critical,
+}
+
+impl RollOutcome {
+    pub fn is_hit(self) -> bool {
+        !matches!(self, RollOutcome::Miss)
+    }
+
+    /// Appended to combat log lines
+    pub fn log_suffix(self) -> &'static str {
+        match self {
+            RollOutcome::Critical => " Critical hit!",
+            _ => "",
+        }
+    }
+}
+
+pub fn attack_roll(
+    rng: &mut impl RngExt,
+    skill: i32,
+    evasion: i32,
+    mods: Option<&AttackMods>,
+) -> RollOutcome {
+    let roll = rng.random_range(1..=DIE);
+    let crit_range = 1 + mods.map(|m| m.crit_range).unwrap_or(0);
+    let hit = roll == DIE || (roll > 1 && roll + SKILL_STEP * (skill - evasion) >= DIE);
+    let outcome = if !hit {
+        RollOutcome::Miss
+    } else if roll > DIE - crit_range {
+        RollOutcome::Critical
+    } else {
+        RollOutcome::Hit
+    };
+    debug!(roll, skill, evasion, ?outcome, "attack_roll");
+    outcome
+}
+
+/// Unopposed check, e.g. for spells
+pub fn skill_check(rng: &mut impl RngExt, skill: i32) -> bool {
+    attack_roll(rng, skill, 0, None).is_hit()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Attack {
+    pub power: i32,
+    pub ty: DamageType,
+    pub mods: AttackMods,
+}
+
+impl Attack {
+    pub fn new(power: i32, ty: DamageType) -> Self {
+        Self {
+            power,
+            ty,
+            mods: AttackMods::DEFAULT,
+        }
+    }
+
+    pub fn physical(power: i32) -> Self {
+        Self::new(power, DamageType::Physical)
+    }
+
+    pub fn with_mods(mut self, mods: Option<&AttackMods>) -> Self {
+        if let Some(mods) = mods {
+            self.mods = *mods;
+        }
+        self
+    }
+}
+
+/// Damage of a hit after wards, armor, criticals and resistances
+///
+/// Armor only protects against physical damage
+pub fn compute_damage(
+    attack: &Attack,
+    outcome: RollOutcome,
+    defense: Option<&mut Defense>,
+    resistances: Option<&Resistances>,
+) -> i32 {
+    debug!(?attack, ?outcome, ?defense, ?resistances, "compute_damage");
+    let mut damage = attack.power;
+    if let Some(defense) = defense {
+        if defense.ward > 0 {
+            defense.ward -= 1;
+            return 0;
+        }
+        if attack.ty == DamageType::Physical {
+            let armor = (defense.melee_defense - attack.mods.armor_penetration).max(0);
+            // all damage must be at least 1
+            damage = (damage - armor).max(1);
+        }
+    }
+    if outcome == RollOutcome::Critical {
+        damage *= attack.mods.crit_multiplier;
+    }
+    match resistances {
+        Some(resistances) => resistances.apply(attack.ty, damage),
+        None => damage,
+    }
+}
@@ -1,17 +1,23 @@
 //! Implementation note: if a component is persisted, and stores an ID to another entity, make sure
-//! it's remapped when loading! See [[Core::load]]
+//! it's remapped when loading! See [[GameSession::load]]
 //!
 use std::collections::VecDeque;
 
-use crate::{HashMap, Stuff, grid::Grid, math::Vec2};
+use crate::{
+    HashSet, Stuff,
+    grid::Grid,
+    math::Vec2,
+    pathfinder::{DijkstraMap, Path},
+};
 use cecs::entity_id::EntityId;
+use rand::{RngExt, SeedableRng, rngs::Xoshiro256PlusPlus};
 use serde_derive::{Deserialize, Serialize};
 use smallvec::SmallVec;
 use tracing::debug;
-use wasm_bindgen::JsValue;
 
 // reexport generated tags
 pub use crate::game_config::StuffTag;
+pub use crate::status_effects::{StatusEffect, StatusEffects, StatusKind, apply_status_effect};
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Pos(pub Vec2);
@@ -130,17 +136,174 @@ pub struct Ranged {
     pub skill: i32,
 }
 
+impl std::ops::AddAssign for Ranged {
+    fn add_assign(&mut self, rhs: Self) {
+        self.power += rhs.power;
+        self.range += rhs.range;
+        self.skill += rhs.skill;
+    }
+}
+
+impl std::ops::SubAssign for Ranged {
+    fn sub_assign(&mut self, rhs: Self) {
+        self.power -= rhs.power;
+        self.range -= rhs.range;
+        self.skill -= rhs.skill;
+    }
+}
+
+/// Stack of ammunition, e.g. arrows
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Ammo {
+    pub count: u32,
+}
+
+/// Ranged weapon that needs ammunition of the given kind to fire
+#[derive(Debug, Clone, Copy)]
+pub struct Launcher {
+    pub ammo: StuffTag,
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct PathCache {
     pub path: SmallVec<[Vec2; 16]>,
 }
 
+/// Shortens the range monsters notice the creature from, loaded from the config
+///
+/// Equipped items add their stealth to their wearer's
+#[derive(Debug, Clone, Copy)]
+pub struct Stealth(pub i32);
+
+/// Hundredths of a hp regained every turn, loaded from the config
+///
+/// Equipped items add their regen to their wearer's
+#[derive(Debug, Clone, Copy)]
+pub struct Regen(pub i32);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Noise {
+    pub pos: Vec2,
+    /// Number of tiles the sound travels
+    pub volume: i32,
+}
+
+/// Sounds made since the last AI update, they wake up and attract monsters in earshot
+#[derive(Debug, Default)]
+pub struct Noises(pub Vec<Noise>);
+
+impl Noises {
+    pub fn push(&mut self, pos: Vec2, volume: i32) {
+        self.0.push(Noise { pos, volume });
+    }
+}
+
+/// What a monster is up to, drives its movement and attacks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state")]
+pub enum AiState {
+    /// Wakes up when hurt or when a hostile creature comes close
+    Asleep,
+    /// Stays in place until it sees a hostile creature
+    Idle,
+    /// Walks around randomly until it sees a hostile creature
+    Wandering,
+    /// Chases hostile creatures, searches where one was last seen when it loses sight
+    Hunting { last_known: Vec2 },
+    /// Runs from hostile creatures when hurt
+    Fleeing,
+    /// Walks back to its leash origin
+    Returning,
+}
+
+/// Per-monster AI settings, loaded from the config
+#[derive(Debug, Clone, Copy)]
+pub struct AiBehaviour {
+    /// Initial state, wandering monsters go back to wandering after losing their target
+    pub initial: AiState,
+    /// Flee when hp drops to this percent of max hp
+    pub flee_hp: i32,
+}
+
+impl Default for AiBehaviour {
+    fn default() -> Self {
+        Self {
+            initial: AiState::Idle,
+            flee_hp: 0,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Leash {
     pub origin: Vec2,
     pub radius: i32,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DamageType {
+    Physical,
+    Fire,
+    Lightning,
+    Poison,
+}
+
+/// Damage taken by type, loaded from the config
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Resistances {
+    /// Half damage
+    pub resistant: &'static [DamageType],
+    /// Double damage
+    pub vulnerable: &'static [DamageType],
+    /// No damage
+    pub immune: &'static [DamageType],
+}
+
+impl Resistances {
+    pub fn is_immune(&self, ty: DamageType) -> bool {
+        self.immune.contains(&ty)
+    }
+
+    pub fn apply(&self, ty: DamageType, damage: i32) -> i32 {
+        if self.is_immune(ty) {
+            0
+        } else if self.resistant.contains(&ty) {
+            // round up, so resisted hits still hurt
+            (damage + 1) / 2
+        } else if self.vulnerable.contains(&ty) {
+            damage * 2
+        } else {
+            damage
+        }
+    }
+}
+
+/// Creatures attack members of hostile factions, loaded from the config
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Faction {
+    Player,
+    Greenskin,
+    Undead,
+    Monster,
+}
+
+/// Hostility is symmetric, factions missing from the table ignore each other
+const HOSTILE_FACTIONS: &[(Faction, Faction)] = &[
+    (Faction::Player, Faction::Greenskin),
+    (Faction::Player, Faction::Undead),
+    (Faction::Player, Faction::Monster),
+    (Faction::Greenskin, Faction::Undead),
+    (Faction::Monster, Faction::Undead),
+];
+
+impl Faction {
+    pub fn is_hostile(self, other: Faction) -> bool {
+        HOSTILE_FACTIONS
+            .iter()
+            .any(|&(a, b)| (a, b) == (self, other) || (b, a) == (self, other))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Color(pub String);
 
@@ -149,9 +312,130 @@ unsafe impl Sync for Color {}
 
 pub struct Visible(pub Grid<bool>);
 
+/// Cells in the player's line of sight, lit or not
+pub struct LineOfSight(pub Grid<bool>);
+
 #[derive(Serialize, Deserialize)]
 pub struct Explored(pub Grid<bool>);
 
+/// Lights the cells in its line of sight, carried lights are emitted by the player
+#[derive(Debug, Clone, Copy)]
+pub struct LightSource {
+    pub radius: i32,
+    pub color: &'static str,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Light {
+    /// 0 is dark
+    pub intensity: u8,
+    pub color: Option<&'static str>,
+}
+
+pub struct LightMap(pub Grid<Light>);
+
+/// Maps shared by the monsters chasing or fleeing the player, recomputed every AI update
+#[derive(Default)]
+pub struct PlayerMaps {
+    /// Player position the maps were computed for
+    pub goal: Option<Vec2>,
+    pub approach: DijkstraMap,
+    pub flee: DijkstraMap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "ty", content = "pos")]
+pub enum TravelGoal {
+    /// Nearest unexplored cell the player can reach
+    Explore,
+    /// An explored cell
+    To(Vec2),
+    /// Nearest explored stairs
+    Stairs,
+    /// Nearest explored shop
+    Shop,
+    /// Wait in place until the hp is full
+    Rest,
+}
+
+/// Player activity spanning several turns, stopped by anything that needs the player's attention
+#[derive(Default)]
+pub struct Travel {
+    pub goal: Option<TravelGoal>,
+    /// Player hp before the last step, `None` until the first step is taken
+    pub last_hp: Option<i32>,
+    /// Items that were in view before the last step
+    pub known_items: HashSet<EntityId>,
+    pub map: DijkstraMap,
+    pub path: Path,
+}
+
+impl Travel {
+    pub fn start(&mut self, goal: TravelGoal) {
+        self.goal = Some(goal);
+        self.last_hp = None;
+        self.known_items.clear();
+    }
+
+    pub fn stop(&mut self) {
+        self.goal = None;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.goal.is_some()
+    }
+}
+
+/// Light level of every cell of the floor, on top of the [LightSource]s
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct AmbientLight(pub u8);
+
+const CROSS_DIRECTIONS: [Vec2; 4] = [Vec2::X, Vec2::Y, Vec2::new(-1, 0), Vec2::new(0, -1)];
+const ALL_DIRECTIONS: [Vec2; 8] = [
+    Vec2::X,
+    Vec2::Y,
+    Vec2::new(-1, 0),
+    Vec2::new(0, -1),
+    Vec2::new(1, 1),
+    Vec2::new(-1, 1),
+    Vec2::new(1, -1),
+    Vec2::new(-1, -1),
+];
+
+/// Rules chosen at the start of a run, they can't change during the run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Ruleset {
+    /// Creatures may move and attack diagonally
+    pub diagonal_movement: bool,
+}
+
+impl Ruleset {
+    /// Steps a creature can take in a turn
+    pub fn directions(&self) -> &'static [Vec2] {
+        if self.diagonal_movement {
+            &ALL_DIRECTIONS
+        } else {
+            &CROSS_DIRECTIONS
+        }
+    }
+
+    /// Number of steps between two positions on an empty floor
+    ///
+    /// Used for ranges as well, so everything within range is also reachable in as many steps
+    pub fn distance(&self, a: Vec2, b: Vec2) -> i32 {
+        if self.diagonal_movement {
+            a.chebyshev(b)
+        } else {
+            a.manhatten(b)
+        }
+    }
+
+    pub fn is_adjacent(&self, a: Vec2, b: Vec2) -> bool {
+        self.distance(a, b) <= 1
+    }
+}
+
 #[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct GameTick(pub i32);
 
@@ -161,12 +445,63 @@ impl Default for GameTick {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CharacterName(pub String);
+
+impl Default for CharacterName {
+    fn default() -> Self {
+        CharacterName("Wanderer".to_owned())
+    }
+}
+
+/// Source of all randomness in the simulation
+///
+/// Persisted with the world, so a run can be reproduced from its seed or a save
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GameRng {
+    pub seed: u64,
+    rng: Xoshiro256PlusPlus,
+}
+
+impl GameRng {
+    pub fn from_seed(seed: u64) -> Self {
+        Self {
+            seed,
+            rng: Xoshiro256PlusPlus::seed_from_u64(seed),
+        }
+    }
+
+    pub fn from_entropy() -> Self {
+        Self::from_seed(rand::rng().random())
+    }
+}
+
+impl std::ops::Deref for GameRng {
+    type Target = Xoshiro256PlusPlus;
+
+    fn deref(&self) -> &Self::Target {
+        &self.rng
+    }
+}
+
+impl std::ops::DerefMut for GameRng {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.rng
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct Viewport(pub Vec2);
 #[derive(Clone, Copy)]
 pub struct CameraPos(pub Vec2);
-pub struct Output(pub JsValue);
-pub struct Visibility(pub Vec2);
+#[derive(Default)]
+pub struct Output(pub Option<crate::RenderedOutput>);
+/// Sight range of the player
+pub struct Visibility {
+    pub radius: i32,
+    /// Limit the sight to a circle instead of a square
+    pub circular: bool,
+}
 pub struct ShouldUpdateWorld(pub bool);
 pub struct ShouldUpdatePlayer(pub bool);
 
@@ -197,34 +532,12 @@ impl PlayerId {
     }
 }
 
-#[derive(Default)]
-pub struct IconCollection(pub HashMap<&'static str, web_sys::Path2d>);
-
-#[derive(Default)]
-pub struct RenderResources {
-    pub canvas: Option<web_sys::HtmlCanvasElement>,
-    pub ctx: Option<web_sys::CanvasRenderingContext2d>,
-    pub width: u32,
-    pub height: u32,
-}
-
-impl RenderResources {
-    pub fn update_dims(&mut self) {
-        let dims = self
-            .canvas
-            .as_ref()
-            .map(|canvas| (canvas.width(), canvas.height()));
-        let (width, height) = dims.unwrap_or_default();
-        self.width = width;
-        self.height = height;
-    }
-}
-
 #[derive(Default)]
 pub struct Selected(pub Option<EntityId>);
 
+/// Grid position of the last click
 #[derive(Default)]
-pub struct ClickPosition(pub Option<[f64; 2]>);
+pub struct ClickPosition(pub Option<Vec2>);
 
 #[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct DungeonFloor {
@@ -327,11 +640,6 @@ pub enum AppMode {
 #[derive(Default, Debug, Clone, Copy)]
 pub struct Velocity(pub Vec2);
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-pub struct ConfusedAi {
-    pub duration: i32,
-}
-
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Aoe {
     pub radius: u32,
@@ -398,6 +706,7 @@ pub struct Exp {
 #[serde(tag = "ty")]
 pub enum DesiredStat {
     Attack,
+    RangedAttack,
     Hp,
     MeleeDefense,
 }
@@ -406,18 +715,21 @@ pub enum DesiredStat {
 pub enum EquipmentType {
     Weapon,
     Armor,
+    Ranged,
 }
 
 #[derive(Default, Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Equipment {
     pub weapon: Option<EntityId>,
     pub armor: Option<EntityId>,
+    pub ranged: Option<EntityId>,
 }
 
 impl Equipment {
     pub fn contains(&self, id: EntityId) -> bool {
         self.weapon.map(|i| i == id).unwrap_or(false)
             || self.armor.map(|i| i == id).unwrap_or(false)
+            || self.ranged.map(|i| i == id).unwrap_or(false)
     }
 }
 
@@ -426,13 +738,16 @@ impl Equipment {
 pub struct Defense {
     pub melee_defense: i32,
     pub ward: u8,
+    /// Lowers the attacker's chance to hit
+    pub evasion: i32,
 }
 
 impl Defense {
-    pub const fn new(melee_defense: i32) -> Self {
+    pub const fn new(melee_defense: i32, evasion: i32) -> Self {
         Self {
             melee_defense,
             ward: 0,
+            evasion,
         }
     }
 }
@@ -441,6 +756,7 @@ impl std::ops::AddAssign for Defense {
     fn add_assign(&mut self, rhs: Self) {
         self.melee_defense += rhs.melee_defense;
         self.ward = self.ward.saturating_add(rhs.ward);
+        self.evasion += rhs.evasion;
     }
 }
 
@@ -448,6 +764,32 @@ impl std::ops::SubAssign for Defense {
     fn sub_assign(&mut self, rhs: Self) {
         self.melee_defense -= rhs.melee_defense;
         self.ward = self.ward.saturating_sub(rhs.ward);
+        self.evasion -= rhs.evasion;
+    }
+}
+
+/// Modifiers of a weapon's or creature's attacks, loaded from the config
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AttackMods {
+    /// Ignored points of the defender's armor
+    pub armor_penetration: i32,
+    /// Extra faces of the die that are critical hits
+    pub crit_range: i32,
+    pub crit_multiplier: i32,
+}
+
+impl AttackMods {
+    pub const DEFAULT: Self = Self {
+        armor_penetration: 0,
+        crit_range: 0,
+        crit_multiplier: 2,
+    };
+}
+
+impl Default for AttackMods {
+    fn default() -> Self {
+        Self::DEFAULT
     }
 }
 
@@ -462,12 +804,6 @@ pub struct StaticVisibility;
 #[derive(Debug, Clone, Copy)]
 pub struct UseItem;
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
-pub struct Poisoned {
-    pub duration: i32,
-    pub power: i32,
-}
-
 /// Mark item to remove
 #[derive(Debug, Clone, Copy)]
 pub struct ClearInventoryItem;
@@ -510,6 +846,14 @@ pub struct WardScroll;
 #[derive(Debug, Clone, Copy)]
 pub struct Unequip;
 
+/// Mark this inventory item to be dropped in this tick
+#[derive(Debug, Clone, Copy)]
+pub struct DropItem;
+
+/// Mark this launcher to fire in this tick
+#[derive(Debug, Clone, Copy)]
+pub struct Fire;
+
 /// Monetary value of an entity
 #[derive(Debug, Clone, Copy, serde_derive::Serialize, serde_derive::Deserialize)]
 pub struct CoinValue(pub u16);
@@ -519,9 +863,3 @@ pub struct CoinPouch(pub u32);
 
 #[derive(Debug, Clone, Copy, serde_derive::Serialize, serde_derive::Deserialize)]
 pub struct Slow(pub u16);
-
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
-pub struct Slowed {
-    pub duration: u16,
-    pub power: u16,
-}
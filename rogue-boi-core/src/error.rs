@@ -0,0 +1,76 @@
+//! Errors of the player facing operations
+//!
+//! Every error has a stable [code](CoreError::code), front-ends should branch on that instead of
+//! the message.
+use cecs::entity_id::EntityId;
+use serde::ser::SerializeStruct;
+
+use crate::{KeymapError, LoadError, ReplayError, map_gen::MIN_MAP_SIZE, math::Vec2};
+
+#[derive(Debug, thiserror::Error)]
+pub enum CoreError {
+    #[error("Entity {0} does not exist")]
+    InvalidId(EntityId),
+    #[error("Player not found")]
+    NoPlayer,
+    #[error("Not in a shop")]
+    NotInShop,
+    #[error("Shop has no item at index {0}")]
+    InvalidShopIndex(usize),
+    #[error("Inventory is full")]
+    InventoryFull,
+    #[error("Not enough coins, {cost} needed")]
+    InsufficientCoins { cost: u32 },
+    #[error("Item {0} is not in the inventory")]
+    NotInInventory(EntityId),
+    #[error("Item {0} can not be sold")]
+    Unsellable(EntityId),
+    #[error("Failed to deserialize {what}: {reason}")]
+    Deserialize { what: &'static str, reason: String },
+    #[error("Storage failure: {0}")]
+    Storage(String),
+    #[error("Map dimensions {0} are smaller than the minimum {MIN_MAP_SIZE}")]
+    InvalidMapDims(Vec2),
+    #[error(transparent)]
+    Load(#[from] LoadError),
+    #[error(transparent)]
+    Replay(#[from] ReplayError),
+    #[error(transparent)]
+    Keymap(#[from] KeymapError),
+}
+
+impl CoreError {
+    /// Stable identifier of the error kind
+    pub fn code(&self) -> &'static str {
+        match self {
+            CoreError::InvalidId(_) => "invalid_id",
+            CoreError::NoPlayer => "no_player",
+            CoreError::NotInShop => "not_in_shop",
+            CoreError::InvalidShopIndex(_) => "invalid_shop_index",
+            CoreError::InventoryFull => "inventory_full",
+            CoreError::InsufficientCoins { .. } => "insufficient_coins",
+            CoreError::NotInInventory(_) => "not_in_inventory",
+            CoreError::Unsellable(_) => "unsellable_item",
+            CoreError::Deserialize { .. } => "deserialization_failed",
+            CoreError::Storage(_) => "storage_failed",
+            CoreError::InvalidMapDims(_) => "invalid_map_dims",
+            CoreError::Load(LoadError::EmptySlot(_)) => "empty_slot",
+            CoreError::Load(LoadError::TooNew { .. }) => "save_too_new",
+            CoreError::Load(_) => "load_failed",
+            CoreError::Replay(ReplayError::Desync { .. }) => "replay_desync",
+            CoreError::Replay(_) => "invalid_replay",
+            CoreError::Keymap(KeymapError::Conflict { .. }) => "keymap_conflict",
+            CoreError::Keymap(_) => "invalid_keymap",
+        }
+    }
+}
+
+/// Serialized as `{ code, message }`
+impl serde::Serialize for CoreError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("CoreError", 2)?;
+        s.serialize_field("code", self.code())?;
+        s.serialize_field("message", &self.to_string())?;
+        s.end()
+    }
+}
@@ -0,0 +1,150 @@
+//! Symmetric shadowcasting field of view
+//!
+//! Based on <https://www.albertford.com/shadowcasting/>. Walls are lit if any part of them is in
+//! view, floor cells only if their center is. This makes visibility symmetric: if `b` is visible
+//! from `a`, then `a` is visible from `b`, given the same radius.
+use crate::math::Vec2;
+
+/// Call `mark_visible` on every cell visible from `origin`, including `origin` itself
+///
+/// `is_opaque` should return true for out of bounds positions.
+pub fn compute_fov(
+    origin: Vec2,
+    radius: i32,
+    circular: bool,
+    is_opaque: impl Fn(Vec2) -> bool,
+    mut mark_visible: impl FnMut(Vec2),
+) {
+    mark_visible(origin);
+    for quadrant in [
+        Quadrant::North,
+        Quadrant::East,
+        Quadrant::South,
+        Quadrant::West,
+    ] {
+        let mut caster = Caster {
+            origin,
+            quadrant,
+            radius,
+            circular,
+            is_opaque: &is_opaque,
+            mark_visible: &mut mark_visible,
+        };
+        caster.scan(Row {
+            depth: 1,
+            start: Slope::new(-1, 1),
+            end: Slope::new(1, 1),
+        });
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Quadrant {
+    North,
+    East,
+    South,
+    West,
+}
+
+impl Quadrant {
+    fn transform(self, origin: Vec2, depth: i32, col: i32) -> Vec2 {
+        match self {
+            Quadrant::North => Vec2::new(origin.x + col, origin.y - depth),
+            Quadrant::South => Vec2::new(origin.x + col, origin.y + depth),
+            Quadrant::East => Vec2::new(origin.x + depth, origin.y + col),
+            Quadrant::West => Vec2::new(origin.x - depth, origin.y + col),
+        }
+    }
+}
+
+/// Rational slope, so the rounding of cells on the edges is exact
+#[derive(Clone, Copy)]
+struct Slope {
+    num: i32,
+    /// always positive
+    den: i32,
+}
+
+impl Slope {
+    fn new(num: i32, den: i32) -> Self {
+        Self { num, den }
+    }
+
+    /// Slope of the left edge of the cell
+    fn of_cell(depth: i32, col: i32) -> Self {
+        Self::new(2 * col - 1, 2 * depth)
+    }
+}
+
+struct Row {
+    depth: i32,
+    start: Slope,
+    end: Slope,
+}
+
+impl Row {
+    fn min_col(&self) -> i32 {
+        // round depth * start, ties up
+        (2 * self.depth * self.start.num + self.start.den).div_euclid(2 * self.start.den)
+    }
+
+    fn max_col(&self) -> i32 {
+        // round depth * end, ties down
+        -(self.end.den - 2 * self.depth * self.end.num).div_euclid(2 * self.end.den)
+    }
+
+    fn is_symmetric(&self, col: i32) -> bool {
+        col * self.start.den >= self.depth * self.start.num
+            && col * self.end.den <= self.depth * self.end.num
+    }
+}
+
+struct Caster<'a> {
+    origin: Vec2,
+    quadrant: Quadrant,
+    radius: i32,
+    circular: bool,
+    is_opaque: &'a dyn Fn(Vec2) -> bool,
+    mark_visible: &'a mut dyn FnMut(Vec2),
+}
+
+impl Caster<'_> {
+    fn in_range(&self, depth: i32, col: i32) -> bool {
+        !self.circular || depth * depth + col * col <= self.radius * (self.radius + 1)
+    }
+
+    fn scan(&mut self, mut row: Row) {
+        if row.depth > self.radius {
+            return;
+        }
+        let mut prev_opaque = None;
+        for col in row.min_col()..=row.max_col() {
+            let pos = self.quadrant.transform(self.origin, row.depth, col);
+            let opaque = (self.is_opaque)(pos);
+            if self.in_range(row.depth, col) && (opaque || row.is_symmetric(col)) {
+                (self.mark_visible)(pos);
+            }
+            match (prev_opaque, opaque) {
+                (Some(true), false) => {
+                    row.start = Slope::of_cell(row.depth, col);
+                }
+                (Some(false), true) => {
+                    self.scan(Row {
+                        depth: row.depth + 1,
+                        start: row.start,
+                        end: Slope::of_cell(row.depth, col),
+                    });
+                }
+                _ => {}
+            }
+            prev_opaque = Some(opaque);
+        }
+        if prev_opaque == Some(false) {
+            self.scan(Row {
+                depth: row.depth + 1,
+                start: row.start,
+                end: row.end,
+            });
+        }
+    }
+}
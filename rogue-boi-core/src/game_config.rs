@@ -16,6 +16,43 @@ pub struct StuffPrototype {
     pub aoe: Option<Aoe>,
     pub value: Option<CoinValue>,
     pub slow: Option<Slow>,
+    pub light: Option<LightSource>,
+    pub ammo: Option<Ammo>,
+    pub resistances: Option<Resistances>,
+    pub attack_mods: Option<AttackMods>,
+    pub faction: Option<Faction>,
+    pub ai_behaviour: Option<AiBehaviour>,
+    pub stealth: Option<Stealth>,
+    pub regen: Option<Regen>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum PrefabCell {
+    Floor,
+    Stuff(StuffTag),
+    /// Random enemy of the floor
+    Enemy,
+    /// Random item of the floor
+    Item,
+}
+
+/// Hand made room, authored in `assets/prefabs.txt`
+#[derive(Debug)]
+pub struct Prefab {
+    pub name: &'static str,
+    /// First floor the prefab may appear on
+    pub level: u32,
+    pub weight: i32,
+    pub width: i32,
+    pub height: i32,
+    /// Row-major
+    pub cells: &'static [PrefabCell],
+}
+
+impl Prefab {
+    pub fn at(&self, x: i32, y: i32) -> PrefabCell {
+        self.cells[(y * self.width + x) as usize]
+    }
 }
 
 fn insert_optional<T: cecs::Component>(cmd: &mut cecs::commands::EntityCommands, stuff: Option<T>) {
@@ -32,6 +69,18 @@ pub fn get_color(tag: StuffTag) -> Option<&'static Color> {
     STUFF_PROTOTYPES[&tag].color.as_ref()
 }
 
+pub fn get_attack_mods(tag: StuffTag) -> Option<&'static AttackMods> {
+    STUFF_PROTOTYPES[&tag].attack_mods.as_ref()
+}
+
+pub fn get_faction(tag: StuffTag) -> Option<Faction> {
+    STUFF_PROTOTYPES[&tag].faction
+}
+
+pub fn get_stealth(tag: StuffTag) -> Option<i32> {
+    STUFF_PROTOTYPES[&tag].stealth.map(|s| s.0)
+}
+
 pub fn insert_default_transient_components(
     cmd: &mut cecs::commands::EntityCommands,
     tag: StuffTag,
@@ -42,6 +91,13 @@ pub fn insert_default_transient_components(
     insert_optional(cmd, desc.description);
     insert_optional(cmd, desc.color);
     insert_optional(cmd, desc.exp);
+    insert_optional(cmd, desc.light);
+    insert_optional(cmd, desc.resistances);
+    insert_optional(cmd, desc.attack_mods);
+    insert_optional(cmd, desc.faction);
+    insert_optional(cmd, desc.ai_behaviour);
+    insert_optional(cmd, desc.stealth);
+    insert_optional(cmd, desc.regen);
 }
 
 // components that are saved should not be inserted when loading
@@ -56,6 +112,8 @@ pub fn insert_default_components(cmd: &mut cecs::commands::EntityCommands, tag:
     insert_optional(cmd, desc.aoe);
     insert_optional(cmd, desc.value);
     insert_optional(cmd, desc.slow);
+    insert_optional(cmd, desc.ammo);
+    insert_optional(cmd, desc.ai_behaviour.map(|b| b.initial));
 }
 
 include!(concat!(env!("OUT_DIR"), "/game_config_gen.rs"));
@@ -103,21 +103,6 @@ impl<T: serde::Serialize> Grid<T> {
         }
     }
 
-    pub fn splat_set(&mut self, [from, to]: [Vec2; 2], value: T)
-    where
-        T: Clone,
-    {
-        let miny = from.y.max(0);
-        let minx = from.x.max(0);
-        let maxy = to.y.min(self.dims.y - 1);
-        let maxx = to.x.min(self.dims.x - 1);
-        for y in miny..maxy {
-            for x in minx..maxx {
-                self[Vec2::new(x, y)] = value.clone();
-            }
-        }
-    }
-
     pub fn scan_range(&self, [from, to]: [Vec2; 2], mut f: impl FnMut(Vec2, &T)) {
         let miny = from.y.max(0);
         let minx = from.x.max(0);
@@ -0,0 +1,381 @@
+//! Translation of key presses into player [Action]s
+//!
+//! The [Keymap] is a preference of the player, it is not part of the save. Games keep running with
+//! whatever keymap is set, replays record the changes.
+use std::collections::BTreeMap;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{HashMap, InputEvent, math::Vec2};
+
+#[derive(Debug, thiserror::Error)]
+pub enum KeymapError {
+    #[error("Failed to decode keymap: {0}")]
+    Decode(String),
+    #[error("{chord} is bound to both {first:?} and {second:?}")]
+    Conflict {
+        chord: KeyChord,
+        first: Action,
+        second: Action,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Action {
+    MoveUp,
+    MoveDown,
+    MoveLeft,
+    MoveRight,
+    MoveUpLeft,
+    MoveUpRight,
+    MoveDownLeft,
+    MoveDownRight,
+    Wait,
+    Interact,
+    Fire,
+    /// Use the selected inventory item
+    UseSelected,
+    /// Drop the selected inventory item
+    DropSelected,
+    CancelItemUse,
+    /// Walk toward the nearest unexplored cell until something interesting happens
+    AutoExplore,
+    /// Walk to the nearest known stairs
+    TravelToStairs,
+    /// Wait until healed
+    Rest,
+}
+
+impl Action {
+    pub const ALL: [Action; 17] = [
+        Action::MoveUp,
+        Action::MoveDown,
+        Action::MoveLeft,
+        Action::MoveRight,
+        Action::MoveUpLeft,
+        Action::MoveUpRight,
+        Action::MoveDownLeft,
+        Action::MoveDownRight,
+        Action::Wait,
+        Action::Interact,
+        Action::Fire,
+        Action::UseSelected,
+        Action::DropSelected,
+        Action::CancelItemUse,
+        Action::AutoExplore,
+        Action::TravelToStairs,
+        Action::Rest,
+    ];
+
+    /// Step of the movement actions
+    pub fn direction(self) -> Option<Vec2> {
+        let delta = match self {
+            Action::MoveUp => Vec2::new(0, -1),
+            Action::MoveDown => Vec2::new(0, 1),
+            Action::MoveLeft => Vec2::new(-1, 0),
+            Action::MoveRight => Vec2::new(1, 0),
+            Action::MoveUpLeft => Vec2::new(-1, -1),
+            Action::MoveUpRight => Vec2::new(1, -1),
+            Action::MoveDownLeft => Vec2::new(-1, 1),
+            Action::MoveDownRight => Vec2::new(1, 1),
+            _ => return None,
+        };
+        Some(delta)
+    }
+}
+
+/// A key and the modifiers held with it
+///
+/// `key` is the browser's
+/// [KeyboardEvent.key](https://developer.mozilla.org/en-US/docs/Web/API/KeyboardEvent/key)
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct KeyChord {
+    pub key: String,
+    #[serde(default)]
+    pub ctrl: bool,
+    #[serde(default)]
+    pub shift: bool,
+    #[serde(default)]
+    pub alt: bool,
+}
+
+impl KeyChord {
+    pub fn new(key: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            ctrl: false,
+            shift: false,
+            alt: false,
+        }
+    }
+
+    fn matches(&self, event: &InputEvent) -> bool {
+        match event {
+            InputEvent::KeyDown {
+                key,
+                ctrl,
+                shift,
+                alt,
+            } => &self.key == key && self.ctrl == *ctrl && self.shift == *shift && self.alt == *alt,
+            InputEvent::KeyUp { .. } => false,
+        }
+    }
+}
+
+impl std::fmt::Display for KeyChord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.ctrl {
+            write!(f, "Ctrl+")?;
+        }
+        if self.alt {
+            write!(f, "Alt+")?;
+        }
+        if self.shift {
+            write!(f, "Shift+")?;
+        }
+        write!(f, "{}", self.key)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeymapPreset {
+    Wasd,
+    /// WASD for AZERTY keyboards
+    Zqsd,
+    ViKeys,
+    Numpad,
+}
+
+/// Keys bound to each [Action], an action may have any number of keys
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Keymap {
+    pub bindings: BTreeMap<Action, Vec<KeyChord>>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Self::preset(KeymapPreset::Wasd)
+    }
+}
+
+impl Keymap {
+    pub fn preset(preset: KeymapPreset) -> Self {
+        let arrows = [
+            (Action::MoveUp, "ArrowUp"),
+            (Action::MoveDown, "ArrowDown"),
+            (Action::MoveLeft, "ArrowLeft"),
+            (Action::MoveRight, "ArrowRight"),
+        ];
+        let numpad = [
+            (Action::MoveUp, "8"),
+            (Action::MoveDown, "2"),
+            (Action::MoveLeft, "4"),
+            (Action::MoveRight, "6"),
+            (Action::MoveUpLeft, "7"),
+            (Action::MoveUpRight, "9"),
+            (Action::MoveDownLeft, "1"),
+            (Action::MoveDownRight, "3"),
+        ];
+        let bindings: &[(Action, &str)] = match preset {
+            KeymapPreset::Wasd => &[
+                (Action::MoveUp, "w"),
+                (Action::MoveDown, "s"),
+                (Action::MoveLeft, "a"),
+                (Action::MoveRight, "d"),
+                (Action::Wait, " "),
+                (Action::Interact, "e"),
+                (Action::Fire, "f"),
+                (Action::UseSelected, "r"),
+                (Action::DropSelected, "x"),
+                (Action::AutoExplore, "o"),
+                (Action::TravelToStairs, "g"),
+                (Action::Rest, "c"),
+            ],
+            KeymapPreset::Zqsd => &[
+                (Action::MoveUp, "z"),
+                (Action::MoveDown, "s"),
+                (Action::MoveLeft, "q"),
+                (Action::MoveRight, "d"),
+                (Action::Wait, " "),
+                (Action::Interact, "e"),
+                (Action::Fire, "f"),
+                (Action::UseSelected, "r"),
+                (Action::DropSelected, "x"),
+                (Action::AutoExplore, "o"),
+                (Action::TravelToStairs, "g"),
+                (Action::Rest, "c"),
+            ],
+            KeymapPreset::ViKeys => &[
+                (Action::MoveUp, "k"),
+                (Action::MoveDown, "j"),
+                (Action::MoveLeft, "h"),
+                (Action::MoveRight, "l"),
+                (Action::MoveUpLeft, "y"),
+                (Action::MoveUpRight, "u"),
+                (Action::MoveDownLeft, "b"),
+                (Action::MoveDownRight, "n"),
+                (Action::Wait, "."),
+                (Action::Interact, ","),
+                (Action::Fire, "f"),
+                (Action::UseSelected, "a"),
+                (Action::DropSelected, "d"),
+                (Action::AutoExplore, "o"),
+                (Action::TravelToStairs, "g"),
+                (Action::Rest, "z"),
+            ],
+            KeymapPreset::Numpad => &[
+                // numpad with num lock off
+                (Action::MoveUpLeft, "Home"),
+                (Action::MoveUpRight, "PageUp"),
+                (Action::MoveDownLeft, "End"),
+                (Action::MoveDownRight, "PageDown"),
+                (Action::Wait, "5"),
+                (Action::Wait, "Clear"),
+                (Action::Interact, "Enter"),
+                (Action::Fire, "/"),
+                (Action::UseSelected, "+"),
+                (Action::DropSelected, "-"),
+                (Action::AutoExplore, "*"),
+                (Action::TravelToStairs, "0"),
+                (Action::Rest, "Delete"),
+            ],
+        };
+        let movement: &[(Action, &str)] = match preset {
+            KeymapPreset::ViKeys => &[],
+            _ => &numpad,
+        };
+
+        let mut keymap = Self {
+            bindings: BTreeMap::new(),
+        };
+        for (action, key) in arrows
+            .iter()
+            .chain(movement)
+            .chain(bindings)
+            .chain(&[(Action::CancelItemUse, "Escape")])
+        {
+            keymap.bind(*action, KeyChord::new(*key));
+        }
+        keymap
+    }
+
+    /// First action bound to the pressed key
+    pub fn action(&self, event: &InputEvent) -> Option<Action> {
+        self.bindings
+            .iter()
+            .find(|(_, chords)| chords.iter().any(|chord| chord.matches(event)))
+            .map(|(action, _)| *action)
+    }
+
+    pub fn bind(&mut self, action: Action, chord: KeyChord) {
+        let chords = self.bindings.entry(action).or_default();
+        if !chords.contains(&chord) {
+            chords.push(chord);
+        }
+    }
+
+    /// Remove the chord from every action
+    pub fn unbind(&mut self, chord: &KeyChord) {
+        for chords in self.bindings.values_mut() {
+            chords.retain(|c| c != chord);
+        }
+        self.bindings.retain(|_, chords| !chords.is_empty());
+    }
+
+    /// Chords bound to more than one action
+    pub fn conflicts(&self) -> Vec<KeymapError> {
+        let mut seen = HashMap::<&KeyChord, Action>::default();
+        let mut conflicts = Vec::new();
+        for (action, chord) in self
+            .bindings
+            .iter()
+            .flat_map(|(action, chords)| chords.iter().map(move |c| (*action, c)))
+        {
+            match seen.get(chord) {
+                Some(first) if *first != action => conflicts.push(KeymapError::Conflict {
+                    chord: chord.clone(),
+                    first: *first,
+                    second: action,
+                }),
+                Some(_) => {}
+                None => {
+                    seen.insert(chord, action);
+                }
+            }
+        }
+        conflicts
+    }
+
+    pub fn validate(&self) -> Result<(), KeymapError> {
+        match self.conflicts().into_iter().next() {
+            Some(conflict) => Err(conflict),
+            None => Ok(()),
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).expect("failed to serialize keymap")
+    }
+
+    /// Conflicting keymaps are rejected
+    pub fn decode(pl: &str) -> Result<Self, KeymapError> {
+        let keymap: Self =
+            serde_json::from_str(pl).map_err(|err| KeymapError::Decode(err.to_string()))?;
+        keymap.validate()?;
+        Ok(keymap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRESETS: [KeymapPreset; 4] = [
+        KeymapPreset::Wasd,
+        KeymapPreset::Zqsd,
+        KeymapPreset::ViKeys,
+        KeymapPreset::Numpad,
+    ];
+
+    #[test]
+    fn presets_have_no_conflicts() {
+        for preset in PRESETS {
+            let keymap = Keymap::preset(preset);
+            assert!(
+                keymap.validate().is_ok(),
+                "{preset:?}: {:?}",
+                keymap.conflicts()
+            );
+        }
+    }
+
+    #[test]
+    fn keymaps_round_trip() {
+        for preset in PRESETS {
+            let keymap = Keymap::preset(preset);
+            assert_eq!(Keymap::decode(&keymap.encode()).unwrap(), keymap);
+        }
+    }
+
+    #[test]
+    fn chord_bound_to_two_actions_is_rejected() {
+        let mut keymap = Keymap::default();
+        let chord = keymap.bindings[&Action::MoveUp][0].clone();
+        keymap
+            .bindings
+            .entry(Action::MoveDown)
+            .or_default()
+            .push(chord.clone());
+
+        assert_eq!(keymap.conflicts().len(), 1);
+        let result = Keymap::decode(&keymap.encode());
+        assert!(
+            matches!(
+                &result,
+                Err(KeymapError::Conflict { chord: c, first: Action::MoveUp, second: Action::MoveDown })
+                    if *c == chord
+            ),
+            "{result:?}"
+        );
+    }
+}
@@ -3,70 +3,66 @@
 
 mod archetypes;
 mod colors;
+mod combat;
 mod components;
+mod error;
+mod fov;
 mod game_config;
 mod grid;
+mod keymap;
 mod map_gen;
-mod math;
+pub mod math;
 mod pathfinder;
+mod replay;
+mod save;
+mod session;
+mod status_effects;
 mod systems;
+#[cfg(feature = "web")]
 mod utils;
+#[cfg(feature = "web")]
+mod web;
 
-use std::{cell::RefCell, rc::Rc};
-
-use crate::{
-    archetypes::init_entity,
-    systems::{
-        handle_click, init_world_systems, perform_drop_item, regenerate_dungeon, update_output,
-        update_unequip,
-    },
-};
-use anyhow::Context as _;
-use base64::{Engine, engine::GeneralPurpose};
+use crate::systems::init_world_systems;
+use base64::engine::GeneralPurpose;
 use cecs::{prelude::*, serde::WorldSerializer};
-use colors::WHITE;
 use components::*;
 use grid::Grid;
-use icons::ICONS;
 use math::Vec2;
-use tracing::{debug, error};
-use wasm_bindgen::prelude::*;
-
-pub type HashMap<K, V> = rustc_hash::FxHashMap<K, V>;
-pub type HashSet<K> = rustc_hash::FxHashSet<K>;
 
-/// State object
-#[wasm_bindgen]
-pub struct Core {
-    world: Rc<RefCell<World>>,
-}
+pub use cecs::entity_id::EntityId;
+pub use components::{AppMode, CoinPouch, Defense, DesiredStat, Hp, Ruleset, StuffTag, TravelGoal};
+pub use error::CoreError;
+pub use keymap::{Action, KeyChord, Keymap, KeymapError, KeymapPreset};
+pub use map_gen::MapGeneratorKind;
+pub use replay::{Replay, ReplayAction, ReplayEntry, ReplayError};
+pub use save::{
+    LoadError, MemoryStorage, SAVE_VERSION, SaveHeader, SaveSlots, SaveVersion, SlotInfo,
+    SlotStorage, peek_save,
+};
+pub use session::GameSession;
+pub use status_effects::{StatusEffect, StatusEffects, StatusKind};
+#[cfg(feature = "web")]
+pub use web::{Core, init_core};
 
-#[wasm_bindgen(start)]
-pub fn start() {
-    utils::set_panic_hook();
-    tracing_wasm::set_as_global_default_with_config(
-        tracing_wasm::WASMLayerConfigBuilder::new()
-            .set_max_level(tracing::Level::DEBUG)
-            .build(),
-    );
-}
+// the engine has to be able to load browser-encoded data as well
+const BASE64_ENGINE: GeneralPurpose = base64::engine::general_purpose::STANDARD;
 
-fn compute_icons() -> IconCollection {
-    let inner = icons::ICONS_SVG
-        .iter()
-        .map(|(k, svg)| (*k, web_sys::Path2d::new_with_path_string(svg).unwrap()))
-        .collect();
-    IconCollection(inner)
-}
+pub type HashMap<K, V> = rustc_hash::FxHashMap<K, V>;
+pub type HashSet<K> = rustc_hash::FxHashSet<K>;
 
 fn get_world_persister() -> impl WorldSerializer {
     let persister = cecs::serde::WorldPersister::new()
-        .with_version(cecs::serde::Version::new(2, 0, 0))
+        .with_version(save::SAVE_VERSION.to_cecs())
         .with_resource::<WorldDims>()
         .with_resource::<GameTick>()
         .with_resource::<LogHistory>()
         .with_resource::<DungeonFloor>()
-        .with_resource::<Explored>();
+        .with_resource::<Explored>()
+        .with_resource::<GameRng>()
+        .with_resource::<CharacterName>()
+        .with_resource::<AmbientLight>()
+        .with_resource::<Ruleset>();
 
     archetypes::register_persistent_components(persister)
 }
@@ -76,17 +72,24 @@ fn init_world_transient_resources(world_dims: Vec2, world: &mut World) {
     world.insert_resource(StaticGrid(Grid::new(world_dims)));
     world.insert_resource(ClickPosition(None));
     world.insert_resource(Selected::default());
-    world.insert_resource(compute_icons());
     world.insert_resource(ShouldUpdateWorld(false));
     world.insert_resource(ShouldUpdatePlayer(false));
     world.insert_resource(Vec::<InputEvent>::with_capacity(16));
     world.insert_resource(PlayerActions::new());
+    world.insert_resource(Keymap::default());
     world.insert_resource(Visible(Grid::new(world_dims)));
+    world.insert_resource(LineOfSight(Grid::new(world_dims)));
+    world.insert_resource(LightMap(Grid::new(world_dims)));
+    world.insert_resource(Noises::default());
+    world.insert_resource(PlayerMaps::default());
+    world.insert_resource(Travel::default());
     world.insert_resource(Viewport(Vec2::new(16, 16)));
-    world.insert_resource(Visibility(Vec2::new(10, 10)));
+    world.insert_resource(Visibility {
+        radius: 10,
+        circular: true,
+    });
     world.insert_resource(CameraPos(Vec2::ZERO));
-    world.insert_resource(Output(JsValue::null()));
-    world.insert_resource(RenderResources::default());
+    world.insert_resource(Output::default());
     world.insert_resource(BounceOffTime(0));
     world.insert_resource(ShouldTick(false));
     world.insert_resource(DeltaTime(0));
@@ -94,6 +97,7 @@ fn init_world_transient_resources(world_dims: Vec2, world: &mut World) {
     world.insert_resource(AppMode::Game);
     world.insert_resource(TargetPos::default());
     world.insert_resource(PlayerId::default());
+    world.insert_resource(map_gen::MapGenStats::default());
 }
 
 fn init_world_resources(world_dims: Vec2, world: &mut World) {
@@ -102,6 +106,10 @@ fn init_world_resources(world_dims: Vec2, world: &mut World) {
     world.insert_resource(GameTick::default());
     world.insert_resource(LogHistory::default());
     world.insert_resource(Explored(Grid::new(world_dims)));
+    world.insert_resource(GameRng::from_entropy());
+    world.insert_resource(CharacterName::default());
+    world.insert_resource(AmbientLight::default());
+    world.insert_resource(Ruleset::default());
 }
 
 pub fn init_world(world_dims: Vec2, world: &mut World) {
@@ -123,62 +131,66 @@ fn default_world() -> World {
     world
 }
 
-#[wasm_bindgen(js_name = "initCore")]
-pub fn init_core() -> Core {
-    let world = default_world();
-
-    let world = Rc::new(RefCell::new(world));
-    Core { world }
-}
-
 pub type Stuff = Option<EntityId>;
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 #[serde(tag = "ty")]
 pub enum InputEvent {
-    KeyUp { key: String },
-    KeyDown { key: String },
+    KeyUp {
+        key: String,
+    },
+    KeyDown {
+        key: String,
+        #[serde(default)]
+        ctrl: bool,
+        #[serde(default)]
+        shift: bool,
+        #[serde(default)]
+        alt: bool,
+    },
 }
 
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
-pub struct RenderedOutput<'a> {
+pub struct RenderedOutput {
     pub selected: Option<EntityId>,
     pub player: Option<PlayerOutput>,
-    pub log: Vec<&'a str>,
+    pub log: Vec<String>,
     pub targeting: bool,
     pub dungeon_level: u32,
     pub app_mode: AppMode,
-    pub shop: Option<ShopOutput<'a>>,
+    pub shop: Option<ShopOutput>,
 }
 
-#[derive(serde::Serialize, Debug)]
+#[derive(serde::Serialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
-pub struct ShopOutput<'a> {
+pub struct ShopOutput {
     pub id: EntityId,
-    pub inventory: Vec<Option<ShopEntryOutput<'a>>>,
+    pub inventory: Vec<Option<ShopEntryOutput>>,
 }
 
 #[derive(Debug, Clone, serde:: Serialize)]
 #[serde(rename_all = "camelCase")]
-pub struct ShopEntryOutput<'a> {
+pub struct ShopEntryOutput {
     pub tag: StuffTag,
-    pub icon: &'a str,
-    pub color: Option<&'a str>,
+    pub icon: &'static str,
+    pub color: Option<&'static str>,
     pub cost: u16,
 }
 
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct PlayerOutput {
     pub player_hp: Hp,
     pub player_attack: i32,
+    pub player_ranged_attack: i32,
     pub player_pos: Vec2,
     pub current_xp: u32,
     pub needed_xp: u32,
     pub level: u32,
     pub defense: Defense,
     pub coins: CoinPouch,
+    pub light_radius: i32,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -188,6 +200,7 @@ pub struct PlayerActions {
     target: Option<EntityId>,
     interact: bool,
     wait: bool,
+    fire: bool,
 }
 
 impl PlayerActions {
@@ -213,6 +226,7 @@ impl PlayerActions {
         self.wait = false;
         self.len = 0;
         self.interact = false;
+        self.fire = false;
     }
 
     pub fn insert_move(&mut self, delta: Vec2) {
@@ -252,6 +266,17 @@ impl PlayerActions {
         self.interact = true;
     }
 
+    pub fn fire(&self) -> bool {
+        self.fire
+    }
+
+    pub fn insert_fire(&mut self) {
+        if !self.fire {
+            self.len += 1;
+        }
+        self.fire = true;
+    }
+
     pub fn insert_empty(&mut self) {
         self.len += 1;
     }
@@ -264,11 +289,12 @@ type ItemPropsTuple<'a> = (
     &'a StuffTag,
     Option<&'a Ranged>,
     Option<&'a Color>,
+    Option<&'a Ammo>,
 );
 type ItemPropsQ<'a> = Query<'a, ItemPropsTuple<'a>>;
 
 fn to_item_desc(id: EntityId, i: ItemPropsTuple) -> ItemDesc {
-    let (icon, desc, name, tag, ranged, color) = i;
+    let (icon, desc, name, tag, ranged, color, ammo) = i;
     ItemDesc {
         id,
         color: color.map(|c| c.0.clone()),
@@ -277,16 +303,20 @@ fn to_item_desc(id: EntityId, i: ItemPropsTuple) -> ItemDesc {
         icon: icon.map(|icon| icon.0.to_string()),
         usable: archetypes::usable(*tag),
         range: ranged.map(|r| r.range).unwrap_or(0),
+        ammo: ammo.map(|a| a.count),
     }
 }
 
-// the engine has to be able to load browser-encoded data as well
-const BASE64_ENGINE: GeneralPurpose = base64::engine::general_purpose::STANDARD;
-
-#[derive(serde::Deserialize)]
+#[derive(serde::Deserialize, Debug, Clone, Copy)]
 pub struct MapGenParams {
     pub dims: Vec2,
     pub level: u32,
+    /// Random seed if not set
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Chosen by `level` if not set
+    #[serde(default)]
+    pub generator: Option<MapGeneratorKind>,
 }
 
 impl Default for MapGenParams {
@@ -294,464 +324,15 @@ impl Default for MapGenParams {
         MapGenParams {
             dims: WORLD_DIMS,
             level: 1,
+            seed: None,
+            generator: None,
         }
     }
 }
 
-#[wasm_bindgen]
-impl Core {
-    pub fn restart(&mut self) {
-        let mut world = self.world.borrow_mut();
-
-        // delete the player
-        world
-            .run_system(|mut cmd: Commands, q: Query<EntityId, With<PlayerTag>>| {
-                for id in q.iter() {
-                    cmd.delete(id);
-                }
-            })
-            .unwrap();
-
-        world.insert_resource(DeltaTime(0));
-        world.insert_resource(GameTick::default());
-        world.insert_resource(DungeonFloor::default());
-        world.insert_resource(Selected::default());
-        world.insert_resource(AppMode::Game);
-
-        let log = world.get_resource_mut::<LogHistory>().unwrap();
-        log.items.clear();
-        log.push(WHITE, "Hello wanderer!");
-
-        world.run_system(regenerate_dungeon).unwrap();
-        drop(world);
-        self.tick(10000);
-    }
-
-    /// Generate a dungeon without altering the world state
-    #[wasm_bindgen(js_name = "generateDungeon")]
-    pub fn generate_dungeon(&self, params: JsValue) -> JsValue {
-        let params: Option<MapGenParams> =
-            serde_wasm_bindgen::from_value(params).expect("Failed to deserialize params");
-        let params = params.unwrap_or_default();
-
-        let mut world = World::new(params.dims.x as u32 * params.dims.y as u32);
-        init_world(params.dims, &mut world);
-        world.insert_resource(DungeonFloor {
-            current: params.level,
-            desired: params.level,
-        });
-        world.insert_resource(map_gen::MapGenProps::from_level(params.level));
-        world.run_system(map_gen::generate_map).unwrap();
-
-        world.run_view_system(move |tags: Query<(EntityId, &Icon, &Pos)>| {
-            let map = tags
-                .iter()
-                .map(|(_id, icon, pos)| (format!("{};{}", pos.0.x, pos.0.y), &icon.0))
-                .collect::<HashMap<_, _>>();
-            serde_wasm_bindgen::to_value(&map).unwrap()
-        })
-    }
-
-    /// return the name of the icons (without the extension!)
-    pub fn icons(&self) -> JsValue {
-        let entries: Vec<_> = ICONS.iter().map(|(k, _x)| k).collect();
-        serde_wasm_bindgen::to_value(&entries).unwrap()
-    }
-
-    pub fn tick(&mut self, dt_ms: i32) {
-        let mut world = self.world.borrow_mut();
-        world.insert_resource(DeltaTime(dt_ms));
-        world.tick();
-    }
-
-    #[wasm_bindgen(js_name = "pushEvent")]
-    pub fn push_event(&mut self, event: JsValue) {
-        let event: InputEvent = serde_wasm_bindgen::from_value(event).unwrap();
-        let world = self.world.borrow();
-        let mut inputs = ResMut::<Vec<InputEvent>>::new(&world);
-        inputs.push(event);
-    }
-
-    #[wasm_bindgen(js_name = "getOutput")]
-    pub fn get_output(&self) -> JsValue {
-        let mut world = self.world.borrow_mut();
-        let mut output = world.get_resource::<Output>().unwrap().0.clone();
-        if output.is_null() {
-            // Kinda lame, but ensure that the output is not null
-            world.run_system(update_output).unwrap();
-            output = world.get_resource::<Output>().unwrap().0.clone();
-        }
-        output
-    }
-
-    #[wasm_bindgen(js_name = "getEquipment")]
-    pub fn get_equipment(&self) -> JsValue {
-        let world = self.world.borrow();
-        let item_props = ItemPropsQ::new(&world);
-
-        let result = match Query::<&Equipment, With<PlayerTag>>::new(&world)
-            .iter()
-            .next()
-        {
-            Some(equipment) => serde_wasm_bindgen::to_value(&serde_json::json!({
-                "weapon": equipment.weapon.map(|id|to_item_desc(id, item_props.fetch(id).unwrap())),
-                "armor": equipment.armor.map(|id|to_item_desc(id, item_props.fetch(id).unwrap()))
-            }))
-            .unwrap(),
-            None => JsValue::null(),
-        };
-        result
-    }
-
-    #[wasm_bindgen(js_name = "getInventory")]
-    pub fn get_inventory(&self) -> JsValue {
-        let world = self.world.borrow();
-        let item_props = ItemPropsQ::new(&world);
-        let inventory = Query::<&Inventory, With<PlayerTag>>::new(&world)
-            .iter()
-            .next()
-            .map(|inv| {
-                inv.iter()
-                    .filter_map(|id| Some(to_item_desc(id, item_props.fetch(id)?)))
-                    .collect::<Vec<_>>()
-            });
-
-        serde_wasm_bindgen::to_value(&inventory).unwrap()
-    }
-
-    #[wasm_bindgen(js_name = "useItem")]
-    pub fn use_item(&mut self, id: JsValue) {
-        let id: EntityId = serde_wasm_bindgen::from_value(id).unwrap();
-        if !self.world.borrow().is_id_valid(id) {
-            error!("use_item id is not valid");
-            return;
-        }
-        let mut w = self.world.borrow_mut();
-        w.run_system(|mut cmd: Commands| {
-            cmd.entity(id).insert_bundle((UseItem,));
-        })
-        .unwrap();
-    }
-
-    #[wasm_bindgen(js_name = "unequipItem")]
-    pub fn unequip_item(&mut self, id: JsValue) {
-        let id: EntityId = serde_wasm_bindgen::from_value(id).unwrap();
-        if !self.world.borrow().is_id_valid(id) {
-            error!("unequip_item id is not valid");
-            return;
-        }
-        let mut w = self.world.borrow_mut();
-        w.run_system(|mut cmd: Commands| {
-            cmd.entity(id).insert_bundle((Unequip,));
-        })
-        .unwrap();
-    }
-
-    #[wasm_bindgen(js_name = "dropItem")]
-    pub fn drop_item(&mut self, id: JsValue) {
-        let id: EntityId = serde_wasm_bindgen::from_value(id).unwrap();
-        if !self.world.borrow().is_id_valid(id) {
-            error!("drop_item id is not valid");
-            return;
-        }
-
-        let mut world = self.world.borrow_mut();
-        world
-            .run_system(
-                |mut cmd: Commands,
-                 mut q: Query<(&Pos, &mut Inventory), With<PlayerTag>>,
-                 q_item: Query<&Name>,
-                 mut log: ResMut<LogHistory>,
-                 mut actions: ResMut<PlayerActions>,
-                 grid: Res<Grid<Stuff>>| {
-                    // remove item from inventory and add a position
-                    // TODO: random empty nearby position intead of the player's?
-                    if let Some((pos, inv)) = q.single_mut() {
-                        if let Some(item) = inv.remove(id) {
-                            if let Some(name) = q_item.fetch(item) {
-                                perform_drop_item(cmd.entity(id), pos, name, &mut log, &grid);
-                            }
-                        }
-                    }
-
-                    actions.insert_empty();
-                },
-            )
-            .unwrap();
-    }
-
-    #[wasm_bindgen]
-    pub fn wait(&mut self) {
-        self.world
-            .borrow_mut()
-            .get_resource_mut::<PlayerActions>()
-            .unwrap()
-            .insert_wait();
-    }
-
-    #[wasm_bindgen(js_name = "setTarget")]
-    pub fn set_target(&mut self, id: JsValue) {
-        let id: EntityId = serde_wasm_bindgen::from_value(id).unwrap();
-        debug!("set_target {}", id);
-        self.world
-            .borrow_mut()
-            .get_resource_mut::<PlayerActions>()
-            .unwrap()
-            .set_target(id);
-    }
-
-    #[wasm_bindgen(js_name = "setSelection")]
-    pub fn set_selection(&mut self, id: JsValue) {
-        let id: EntityId = serde_wasm_bindgen::from_value(id).unwrap();
-        debug!("set_selection {}", id);
-        let mut world = self.world.borrow_mut();
-        if world.is_id_valid(id) {
-            world.get_resource_mut::<Selected>().unwrap().0 = Some(id);
-            world.run_system(update_output).unwrap();
-        }
-    }
-
-    #[wasm_bindgen(js_name = "fetchEntity")]
-    pub fn fetch_entity(&self, id: JsValue) -> JsValue {
-        let id: EntityId = serde_wasm_bindgen::from_value(id).unwrap();
-        let mut world = self.world.borrow_mut();
-        if !world.is_id_valid(id) {
-            return JsValue::null();
-        }
-        world
-            .run_system(move |tags: Query<&StuffTag>, q| {
-                archetypes::stuff_to_js(id, *tags.fetch(id).unwrap(), &q)
-            })
-            .unwrap()
-    }
-
-    #[wasm_bindgen(js_name = "setCanvas")]
-    pub fn set_canvas(&mut self, canvas: Option<web_sys::HtmlCanvasElement>) {
-        debug!("Setting canvas");
-
-        let dims = canvas
-            .as_ref()
-            .map(|canvas| (canvas.width(), canvas.height()));
-        let (width, height) = dims.unwrap_or_default();
-        let resources = RenderResources {
-            ctx: canvas
-                .as_ref()
-                .and_then(|c| c.get_context("2d").ok())
-                .and_then(|x| x) // unwrap the inner optional
-                .and_then(|x| x.dyn_into().ok()),
-            canvas,
-            width,
-            height,
-        };
-
-        if let Some(canvas) = resources.canvas.as_ref() {
-            let world = Rc::clone(&self.world);
-            let closure = Closure::<dyn FnMut(_)>::new(move |event: web_sys::MouseEvent| {
-                let [x, y] = [event.offset_x() as f64, event.offset_y() as f64];
-                let mut world = world.borrow_mut();
-                world.insert_resource(ClickPosition(Some([x, y])));
-                world.run_system(handle_click).unwrap();
-                world.run_system(update_output).unwrap();
-            });
-            canvas
-                .add_event_listener_with_callback("mousedown", closure.as_ref().unchecked_ref())
-                .unwrap();
-            closure.forget();
-        }
-
-        let mut w = self.world.borrow_mut();
-        w.insert_resource(resources);
-
-        w.run_stage(
-            SystemStage::new("adhoc-render")
-                .with_system(systems::update_camera_pos)
-                .with_system(systems::update_fov)
-                .with_system(systems::render_onto_canvas)
-                .build(),
-        )
-        .unwrap();
-    }
-
-    #[wasm_bindgen(js_name = "cancelItemUse")]
-    pub fn cancel_item_use(&mut self) {
-        let mut world = self.world.borrow_mut();
-        world
-            .run_system(|mut cmd: Commands, q: Query<EntityId, With<UseItem>>| {
-                q.iter().for_each(|id| {
-                    cmd.entity(id).remove::<UseItem>();
-                });
-            })
-            .unwrap();
-        let mode = world.get_resource_mut::<AppMode>().unwrap();
-        if matches!(*mode, AppMode::Targeting) {
-            *mode = AppMode::Game;
-        }
-        let log = world.get_resource_mut::<LogHistory>().unwrap();
-        log.push(WHITE, "Cancel item use");
-    }
-
-    pub fn save(&self) -> String {
-        let world = self.world.borrow();
-        let ser = WorldSer { world: &world };
-
-        let mut result = Vec::<u8>::with_capacity(36000);
-        ciborium::into_writer(&ser, &mut result).expect("failed to serialize");
-
-        debug!("cbor size {}", result.len());
-        let encoded = BASE64_ENGINE.encode(result);
-        debug!("encoded size {}", encoded.len());
-
-        encoded
-    }
-
-    pub fn load(&mut self, pl: String) -> Result<(), JsValue> {
-        debug!("• loading");
-        let result: anyhow::Result<()> = (|| {
-            let pl = BASE64_ENGINE.decode(pl).context("failed to b64 decode")?;
-
-            let WorldDe { mut world } =
-                ciborium::from_reader(pl.as_slice()).context("failed to load world")?;
-
-            let dims = *world
-                .get_resource::<WorldDims>()
-                .context("world has no dims")?;
-            init_world_transient_resources(dims.0, &mut world);
-
-            // commands need to be applied between systems, so run them one at a time instead of
-            // iun a SystemStage
-            world
-                .run_system(archetypes::insert_transient_components)
-                .context("Failed to insert transient components")?;
-            world
-                .run_system(systems::init_grids)
-                .context("Failed to init grids")?;
-            world
-                .run_system(systems::update_camera_pos)
-                .context("Failed to update camera")?;
-            world
-                .run_system(systems::update_fov)
-                .context("Failed to update fov")?;
-            init_world_systems(&mut world);
-
-            self.world.replace(world);
-            self.tick(0);
-            Ok(())
-        })();
-        debug!(?result, "✓ loading");
-        result.map_err(|err| err.to_string().into())
-    }
-
-    #[wasm_bindgen(js_name = "setLevelupStat")]
-    pub fn set_levelup_stat(&mut self, stat: JsValue) {
-        let stat: DesiredStat = serde_wasm_bindgen::from_value(stat).unwrap();
-        let mut w = self.world.borrow_mut();
-        w.insert_resource(stat);
-    }
-
-    #[wasm_bindgen(js_name = "buyItem")]
-    pub fn buy_item(&mut self, item_idx: usize) -> Result<(), JsValue> {
-        let mut world = self.world.borrow_mut();
-        // make room for the new item
-        world.reserve_entities(1);
-
-        world
-            .run_system(
-                |mut q_shop: Query<&mut Shop, With<MarkActive>>,
-                 mut cmd: Commands,
-                 mut grid: ResMut<Grid<Stuff>>,
-                 mut q_player: Query<(&mut Inventory, &mut CoinPouch), With<PlayerTag>>,
-                 mut log: ResMut<LogHistory>| {
-                    let Some(shop) = q_shop.single_mut() else {
-                        return Err("Not in a shop".into());
-                    };
-                    let Some((inventory, coins)) = q_player.single_mut() else {
-                        return Err("Player inventory not found".into());
-                    };
-                    if inventory.is_full() {
-                        // TODO: place the item on the ground instead
-                        log.push(colors::IMPOSSIBLE, "Inventory is full");
-                        return Ok(());
-                    }
-                    match shop.items.get(item_idx).and_then(|x| x.as_ref()) {
-                        Some(item) => {
-                            if coins.0 < item.cost as u32 {
-                                log.push(colors::IMPOSSIBLE, "Not enough coins");
-                                return Ok(());
-                            }
-                            coins.0 -= item.cost as u32;
-                            log.push(colors::WHITE, format!("Purchase {:?}", item.tag));
-
-                            let cmd = init_entity(Vec2::ZERO, item.tag, &mut cmd, &mut grid);
-                            let Ok(id) = cmd.remove::<Pos>().id() else {
-                                unreachable!("Failed to spawn item");
-                            };
-                            inventory.add(id).unwrap();
-
-                            shop.items.get_mut(item_idx).unwrap().take();
-                            Ok(())
-                        }
-                        None => return Err("Invalid item index".into()),
-                    }
-                },
-            )
-            .unwrap()
-    }
-
-    #[wasm_bindgen(js_name = "sellItem")]
-    pub fn sell_item(&mut self, id: JsValue) -> Result<(), JsValue> {
-        let id: EntityId = serde_wasm_bindgen::from_value(id)
-            .map_err(|err| format!("Failed to parse id: {err}"))?;
-
-        let mut world = self.world.borrow_mut();
-
-        // check for eligibility
-        // if item is equipped, then unequip it
-        // then get its value and delete it
-
-        world
-            .run_system(
-                |mut cmd: Commands, q_player: Query<(&Inventory, &Equipment), With<PlayerTag>>| {
-                    let Some((inventory, equipment)) = q_player.single() else {
-                        return Err("Failed to find player");
-                    };
-                    let in_inventory = inventory.items.contains(&id);
-                    let in_weapon_slot = equipment.weapon == Some(id);
-                    let in_armor_slot = equipment.armor == Some(id);
-                    if !in_inventory && !in_weapon_slot && !in_armor_slot {
-                        return Err("Item is not in inventory");
-                    }
-                    if !in_inventory {
-                        cmd.entity(id).insert(Unequip);
-                    }
-                    Ok(())
-                },
-            )
-            .unwrap()?;
-        world.run_system(update_unequip).unwrap();
-        world
-            .run_system(
-                |mut cmd: Commands,
-                 mut q_player: Query<&mut CoinPouch, With<PlayerTag>>,
-                 mut log: ResMut<LogHistory>,
-                 q_item: Query<(&Name, &CoinValue)>| {
-                    let (item_name, item_value) = q_item.fetch(id).unwrap();
-                    let coins = q_player.one_mut();
-                    coins.0 += item_value.0 as u32;
-                    cmd.delete(id);
-                    log.push(
-                        WHITE,
-                        format!("Sell {} for {} coins", item_name.0, item_value.0),
-                    );
-                    Ok(())
-                },
-            )
-            .unwrap()
-    }
-}
-
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
-struct ItemDesc {
+pub struct ItemDesc {
     pub id: EntityId,
     pub name: Option<String>,
     pub description: Option<String>,
@@ -759,6 +340,15 @@ struct ItemDesc {
     pub color: Option<String>,
     pub usable: bool,
     pub range: i32,
+    /// Number of shots left in an ammunition stack
+    pub ammo: Option<u32>,
+}
+
+#[derive(serde::Serialize, Debug, Clone, Default)]
+pub struct EquipmentDesc {
+    pub weapon: Option<ItemDesc>,
+    pub armor: Option<ItemDesc>,
+    pub ranged: Option<ItemDesc>,
 }
 
 pub struct WorldSer<'a> {
@@ -1,6 +1,13 @@
+mod bsp;
+mod caves;
+mod connectivity;
+mod drunkard;
+mod prefab;
 mod rect_room;
+mod rooms;
 mod tunnel_iter;
 
+pub use self::connectivity::MapGenStats;
 use self::rect_room::RectRoom;
 use self::tunnel_iter::TunnelIter;
 use crate::{
@@ -10,20 +17,80 @@ use crate::{
 };
 use cecs::prelude::*;
 use rand::{
-    RngExt, distr::weighted::WeightedIndex, prelude::Distribution, seq::IndexedRandom as _,
+    RngExt,
+    distr::weighted::WeightedIndex,
+    prelude::Distribution,
+    seq::{IndexedRandom as _, SliceRandom as _},
 };
-use tracing::debug;
+use tracing::{debug, warn};
 
 use crate::{
     Stuff,
     archetypes::init_entity,
-    components::{DungeonFloor, PlayerTag, Pos, StuffTag, WorldDims},
+    components::{DungeonFloor, GameRng, PlayerTag, Pos, StuffTag, WorldDims},
     grid::Grid,
     math::Vec2,
 };
 
+/// Carves the layout of a floor
+///
+/// The grid passed to [carve](MapGenerator::carve) is filled with walls. Implementations clear the
+/// walkable cells and return the rooms that entities are placed into. The first room is where the
+/// player spawns. Small maps may have a single room, then the stairs are placed into it as well.
+pub trait MapGenerator {
+    fn carve(
+        &self,
+        rng: &mut GameRng,
+        grid: &mut Grid<Option<StuffTag>>,
+        props: &MapGenProps,
+    ) -> Vec<RectRoom>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum MapGeneratorKind {
+    /// Random rectangular rooms connected by tunnels
+    Rooms,
+    /// Binary space partitioning, a room in every leaf
+    Bsp,
+    /// Cellular automata caves
+    Caves,
+    /// Drunkard's walk
+    Drunkard,
+}
+
+impl MapGeneratorKind {
+    pub fn generator(self) -> &'static dyn MapGenerator {
+        match self {
+            MapGeneratorKind::Rooms => &rooms::RoomsGenerator,
+            MapGeneratorKind::Bsp => &bsp::BspGenerator,
+            MapGeneratorKind::Caves => &caves::CavesGenerator,
+            MapGeneratorKind::Drunkard => &drunkard::DrunkardGenerator,
+        }
+    }
+
+    /// Generator used on the given floor
+    pub fn from_level(level: u32) -> Self {
+        // the first floors use the classic layout, then cycle through the generators
+        const ROTATION: &[MapGeneratorKind] = &[
+            MapGeneratorKind::Bsp,
+            MapGeneratorKind::Caves,
+            MapGeneratorKind::Rooms,
+            MapGeneratorKind::Drunkard,
+        ];
+        if level <= 2 {
+            MapGeneratorKind::Rooms
+        } else {
+            ROTATION[level as usize % ROTATION.len()]
+        }
+    }
+}
+
+/// Smallest width and height of a floor every generator can carve
+pub const MIN_MAP_SIZE: i32 = 20;
+
 #[derive(Clone)]
 pub struct MapGenProps {
+    pub generator: MapGeneratorKind,
     pub room_min_size: u32,
     pub room_max_size: u32,
     pub max_rooms: u32,
@@ -50,6 +117,7 @@ impl MapGenProps {
             .unwrap_or(0);
 
         MapGenProps {
+            generator: MapGeneratorKind::from_level(level),
             max_monsters_per_floor,
             max_items_per_floor,
             room_min_size: 6,
@@ -169,6 +237,29 @@ fn place_shop(rng: &mut impl RngExt, grid: &mut Grid<Option<StuffTag>>, room: &R
     }
 }
 
+/// mount torches on the walls of the room
+fn place_torches(rng: &mut impl RngExt, grid: &mut Grid<Option<StuffTag>>, room: &RectRoom) {
+    // only walls next to a floor cell, so corners of rectangular rooms are skipped
+    let mut walls = Vec::new();
+    for y in room.min.y - 1..=room.max.y + 1 {
+        for x in room.min.x - 1..=room.max.x + 1 {
+            let p = Vec2::new(x, y);
+            if grid.at(x, y) == Some(&Some(StuffTag::Wall))
+                && [(0, -1), (0, 1), (-1, 0), (1, 0)]
+                    .into_iter()
+                    .any(|(dx, dy)| grid.at(x + dx, y + dy) == Some(&None))
+            {
+                walls.push(p);
+            }
+        }
+    }
+    let n = rng.random_range(1..=2);
+    for pos in walls.sample(rng, n) {
+        debug!("Placing Torch at {}", pos);
+        grid[*pos] = Some(StuffTag::Torch);
+    }
+}
+
 fn place_items(
     rng: &mut impl RngExt,
     grid: &mut Grid<Option<StuffTag>>,
@@ -194,6 +285,9 @@ fn place_items(
     }
 }
 
+/// Floors that can not be repaired are regenerated at most this many times
+const MAX_GENERATION_ATTEMPTS: u32 = 5;
+
 pub fn generate_map(
     player_q: Query<EntityId, With<PlayerTag>>,
     entities: Query<EntityId>,
@@ -202,8 +296,9 @@ pub fn generate_map(
     props: Res<MapGenProps>,
     dims: Res<WorldDims>,
     floor: Res<DungeonFloor>,
+    mut rng: ResMut<GameRng>,
+    mut stats: ResMut<MapGenStats>,
 ) {
-    let mut rng = rand::rng();
     // player may or may not exist at this point
     let player_id = player_q.iter().next();
     for (_p, stuff) in grid.iter_mut() {
@@ -219,8 +314,25 @@ pub fn generate_map(
         *stuff = None;
     }
     let mut working_grid = Grid::new(dims.0);
-    working_grid.fill(Some(StuffTag::Wall));
-    build_rooms(&mut working_grid, &props, floor.current);
+    *stats = MapGenStats::default();
+    loop {
+        stats.attempts += 1;
+        working_grid.fill(Some(StuffTag::Wall));
+        build_floor(&mut rng, &mut working_grid, &props, floor.current);
+        // split tunnels and stamped prefabs can leave regions cut off
+        if connectivity::connect_regions(&mut *rng, &mut working_grid, &mut stats) {
+            break;
+        }
+        if stats.attempts >= MAX_GENERATION_ATTEMPTS {
+            warn!(?stats, "Failed to generate a connected floor");
+            break;
+        }
+        debug!(
+            attempt = stats.attempts,
+            "Floor is not connected, regenerating"
+        );
+    }
+    debug!(?stats, "Generated floor");
 
     // insert entities into db
     //
@@ -250,7 +362,7 @@ pub fn generate_map(
                     .collect::<Vec<_>>();
                 for slot in shop.items.iter_mut() {
                     if rng.random_bool(0.8) {
-                        let tag = *items.choose(&mut rng).unwrap();
+                        let tag = *items.choose(&mut *rng).unwrap();
                         // TODO: price from config file
                         *slot = Some(ShopEntry {
                             tag,
@@ -286,65 +398,6 @@ pub fn generate_map(
     }
 }
 
-struct Mst {
-    pub edges: Vec<[u32; 2]>,
-    #[allow(unused)]
-    pub parents: Vec<i32>,
-}
-
-/// return edges, which are indices into `points`
-///
-/// the edges in the fully connected graph are sorted by the rooms' manhatten distance
-fn minimum_spanning_tree(points: &[RectRoom]) -> Mst {
-    let mut f = Vec::with_capacity(points.len());
-    let mut edges = points
-        .iter()
-        .enumerate()
-        .skip(1)
-        .flat_map(|(i, a)| {
-            points[..i]
-                .iter()
-                .enumerate()
-                .map(move |(j, b)| (a.center().manhatten(b.center()), [i, j]))
-        })
-        .collect::<Vec<_>>();
-    edges.sort_by_key(|(w, _)| *w);
-
-    let mut parents = vec![-1; points.len()];
-
-    'edges: for (_, [i, j]) in edges {
-        for mut ii in [i, j] {
-            while parents[ii] != -1 {
-                if parents[ii] == j as i32 {
-                    // graph would have a circle
-                    continue 'edges;
-                }
-                ii = parents[ii] as usize;
-            }
-        }
-        if parents[j] == -1 {
-            parents[j] = i as i32;
-            f.push([i as u32, j as u32]);
-            if f.len() == points.len() {
-                break 'edges;
-            }
-        }
-    }
-
-    debug_assert_eq!(
-        1,
-        parents.iter().filter(|i| i == &&-1).count(),
-        "there must be exactly 1 node with no parent in the minimum spanning tree"
-    );
-
-    Mst { edges: f, parents }
-}
-
-fn adjacency_set(matrix: &mut Grid<i8>, i: i32, j: i32, val: i8) {
-    matrix[Vec2::new(i, j)] = val;
-    matrix[Vec2::new(j, i)] = val;
-}
-
 fn assign_rooms_roles(
     mut rng: impl RngExt,
     entity_weights: &EntityChances,
@@ -372,100 +425,108 @@ fn assign_rooms_roles(
     }
 }
 
-fn build_rooms(grid: &mut Grid<Option<StuffTag>>, props: &MapGenProps, floor: u32) {
-    let mut rng = rand::rng();
-    let mut rooms = Vec::<RectRoom>::with_capacity(props.max_rooms as usize);
-
+fn build_floor(
+    rng: &mut GameRng,
+    grid: &mut Grid<Option<StuffTag>>,
+    props: &MapGenProps,
+    floor: u32,
+) {
     let entity_weights = EntityChances::from_level(floor);
 
-    'outer: for _ in 0..props.max_rooms {
-        let width = rng.random_range(props.room_min_size..props.room_max_size) as i32;
-        let height = rng.random_range(props.room_min_size..props.room_max_size) as i32;
-
-        // -3 so all rooms have walls, even those that touch the end of the map
-        const PADDING: i32 = 3;
-        let x = rng.random_range(PADDING..grid.width() - 1 - PADDING - width);
-        let y = rng.random_range(PADDING..grid.height() - 1 - PADDING - height);
-
-        let room = RectRoom::new(RoomKind::Normal, x, y, width, height);
-        for r in rooms.iter() {
-            if room.touches(r) {
-                continue 'outer;
-            }
-        }
-        debug!(?x, ?y, ?width, ?height, "carving room");
-        // increment at the end, so if the previous loop can trigger a retry
-        room.carve(grid);
-        rooms.push(room);
+    let mut rooms = props.generator.generator().carve(rng, grid, props);
+    debug!(generator = ?props.generator, rooms = rooms.len(), "Carved floor");
+    if rooms.is_empty() {
+        warn!(generator = ?props.generator, "No rooms were carved, falling back to rooms");
+        grid.fill(Some(StuffTag::Wall));
+        rooms = MapGeneratorKind::Rooms.generator().carve(rng, grid, props);
     }
 
     // first room is where player spawns, always a basic room
-    assign_rooms_roles(&mut rng, &entity_weights, &mut rooms[1..]);
-
-    debug!(?rooms, "Rooms");
-
-    let tree = minimum_spanning_tree(&rooms);
-    let mut edges = tree.edges;
-    let mut adjacency = Grid::new(Vec2::splat(rooms.len() as i32));
-    for [i, j] in edges.iter() {
-        adjacency_set(&mut adjacency, *i as i32, *j as i32, 1);
+    assign_rooms_roles(&mut *rng, &entity_weights, &mut rooms[1..]);
+
+    for room in rooms[1..]
+        .iter_mut()
+        .filter(|room| room.role == RoomKind::Vault)
+    {
+        if !prefab::stamp_vault(&mut *rng, grid, room, floor, &entity_weights) {
+            room.role = RoomKind::Normal;
+        }
     }
 
-    // let's put some edges back into the graph to produce some circles
-    // the way we connect can produce circles by itself, but it's not that reliable
-    for _ in 0..(rooms.len() / 4).max(1) {
-        let [i, j] = loop {
-            let [i, j] = [
-                rng.random_range(0..rooms.len()) as i32,
-                rng.random_range(0..rooms.len()) as i32,
-            ];
-            if i != j && adjacency[Vec2::new(i, j)] == 0 {
-                break [i, j];
-            }
-        };
-        adjacency_set(&mut adjacency, i, j, 1);
-        edges.push([i as u32, j as u32]);
+    // deeper floors have fewer lit rooms
+    let lit_chance = (0.8 - 0.1 * floor as f64).max(0.2);
+    for (i, room) in rooms.iter().enumerate() {
+        // the first room is always lit, so the player can get their bearings
+        if i == 0 || rng.random_bool(lit_chance) {
+            place_torches(&mut *rng, grid, room);
+        }
     }
 
-    'edges: while let Some([i, j]) = edges.pop() {
-        let r1 = &rooms[i as usize];
-        let r2 = &rooms[j as usize];
-        let c1 = r1.center();
-        let c2 = r2.center();
+    if floor == 1 {
+        // give a starting item on floor 1
+        place_items(&mut *rng, grid, &rooms[0], 1, 1, &entity_weights);
+    }
+    // spawn the player in the first room
+    let spawn = nearest_free(grid, rooms[0].center()).expect("The first room has no floor");
+    grid[spawn] = Some(StuffTag::Player);
 
-        // if the tunnel would cut through a room replace it with two tunnels
-        let tunnel = tunnel_between(&mut rng, c1, c2);
-        for (k, room) in rooms.iter().enumerate() {
-            if k == i as usize || k == j as usize {
-                continue;
+    // place stuff
+    for room in &rooms[1..] {
+        match room.role {
+            RoomKind::Shop => {
+                place_shop(&mut *rng, grid, room);
             }
-            if room.intersects_segment(tunnel.current, tunnel.corner)
-                || room.intersects_segment(tunnel.end, tunnel.corner)
-            {
-                debug!(i, j, k, "Splitting tunnel");
-                // avoid duplicate edges to the same pair of rooms
-                adjacency_set(&mut adjacency, i as i32, j as i32, 0);
-
-                if adjacency[Vec2::new(i as i32, k as i32)] == 0 {
-                    adjacency_set(&mut adjacency, i as i32, k as i32, 1);
-                    edges.push([i, k as u32]);
-                }
-                if adjacency[Vec2::new(j as i32, k as i32)] == 0 {
-                    adjacency_set(&mut adjacency, i as i32, k as i32, 1);
-                    edges.push([j, k as u32]);
-                }
-                continue 'edges;
+            RoomKind::Vault => {
+                // populated by its prefab
+            }
+            RoomKind::Normal => {
+                let n = place_entities(
+                    &mut *rng,
+                    grid,
+                    room,
+                    props.max_monsters_per_floor,
+                    &entity_weights,
+                );
+                // ensure no room is empty by placing at least 1 item in rooms with no monsters
+                let min_items = (n == 0) as u32;
+                place_items(
+                    &mut *rng,
+                    grid,
+                    room,
+                    min_items,
+                    props.max_items_per_floor,
+                    &entity_weights,
+                );
             }
-        }
-
-        for p in tunnel {
-            grid[p] = None;
         }
     }
 
-    // place doors in room gaps
-    // tunnels between room A and B may cut through room C so use a separate loop to fill doors
-    // instead of eagerly in the connector loop
+    // vaults may have no free cells left
+    let candidates = rooms[1..]
+        .iter()
+        .filter(|room| room.role != RoomKind::Vault)
+        .collect::<Vec<_>>();
+    let end_room = match candidates.choose(&mut *rng) {
+        Some(room) => *room,
+        // single room floors have the stairs in the spawn room
+        None => rooms[1..].choose(&mut *rng).unwrap_or(&rooms[0]),
+    };
+    place_stairs(&mut *rng, grid, end_room);
+}
+
+/// Find the closest floor cell to `pos`
+fn nearest_free(grid: &Grid<Option<StuffTag>>, pos: Vec2) -> Option<Vec2> {
+    grid.iter()
+        .filter(|(_, t)| t.is_none())
+        .min_by_key(|(p, _)| p.manhatten(pos))
+        .map(|(p, _)| p)
+}
+
+/// place doors in room gaps
+///
+/// tunnels between room A and B may cut through room C so use a separate loop to fill doors
+/// instead of eagerly in the connector loop
+fn place_doors(grid: &mut Grid<Option<StuffTag>>, rooms: &[RectRoom]) {
     for room in rooms.iter() {
         for p in iter_edge(room) {
             if grid[p].is_none() {
@@ -499,46 +560,82 @@ fn build_rooms(grid: &mut Grid<Option<StuffTag>>, props: &MapGenProps, floor: u3
             }
         }
     }
+}
 
-    if floor == 1 {
-        // give a starting item on floor 1
-        place_items(&mut rng, grid, &rooms[0], 1, 1, &entity_weights);
+/// Split the floor of an irregular map into blocks usable as rooms
+///
+/// Blocks with less than `min_floor` walkable cells are skipped. The returned rooms' bounds are
+/// set so that placing entities inside them only picks cells of the block.
+fn floor_regions(
+    rng: &mut impl RngExt,
+    grid: &Grid<Option<StuffTag>>,
+    block_size: i32,
+    min_floor: usize,
+) -> Vec<RectRoom> {
+    let mut regions = Vec::new();
+    for y in (1..grid.height() - 1).step_by(block_size as usize) {
+        for x in (1..grid.width() - 1).step_by(block_size as usize) {
+            let w = block_size.min(grid.width() - 1 - x);
+            let h = block_size.min(grid.height() - 1 - y);
+            let mut floor = 0;
+            grid.scan_range(
+                [Vec2::new(x, y), Vec2::new(x + w - 1, y + h - 1)],
+                |_, t| floor += t.is_none() as usize,
+            );
+            if floor >= min_floor {
+                // entities are placed in (min, max]
+                regions.push(RectRoom::new(RoomKind::Normal, x - 1, y - 1, w, h));
+            }
+        }
     }
-    // spawn the player in the first room
-    grid[rooms[0].center()] = Some(StuffTag::Player);
+    regions.shuffle(rng);
+    regions
+}
 
-    // place stuff
-    for room in &rooms[1..] {
-        match room.role {
-            RoomKind::Shop => {
-                place_shop(&mut rng, grid, room);
+/// Keep only the largest connected area of floor, fill the rest with walls
+///
+/// Returns the number of floor cells kept
+fn keep_largest_area(grid: &mut Grid<Option<StuffTag>>) -> usize {
+    let mut labels = Grid::<u32>::new(grid.dims());
+    let mut best = (0, 0);
+    let mut label = 0;
+    let mut todo = Vec::new();
+    for y in 0..grid.height() {
+        for x in 0..grid.width() {
+            let start = Vec2::new(x, y);
+            if grid[start].is_some() || labels[start] != 0 {
+                continue;
             }
-            RoomKind::Normal => {
-                let n = place_entities(
-                    &mut rng,
-                    grid,
-                    room,
-                    props.max_monsters_per_floor,
-                    &entity_weights,
-                );
-                // ensure no room is empty by placing at least 1 item in rooms with no monsters
-                let min_items = (n == 0) as u32;
-                place_items(
-                    &mut rng,
-                    grid,
-                    room,
-                    min_items,
-                    props.max_items_per_floor,
-                    &entity_weights,
-                );
+            label += 1;
+            let mut size = 0;
+            labels[start] = label;
+            todo.push(start);
+            while let Some(p) = todo.pop() {
+                size += 1;
+                for d in [
+                    Vec2::new(0, -1),
+                    Vec2::new(0, 1),
+                    Vec2::new(-1, 0),
+                    Vec2::new(1, 0),
+                ] {
+                    let n = p + d;
+                    if grid.at(n.x, n.y) == Some(&None) && labels[n] == 0 {
+                        labels[n] = label;
+                        todo.push(n);
+                    }
+                }
+            }
+            if size > best.1 {
+                best = (label, size);
             }
         }
     }
-
-    let end_room = rooms[1..]
-        .choose(&mut rng)
-        .expect("Expected more than 1 room");
-    place_stairs(&mut rng, grid, end_room);
+    for (p, l) in labels.iter() {
+        if *l != best.0 {
+            grid[p] = Some(StuffTag::Wall);
+        }
+    }
+    best.1
 }
 
 fn tunnel_between(mut rng: impl RngExt, start: Vec2, end: Vec2) -> TunnelIter {
@@ -0,0 +1,97 @@
+//! Binary space partitioning: the map is split recursively, every leaf gets a room
+use std::ops::Range;
+
+use rand::RngExt;
+use tracing::debug;
+
+use super::{MapGenProps, MapGenerator, place_doors, rect_room::RectRoom, tunnel_between};
+use crate::{
+    components::{GameRng, StuffTag},
+    game_config::RoomKind,
+    grid::Grid,
+    math::Vec2,
+};
+
+/// Smallest size of a leaf, including the walls around its room
+const MIN_LEAF: i32 = 10;
+
+pub struct BspGenerator;
+
+impl MapGenerator for BspGenerator {
+    fn carve(
+        &self,
+        rng: &mut GameRng,
+        grid: &mut Grid<Option<StuffTag>>,
+        props: &MapGenProps,
+    ) -> Vec<RectRoom> {
+        let mut rooms = Vec::new();
+        let max = Vec2::new(grid.width() - 2, grid.height() - 2);
+        split(rng, grid, props, Vec2::splat(1), max, &mut rooms);
+        place_doors(grid, &rooms);
+        rooms
+    }
+}
+
+/// Partition the area between `min` and `max` (inclusive) and carve a room into every leaf
+///
+/// Siblings are connected by a tunnel. Returns the indices of the rooms in this subtree.
+fn split(
+    rng: &mut GameRng,
+    grid: &mut Grid<Option<StuffTag>>,
+    props: &MapGenProps,
+    min: Vec2,
+    max: Vec2,
+    rooms: &mut Vec<RectRoom>,
+) -> Range<usize> {
+    let size = max - min + Vec2::splat(1);
+    let split_x = size.x >= 2 * MIN_LEAF;
+    let split_y = size.y >= 2 * MIN_LEAF;
+    if !split_x && !split_y {
+        rooms.push(carve_leaf(rng, grid, props, min, max));
+        return rooms.len() - 1..rooms.len();
+    }
+    // prefer splitting the longer side, so leaves don't end up as long corridors
+    let vertical = match (split_x, split_y) {
+        (true, true) if size.x == size.y => rng.random_bool(0.5),
+        (true, true) => size.x > size.y,
+        (x, _) => x,
+    };
+    let (a, b) = if vertical {
+        let at = rng.random_range(min.x + MIN_LEAF..=max.x + 1 - MIN_LEAF);
+        ([min, Vec2::new(at - 1, max.y)], [Vec2::new(at, min.y), max])
+    } else {
+        let at = rng.random_range(min.y + MIN_LEAF..=max.y + 1 - MIN_LEAF);
+        ([min, Vec2::new(max.x, at - 1)], [Vec2::new(min.x, at), max])
+    };
+    let left = split(rng, grid, props, a[0], a[1], rooms);
+    let right = split(rng, grid, props, b[0], b[1], rooms);
+
+    let c1 = rooms[rng.random_range(left.clone())].center();
+    let c2 = rooms[rng.random_range(right.clone())].center();
+    for p in tunnel_between(&mut *rng, c1, c2) {
+        grid[p] = None;
+    }
+    left.start..right.end
+}
+
+fn carve_leaf(
+    rng: &mut GameRng,
+    grid: &mut Grid<Option<StuffTag>>,
+    props: &MapGenProps,
+    min: Vec2,
+    max: Vec2,
+) -> RectRoom {
+    // leave a wall on every side of the leaf
+    let available = max - min - Vec2::splat(1);
+    let min_size = props.room_min_size as i32;
+    let max_size = props.room_max_size as i32;
+    let width = rng.random_range(min_size.min(available.x)..=max_size.min(available.x));
+    let height = rng.random_range(min_size.min(available.y)..=max_size.min(available.y));
+    let x = rng.random_range(min.x + 1..=max.x - width);
+    let y = rng.random_range(min.y + 1..=max.y - height);
+
+    debug!(?x, ?y, ?width, ?height, "carving room");
+    let room = RectRoom::new(RoomKind::Normal, x, y, width - 1, height - 1);
+    room.carve(grid);
+    room
+}
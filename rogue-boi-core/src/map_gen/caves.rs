@@ -0,0 +1,69 @@
+//! Caves grown by a cellular automaton
+use rand::RngExt;
+use tracing::debug;
+
+use super::{MapGenProps, MapGenerator, floor_regions, keep_largest_area, rect_room::RectRoom};
+use crate::{
+    components::{GameRng, StuffTag},
+    grid::Grid,
+    math::Vec2,
+};
+
+/// Chance of a cell starting out as a wall
+const WALL_CHANCE: f64 = 0.45;
+const SMOOTHING_STEPS: usize = 5;
+const MAX_ATTEMPTS: usize = 10;
+const REGION_SIZE: i32 = 12;
+const MIN_REGION_FLOOR: usize = 20;
+
+pub struct CavesGenerator;
+
+impl MapGenerator for CavesGenerator {
+    fn carve(
+        &self,
+        rng: &mut GameRng,
+        grid: &mut Grid<Option<StuffTag>>,
+        _props: &MapGenProps,
+    ) -> Vec<RectRoom> {
+        let dims = grid.dims();
+        let mut walls = Grid::<bool>::new(dims);
+        for attempt in 0..MAX_ATTEMPTS {
+            for (p, wall) in walls.iter_mut() {
+                *wall = is_border(dims, p) || rng.random_bool(WALL_CHANCE);
+            }
+            for _ in 0..SMOOTHING_STEPS {
+                walls = smooth(&walls);
+            }
+            for (p, wall) in walls.iter() {
+                grid[p] = wall.then_some(StuffTag::Wall);
+            }
+            // disconnected pockets would be unreachable
+            let area = keep_largest_area(grid);
+            debug!(attempt, area, "Grew cave");
+            if area as i32 >= dims.x * dims.y / 3 {
+                break;
+            }
+        }
+        floor_regions(rng, grid, REGION_SIZE, MIN_REGION_FLOOR)
+    }
+}
+
+/// A cell becomes a wall if most of its 3x3 neighbourhood are walls
+fn smooth(walls: &Grid<bool>) -> Grid<bool> {
+    let mut result = Grid::new(walls.dims());
+    for (p, wall) in result.iter_mut() {
+        let mut n = 0;
+        for y in -1..=1 {
+            for x in -1..=1 {
+                // outside of the map counts as wall
+                n += *walls.at(p.x + x, p.y + y).unwrap_or(&true) as i32;
+            }
+        }
+        *wall = is_border(walls.dims(), p) || n >= 5;
+    }
+    result
+}
+
+fn is_border(dims: Vec2, p: Vec2) -> bool {
+    p.x == 0 || p.y == 0 || p.x == dims.x - 1 || p.y == dims.y - 1
+}
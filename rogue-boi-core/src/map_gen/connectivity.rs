@@ -0,0 +1,95 @@
+//! Checks that everything on a floor is reachable from the player's spawn
+use rand::RngExt;
+use tracing::debug;
+
+use super::tunnel_between;
+use crate::{components::StuffTag, grid::Grid, math::Vec2};
+
+/// Maximum number of tunnels dug before giving up on a floor
+const MAX_REPAIRS: u32 = 32;
+
+/// Statistics of the last generated floor
+#[derive(Debug, Clone, Copy, Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MapGenStats {
+    /// Number of floors generated, including the rejected ones
+    pub attempts: u32,
+    /// Disconnected regions joined by an extra tunnel
+    pub repaired_regions: u32,
+    /// Walls removed by the extra tunnels
+    pub carved_cells: u32,
+    /// Walkable cells reachable from the spawn
+    pub reachable_cells: u32,
+}
+
+/// Monsters and items can be dealt with, so only walls block the way
+fn is_solid(tag: Option<StuffTag>) -> bool {
+    matches!(tag, Some(StuffTag::Wall | StuffTag::Torch))
+}
+
+fn flood_fill(grid: &Grid<Option<StuffTag>>, start: Vec2) -> Grid<bool> {
+    let mut reached = Grid::new(grid.dims());
+    reached[start] = true;
+    let mut todo = vec![start];
+    while let Some(p) = todo.pop() {
+        for n in [
+            Vec2::new(p.x, p.y - 1),
+            Vec2::new(p.x, p.y + 1),
+            Vec2::new(p.x - 1, p.y),
+            Vec2::new(p.x + 1, p.y),
+        ] {
+            if grid.at(n.x, n.y).is_some_and(|t| !is_solid(*t)) && !reached[n] {
+                reached[n] = true;
+                todo.push(n);
+            }
+        }
+    }
+    reached
+}
+
+/// Connect every walkable cell to the player's spawn by digging extra tunnels
+///
+/// Returns false if the floor could not be repaired
+pub(super) fn connect_regions(
+    rng: &mut impl RngExt,
+    grid: &mut Grid<Option<StuffTag>>,
+    stats: &mut MapGenStats,
+) -> bool {
+    let Some(spawn) = grid
+        .iter()
+        .find(|(_, t)| **t == Some(StuffTag::Player))
+        .map(|(p, _)| p)
+    else {
+        return false;
+    };
+    let mut repairs = 0;
+    loop {
+        let reached = flood_fill(grid, spawn);
+        let orphan = grid
+            .iter()
+            .find(|(p, t)| !is_solid(**t) && !reached[*p])
+            .map(|(p, _)| p);
+        let Some(orphan) = orphan else {
+            stats.reachable_cells = reached.iter().filter(|(_, r)| **r).count() as u32;
+            return true;
+        };
+        if repairs == MAX_REPAIRS {
+            return false;
+        }
+        let target = reached
+            .iter()
+            .filter(|(_, r)| **r)
+            .min_by_key(|(p, _)| p.manhatten(orphan))
+            .map(|(p, _)| p)
+            .expect("The spawn is always reached");
+        debug!(%orphan, %target, "Connecting unreachable region");
+        for p in tunnel_between(&mut *rng, orphan, target) {
+            if is_solid(grid[p]) {
+                grid[p] = None;
+                stats.carved_cells += 1;
+            }
+        }
+        repairs += 1;
+        stats.repaired_regions += 1;
+    }
+}
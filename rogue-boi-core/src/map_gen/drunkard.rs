@@ -0,0 +1,59 @@
+//! Drunkard's walk: a random walker digs through solid rock
+use rand::seq::IndexedRandom as _;
+use tracing::debug;
+
+use super::{MapGenProps, MapGenerator, floor_regions, rect_room::RectRoom};
+use crate::{
+    components::{GameRng, StuffTag},
+    grid::Grid,
+    math::Vec2,
+};
+
+/// Portion of the map dug out
+const COVERAGE: f64 = 0.4;
+/// Steps before the walker is moved to a random dug cell, so the map branches out
+const WALK_LENGTH: usize = 200;
+const REGION_SIZE: i32 = 10;
+const MIN_REGION_FLOOR: usize = 15;
+
+pub struct DrunkardGenerator;
+
+impl MapGenerator for DrunkardGenerator {
+    fn carve(
+        &self,
+        rng: &mut GameRng,
+        grid: &mut Grid<Option<StuffTag>>,
+        _props: &MapGenProps,
+    ) -> Vec<RectRoom> {
+        const DIRECTIONS: [Vec2; 4] = [
+            Vec2::new(0, -1),
+            Vec2::new(0, 1),
+            Vec2::new(-1, 0),
+            Vec2::new(1, 0),
+        ];
+        let target = ((grid.width() - 2) * (grid.height() - 2)) as f64 * COVERAGE;
+        let target = target as usize;
+
+        let mut pos = grid.dims() / 2;
+        let mut dug = Vec::with_capacity(target);
+        let mut steps = 0;
+        while dug.len() < target {
+            if grid[pos].take().is_some() {
+                dug.push(pos);
+            }
+            steps += 1;
+            if steps % WALK_LENGTH == 0 {
+                // only dug cells are used, so the map stays connected
+                pos = *dug.choose(&mut *rng).unwrap();
+                continue;
+            }
+            let next = pos + *DIRECTIONS.choose(&mut *rng).unwrap();
+            // keep the outer walls
+            if 0 < next.x && next.x < grid.width() - 1 && 0 < next.y && next.y < grid.height() - 1 {
+                pos = next;
+            }
+        }
+        debug!(steps, dug = dug.len(), "Drunkard finished");
+        floor_regions(rng, grid, REGION_SIZE, MIN_REGION_FLOOR)
+    }
+}
@@ -0,0 +1,120 @@
+//! Stamps hand made prefabs into generated floors
+use rand::{RngExt, distr::weighted::WeightedIndex, prelude::Distribution};
+use tracing::debug;
+
+use super::{EntityChances, rect_room::RectRoom, tunnel_between};
+use crate::{
+    components::StuffTag,
+    game_config::{PREFABS, Prefab, PrefabCell},
+    grid::Grid,
+    math::Vec2,
+};
+
+#[derive(Debug, Clone, Copy)]
+struct Orientation {
+    /// number of quarter turns
+    rotations: u8,
+    mirror: bool,
+}
+
+impl Orientation {
+    fn dims(self, prefab: &Prefab) -> Vec2 {
+        if self.rotations % 2 == 0 {
+            Vec2::new(prefab.width, prefab.height)
+        } else {
+            Vec2::new(prefab.height, prefab.width)
+        }
+    }
+
+    /// Map a position of the oriented prefab back to the authored layout
+    fn source(self, prefab: &Prefab, pos: Vec2) -> Vec2 {
+        let mut pos = pos;
+        let mut dims = self.dims(prefab);
+        for _ in 0..self.rotations {
+            pos = Vec2::new(pos.y, dims.x - 1 - pos.x);
+            dims = Vec2::new(dims.y, dims.x);
+        }
+        if self.mirror {
+            pos.x = dims.x - 1 - pos.x;
+        }
+        pos
+    }
+}
+
+/// Stamp a random prefab of the floor over the room
+///
+/// The prefab is surrounded by a ring of floor, so passages crossing its area stay connected.
+/// Returns false if no prefab fits the map.
+pub(super) fn stamp_vault(
+    rng: &mut impl RngExt,
+    grid: &mut Grid<Option<StuffTag>>,
+    room: &RectRoom,
+    floor: u32,
+    weights: &EntityChances,
+) -> bool {
+    // +2 for the ring, +2 for the outer walls of the map
+    let max_size = grid.width().min(grid.height()) - 4;
+    let candidates = PREFABS
+        .iter()
+        .filter(|p| p.level <= floor && p.width.max(p.height) <= max_size)
+        .collect::<Vec<_>>();
+    let Ok(dist) = WeightedIndex::new(candidates.iter().map(|p| p.weight)) else {
+        return false;
+    };
+    let prefab = candidates[dist.sample(rng)];
+    let orientation = Orientation {
+        rotations: rng.random_range(0..4),
+        mirror: rng.random_bool(0.5),
+    };
+    let dims = orientation.dims(prefab) + Vec2::splat(2);
+    let center = room.center();
+    let min = Vec2::new(
+        (center.x - dims.x / 2).clamp(1, grid.width() - 1 - dims.x),
+        (center.y - dims.y / 2).clamp(1, grid.height() - 1 - dims.y),
+    );
+    let max = min + dims - Vec2::splat(1);
+    debug!(prefab = prefab.name, ?orientation, %min, "Stamping prefab");
+
+    let enemy_dist = WeightedIndex::new(&weights.enemy_weights[..]).unwrap();
+    let item_dist = WeightedIndex::new(&weights.item_weights[..]).unwrap();
+    for y in 0..dims.y {
+        for x in 0..dims.x {
+            let ring = x == 0 || y == 0 || x == dims.x - 1 || y == dims.y - 1;
+            let cell = if ring {
+                PrefabCell::Floor
+            } else {
+                let src = orientation.source(prefab, Vec2::new(x - 1, y - 1));
+                prefab.at(src.x, src.y)
+            };
+            grid[min + Vec2::new(x, y)] = match cell {
+                PrefabCell::Floor => None,
+                PrefabCell::Stuff(tag) => Some(tag),
+                PrefabCell::Enemy => Some(weights.enemy_tags[enemy_dist.sample(rng)]),
+                PrefabCell::Item => Some(weights.item_tags[item_dist.sample(rng)]),
+            };
+        }
+    }
+
+    // connect the ring to the closest open cell, if it doesn't touch one already
+    let distance = |p: Vec2| {
+        let dx = (min.x - p.x).max(p.x - max.x).max(0);
+        let dy = (min.y - p.y).max(p.y - max.y).max(0);
+        dx + dy
+    };
+    let closest = grid
+        .iter()
+        .filter(|(p, t)| distance(*p) > 0 && **t != Some(StuffTag::Wall))
+        .min_by_key(|(p, _)| distance(*p))
+        .map(|(p, _)| p);
+    if let Some(target) = closest
+        && distance(target) > 1
+    {
+        // the closest point of the ring, so the tunnel never crosses the prefab
+        let start = Vec2::new(target.x.clamp(min.x, max.x), target.y.clamp(min.y, max.y));
+        debug!(%start, %target, "Connecting prefab");
+        for p in tunnel_between(&mut *rng, start, target) {
+            grid[p] = None;
+        }
+    }
+    true
+}
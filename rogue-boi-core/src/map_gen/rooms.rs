@@ -0,0 +1,174 @@
+//! Random rectangular rooms, connected by L shaped tunnels
+use rand::RngExt;
+use tracing::debug;
+
+use super::{MapGenProps, MapGenerator, place_doors, rect_room::RectRoom, tunnel_between};
+use crate::{
+    components::{GameRng, StuffTag},
+    game_config::RoomKind,
+    grid::Grid,
+    math::Vec2,
+};
+
+/// Scatters non-overlapping rooms and connects them by a minimum spanning tree of tunnels
+pub struct RoomsGenerator;
+
+impl MapGenerator for RoomsGenerator {
+    fn carve(
+        &self,
+        rng: &mut GameRng,
+        grid: &mut Grid<Option<StuffTag>>,
+        props: &MapGenProps,
+    ) -> Vec<RectRoom> {
+        let mut rooms = Vec::<RectRoom>::with_capacity(props.max_rooms as usize);
+        'outer: for _ in 0..props.max_rooms {
+            let width = rng.random_range(props.room_min_size..props.room_max_size) as i32;
+            let height = rng.random_range(props.room_min_size..props.room_max_size) as i32;
+
+            // -3 so all rooms have walls, even those that touch the end of the map
+            const PADDING: i32 = 3;
+            let x = rng.random_range(PADDING..grid.width() - 1 - PADDING - width);
+            let y = rng.random_range(PADDING..grid.height() - 1 - PADDING - height);
+
+            let room = RectRoom::new(RoomKind::Normal, x, y, width, height);
+            for r in rooms.iter() {
+                if room.touches(r) {
+                    continue 'outer;
+                }
+            }
+            debug!(?x, ?y, ?width, ?height, "carving room");
+            // increment at the end, so if the previous loop can trigger a retry
+            room.carve(grid);
+            rooms.push(room);
+        }
+
+        debug!(?rooms, "Rooms");
+
+        let tree = minimum_spanning_tree(&rooms);
+        let mut edges = tree.edges;
+        let mut adjacency = Grid::new(Vec2::splat(rooms.len() as i32));
+        for [i, j] in edges.iter() {
+            adjacency_set(&mut adjacency, *i as i32, *j as i32, 1);
+        }
+
+        // let's put some edges back into the graph to produce some circles
+        // the way we connect can produce circles by itself, but it's not that reliable
+        // with less than 3 rooms the spanning tree already connects every pair
+        let extra_edges = if rooms.len() < 3 {
+            0
+        } else {
+            (rooms.len() / 4).max(1)
+        };
+        for _ in 0..extra_edges {
+            let [i, j] = loop {
+                let [i, j] = [
+                    rng.random_range(0..rooms.len()) as i32,
+                    rng.random_range(0..rooms.len()) as i32,
+                ];
+                if i != j && adjacency[Vec2::new(i, j)] == 0 {
+                    break [i, j];
+                }
+            };
+            adjacency_set(&mut adjacency, i, j, 1);
+            edges.push([i as u32, j as u32]);
+        }
+
+        'edges: while let Some([i, j]) = edges.pop() {
+            let r1 = &rooms[i as usize];
+            let r2 = &rooms[j as usize];
+            let c1 = r1.center();
+            let c2 = r2.center();
+
+            // if the tunnel would cut through a room replace it with two tunnels
+            let tunnel = tunnel_between(&mut *rng, c1, c2);
+            for (k, room) in rooms.iter().enumerate() {
+                if k == i as usize || k == j as usize {
+                    continue;
+                }
+                if room.intersects_segment(tunnel.current, tunnel.corner)
+                    || room.intersects_segment(tunnel.end, tunnel.corner)
+                {
+                    debug!(i, j, k, "Splitting tunnel");
+                    // avoid duplicate edges to the same pair of rooms
+                    adjacency_set(&mut adjacency, i as i32, j as i32, 0);
+
+                    if adjacency[Vec2::new(i as i32, k as i32)] == 0 {
+                        adjacency_set(&mut adjacency, i as i32, k as i32, 1);
+                        edges.push([i, k as u32]);
+                    }
+                    if adjacency[Vec2::new(j as i32, k as i32)] == 0 {
+                        adjacency_set(&mut adjacency, i as i32, k as i32, 1);
+                        edges.push([j, k as u32]);
+                    }
+                    continue 'edges;
+                }
+            }
+
+            for p in tunnel {
+                grid[p] = None;
+            }
+        }
+
+        place_doors(grid, &rooms);
+        rooms
+    }
+}
+
+struct Mst {
+    pub edges: Vec<[u32; 2]>,
+    #[allow(unused)]
+    pub parents: Vec<i32>,
+}
+
+/// return edges, which are indices into `points`
+///
+/// the edges in the fully connected graph are sorted by the rooms' manhatten distance
+fn minimum_spanning_tree(points: &[RectRoom]) -> Mst {
+    let mut f = Vec::with_capacity(points.len());
+    let mut edges = points
+        .iter()
+        .enumerate()
+        .skip(1)
+        .flat_map(|(i, a)| {
+            points[..i]
+                .iter()
+                .enumerate()
+                .map(move |(j, b)| (a.center().manhatten(b.center()), [i, j]))
+        })
+        .collect::<Vec<_>>();
+    edges.sort_by_key(|(w, _)| *w);
+
+    let mut parents = vec![-1; points.len()];
+
+    'edges: for (_, [i, j]) in edges {
+        for mut ii in [i, j] {
+            while parents[ii] != -1 {
+                if parents[ii] == j as i32 {
+                    // graph would have a circle
+                    continue 'edges;
+                }
+                ii = parents[ii] as usize;
+            }
+        }
+        if parents[j] == -1 {
+            parents[j] = i as i32;
+            f.push([i as u32, j as u32]);
+            if f.len() == points.len() {
+                break 'edges;
+            }
+        }
+    }
+
+    debug_assert_eq!(
+        1,
+        parents.iter().filter(|i| i == &&-1).count(),
+        "there must be exactly 1 node with no parent in the minimum spanning tree"
+    );
+
+    Mst { edges: f, parents }
+}
+
+fn adjacency_set(matrix: &mut Grid<i8>, i: i32, j: i32, val: i8) {
+    matrix[Vec2::new(i, j)] = val;
+    matrix[Vec2::new(j, i)] = val;
+}
@@ -3,9 +3,7 @@ use std::{
     ops::{Add, AddAssign, Div, Index, IndexMut, Mul, Neg, Sub, SubAssign},
 };
 
-use wasm_bindgen::prelude::*;
-
-#[wasm_bindgen]
+#[cfg_attr(feature = "web", wasm_bindgen::prelude::wasm_bindgen)]
 #[derive(
     serde::Serialize, serde::Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq, Hash,
 )]
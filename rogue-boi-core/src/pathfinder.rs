@@ -1,12 +1,20 @@
-use crate::{HashMap, Stuff, components::Walkable, grid::Grid, math::Vec2};
+use crate::{
+    HashMap, Stuff,
+    components::{Ruleset, Walkable},
+    grid::Grid,
+    math::Vec2,
+};
 use arrayvec::ArrayVec;
-use cecs::query::Query;
+use cecs::{entity_id::EntityId, query::Query};
 use smallvec::SmallVec;
-use std::collections::BinaryHeap;
+use std::{cmp::Reverse, collections::BinaryHeap};
 use tracing::debug;
 
 pub type Path = SmallVec<[Vec2; 16]>;
 
+/// Additional cost of stepping on a tile that is taken by an entity
+pub const OCCUPIED_COST: i32 = 25;
+
 #[derive(Eq, Clone, Copy, Debug)]
 struct Node {
     hcost: i32,
@@ -43,7 +51,7 @@ impl Ord for Node {
 
 /// return wether a path was found
 ///
-/// uses manhatten distance
+/// steps and distances follow the `ruleset`
 ///
 /// stops at 1 tile from `to`
 ///
@@ -53,36 +61,51 @@ pub fn find_path(
     to: Vec2,
     grid: &Grid<Stuff>,
     walkies: &Query<&Walkable>,
+    ruleset: Ruleset,
     path: &mut Path,
 ) -> bool {
-    let mut open_set = BinaryHeap::with_capacity(from.manhatten(to) as usize);
+    find_path_over(from, to, grid, walkies, ruleset, |_| true, path)
+}
+
+/// [find_path] that only steps on tiles for which `can_enter` returns true
+pub fn find_path_over(
+    from: Vec2,
+    to: Vec2,
+    grid: &Grid<Stuff>,
+    walkies: &Query<&Walkable>,
+    ruleset: Ruleset,
+    can_enter: impl Fn(Vec2) -> bool,
+    path: &mut Path,
+) -> bool {
+    let mut open_set = BinaryHeap::with_capacity(ruleset.distance(from, to) as usize);
     let mut came_from = HashMap::default();
     let mut gcost = HashMap::default();
 
     open_set.push(Node {
-        hcost: from.manhatten(to),
+        hcost: ruleset.distance(from, to),
         gcost: 0,
         pos: from,
     });
     gcost.insert(from, 0);
 
     while let Some(current) = open_set.pop() {
-        if current.pos.manhatten(to) <= 1 {
+        if ruleset.is_adjacent(current.pos, to) {
             reconstruct_path(from, current.pos, &came_from, path);
             return true;
         }
         let new_g = current.gcost + 1;
 
-        let new_neighbours: ArrayVec<_, 4> = [Vec2::X, Vec2::Y, -Vec2::X, -Vec2::Y]
+        let new_neighbours: ArrayVec<_, 8> = ruleset
+            .directions()
             .iter()
             .map(|x| current.pos + *x)
-            .filter(|pos| grid.contains(pos.x, pos.y))
+            .filter(|pos| grid.contains(pos.x, pos.y) && can_enter(*pos))
             .map(move |pos| {
                 let mut cost = new_g;
                 // add additional cost for positions that have an entity on them
                 // so there is always a path to the target
                 if grid[pos].is_some() && walkies.fetch(grid[pos].unwrap()).is_none() {
-                    cost += 25;
+                    cost += OCCUPIED_COST;
                 }
                 (pos, cost)
             })
@@ -95,7 +118,7 @@ pub fn find_path(
             came_from.insert(neighbour, current.pos);
             gcost.insert(neighbour, new_g);
             open_set.push(Node {
-                hcost: neighbour.manhatten(to),
+                hcost: ruleset.distance(neighbour, to),
                 gcost: new_g,
                 pos: neighbour,
             });
@@ -105,13 +128,121 @@ pub fn find_path(
     false
 }
 
-fn reconstruct_path(target: Vec2, mut pos: Vec2, came_from: &HashMap<Vec2, Vec2>, path: &mut Path) {
-    path.push(pos);
-    while let Some(p) = came_from.get(&pos) {
-        pos = *p;
+/// `from` is not included in the path
+fn reconstruct_path(from: Vec2, mut pos: Vec2, came_from: &HashMap<Vec2, Vec2>, path: &mut Path) {
+    while pos != from {
         path.push(pos);
-        if pos.manhatten(target) <= 1 {
-            return;
+        match came_from.get(&pos) {
+            Some(p) => pos = *p,
+            None => return,
         }
     }
 }
+
+/// Distance of every tile to the closest goal
+///
+/// Computed once and shared by every monster walking towards the same goals, as opposed to
+/// running [find_path] for each of them
+#[derive(Clone)]
+pub struct DijkstraMap(pub Grid<i32>);
+
+impl Default for DijkstraMap {
+    fn default() -> Self {
+        Self(Grid::new(Vec2::ZERO))
+    }
+}
+
+impl DijkstraMap {
+    pub const UNREACHABLE: i32 = i32::MAX;
+
+    /// `seeds` are the goals and their initial values
+    ///
+    /// `entity_cost` is the additional cost of walking through an entity, `None` if it can't be
+    /// walked through
+    pub fn compute(
+        &mut self,
+        seeds: impl IntoIterator<Item = (Vec2, i32)>,
+        grid: &Grid<Stuff>,
+        ruleset: Ruleset,
+        entity_cost: impl Fn(EntityId) -> Option<i32>,
+    ) {
+        if self.0.dims() != grid.dims() {
+            self.0 = Grid::new(grid.dims());
+        }
+        self.0.fill(Self::UNREACHABLE);
+
+        let mut open_set = BinaryHeap::new();
+        for (pos, value) in seeds {
+            if grid.contains(pos.x, pos.y) && value < self.0[pos] {
+                self.0[pos] = value;
+                open_set.push(Reverse((value, pos.x, pos.y)));
+            }
+        }
+        while let Some(Reverse((value, x, y))) = open_set.pop() {
+            let pos = Vec2::new(x, y);
+            if value > self.0[pos] {
+                // stale entry, a cheaper visit was already processed
+                continue;
+            }
+            for neighbour in ruleset.directions().iter().map(|d| pos + *d) {
+                let Some(stuff) = grid.at(neighbour.x, neighbour.y) else {
+                    continue;
+                };
+                let cost = match stuff {
+                    Some(id) => match entity_cost(*id) {
+                        Some(cost) => 1 + cost,
+                        None => continue,
+                    },
+                    None => 1,
+                };
+                let new_value = value + cost;
+                if new_value < self.0[neighbour] {
+                    self.0[neighbour] = new_value;
+                    open_set.push(Reverse((new_value, neighbour.x, neighbour.y)));
+                }
+            }
+        }
+    }
+
+    /// Compute a map that leads away from the goals of `self` into `out`
+    ///
+    /// Scaling the inverted distances makes fleeing monsters prefer open areas over dead ends
+    /// right next to their goal
+    pub fn flee_map(
+        &self,
+        out: &mut DijkstraMap,
+        grid: &Grid<Stuff>,
+        ruleset: Ruleset,
+        entity_cost: impl Fn(EntityId) -> Option<i32>,
+    ) {
+        let seeds = self
+            .0
+            .iter()
+            .filter(|(_, d)| **d != Self::UNREACHABLE)
+            .map(|(pos, d)| (pos, -(*d * 6 / 5)));
+        out.compute(seeds, grid, ruleset, entity_cost);
+    }
+
+    /// Neighbour of `pos` that is the most downhill and can be entered
+    pub fn downhill(
+        &self,
+        pos: Vec2,
+        ruleset: Ruleset,
+        can_enter: impl Fn(Vec2) -> bool,
+    ) -> Option<Vec2> {
+        let current = self
+            .0
+            .at(pos.x, pos.y)
+            .copied()
+            .unwrap_or(Self::UNREACHABLE);
+        ruleset
+            .directions()
+            .iter()
+            .map(|d| pos + *d)
+            .filter(|p| can_enter(*p))
+            .filter_map(|p| Some((p, *self.0.at(p.x, p.y)?)))
+            .filter(|(_, d)| *d < current)
+            .min_by_key(|(_, d)| *d)
+            .map(|(p, _)| p)
+    }
+}
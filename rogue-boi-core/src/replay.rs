@@ -0,0 +1,163 @@
+//! Recording of player actions, so a run can be re-driven from its seed
+//!
+//! Actions are keyed by the simulation step they were issued in. A step is a tick in which the
+//! world consumed the pending player actions, so playback does not depend on frame timing.
+use base64::Engine;
+use cecs::entity_id::EntityId;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{BASE64_ENGINE, DesiredStat, InputEvent, Keymap, Ruleset, TravelGoal, math::Vec2};
+
+pub const REPLAY_VERSION: u32 = 1;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReplayError {
+    #[error("Failed to decode replay: {0}")]
+    Decode(String),
+    #[error("Unsupported replay version {0}, expected {REPLAY_VERSION}")]
+    Version(u32),
+    #[error("Replay desynced at step {step}")]
+    Desync { step: u64 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ReplayAction {
+    Input(InputEvent),
+    UseItem(EntityId),
+    UnequipItem(EntityId),
+    DropItem(EntityId),
+    Wait,
+    SetTarget(EntityId),
+    Select(EntityId),
+    Click(Vec2),
+    CancelItemUse,
+    SetLevelupStat(DesiredStat),
+    BuyItem(usize),
+    SellItem(EntityId),
+    Fire,
+    SetKeymap(Keymap),
+    AutoExplore,
+    Travel(TravelGoal),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplayEntry {
+    /// Number of simulation steps before the action was issued
+    pub step: u64,
+    /// [GameTick](crate::components::GameTick) at the time of the action, informational only
+    pub tick: i32,
+    pub action: ReplayAction,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Replay {
+    pub version: u32,
+    pub seed: u64,
+    #[serde(default)]
+    pub ruleset: Ruleset,
+    /// Number of simulation steps at the end of the recording
+    pub steps: u64,
+    pub entries: Vec<ReplayEntry>,
+}
+
+impl Replay {
+    pub fn new(seed: u64, ruleset: Ruleset) -> Self {
+        Self {
+            version: REPLAY_VERSION,
+            seed,
+            ruleset,
+            steps: 0,
+            entries: Vec::with_capacity(1024),
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        let mut result = Vec::<u8>::with_capacity(self.entries.len() * 8);
+        ciborium::into_writer(self, &mut result).expect("failed to serialize replay");
+        BASE64_ENGINE.encode(result)
+    }
+
+    pub fn decode(pl: &str) -> Result<Self, ReplayError> {
+        let pl = BASE64_ENGINE
+            .decode(pl)
+            .map_err(|err| ReplayError::Decode(err.to_string()))?;
+        let replay: Self = ciborium::from_reader(pl.as_slice())
+            .map_err(|err| ReplayError::Decode(err.to_string()))?;
+        if replay.version != REPLAY_VERSION {
+            return Err(ReplayError::Version(replay.version));
+        }
+        Ok(replay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CoreError, GameSession};
+
+    const SEED: u64 = 0x5eed;
+
+    fn key_down(key: &str) -> InputEvent {
+        InputEvent::KeyDown {
+            key: key.to_owned(),
+            ctrl: false,
+            shift: false,
+            alt: false,
+        }
+    }
+
+    /// Walk around for a couple of turns
+    fn record_session() -> GameSession {
+        let mut session = GameSession::new();
+        session.new_game_with_seed(SEED);
+        for key in ["d", "d", "s", "s", "a", " ", "w", "d"] {
+            session.push_event(key_down(key));
+            session.tick(100);
+        }
+        session.wait();
+        session.tick(100);
+        session
+    }
+
+    #[test]
+    fn playback_reproduces_the_recorded_game() {
+        let mut recorded = record_session();
+        let replay = recorded.replay().expect("new games are recorded");
+        assert!(replay.steps > 0);
+        let expected = serde_json::to_value(recorded.output()).unwrap();
+
+        let replay = Replay::decode(&replay.encode()).unwrap();
+        let mut playback = GameSession::new();
+        playback.play_replay(&replay).unwrap();
+
+        assert_eq!(playback.seed(), SEED);
+        assert_eq!(serde_json::to_value(playback.output()).unwrap(), expected);
+    }
+
+    #[test]
+    fn unsupported_version_is_rejected() {
+        let mut replay = Replay::new(SEED, Ruleset::default());
+        replay.version = REPLAY_VERSION + 1;
+        let result = Replay::decode(&replay.encode());
+        assert!(
+            matches!(result, Err(ReplayError::Version(v)) if v == REPLAY_VERSION + 1),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn missing_actions_desync() {
+        // the world can't advance without player actions
+        let mut replay = Replay::new(SEED, Ruleset::default());
+        replay.steps = 3;
+        let mut playback = GameSession::new();
+        let result = playback.play_replay(&replay);
+        assert!(
+            matches!(
+                result,
+                Err(CoreError::Replay(ReplayError::Desync { step: 0 }))
+            ),
+            "{result:?}"
+        );
+    }
+}
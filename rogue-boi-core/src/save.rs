@@ -0,0 +1,629 @@
+//! Save files and their format versioning
+//!
+//! A save is a [SaveHeader] and the persisted world, the header can be read without loading the
+//! world. Saves from before the header was introduced hold the world only.
+//!
+//! Saves are migrated on the CBOR [Value] level, before the [World](cecs::World) is
+//! deserialized. When changing the shape of a persisted component or resource bump
+//! [SAVE_VERSION] and register a migration from the previous version in [MIGRATIONS].
+//!
+//! Persisted items are keyed by their type path: `Component_<path>` holds a list of
+//! `[entity, value]` pairs, `Resource_<path>` holds the value itself.
+mod slots;
+
+use std::{any::type_name, fmt::Display};
+
+use base64::Engine;
+use ciborium::Value;
+use serde_derive::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{
+    BASE64_ENGINE,
+    components::{
+        AiState, AmbientLight, CharacterName, DungeonFloor, GameRng, GameTick, Level, Ranged,
+        Ruleset, StatusEffect, StatusEffects, StatusKind, StuffTag,
+    },
+    game_config::STUFF_PROTOTYPES,
+};
+
+pub use self::slots::{MemoryStorage, SaveSlots, SlotInfo, SlotStorage};
+
+pub const SAVE_VERSION: SaveVersion = SaveVersion(2, 8, 0);
+
+const VERSION_KEY: &str = "__version__";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SaveVersion(pub u16, pub u16, pub u16);
+
+impl SaveVersion {
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.split('.').map(|x| x.parse::<u16>());
+        let major = parts.next()?.ok()?;
+        let minor = parts.next()?.ok()?;
+        let patch = parts.next()?.ok()?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Self(major, minor, patch))
+    }
+
+    pub fn to_cecs(self) -> cecs::serde::Version {
+        cecs::serde::Version::new(self.0.into(), self.1.into(), self.2.into())
+    }
+}
+
+impl Display for SaveVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoadError {
+    #[error("Failed to decode save: {0}")]
+    Decode(String),
+    #[error("Save has no valid version")]
+    MissingVersion,
+    #[error("Save version {found} is newer than the supported {supported}")]
+    TooNew {
+        found: SaveVersion,
+        supported: SaveVersion,
+    },
+    #[error("No migration from save version {0}")]
+    NoMigration(SaveVersion),
+    #[error("Failed to migrate save from version {from}: {reason}")]
+    Migration { from: SaveVersion, reason: String },
+    #[error("Failed to load world: {0}")]
+    World(String),
+    #[error("Save slot {0} is empty")]
+    EmptySlot(String),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+struct Migration {
+    from: SaveVersion,
+    to: SaveVersion,
+    migrate: fn(&mut Value) -> Result<(), String>,
+}
+
+/// Sorted by version
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: SaveVersion(2, 0, 0),
+        to: SaveVersion(2, 1, 0),
+        migrate: add_game_rng,
+    },
+    Migration {
+        from: SaveVersion(2, 1, 0),
+        to: SaveVersion(2, 2, 0),
+        migrate: add_character_name,
+    },
+    Migration {
+        from: SaveVersion(2, 2, 0),
+        to: SaveVersion(2, 3, 0),
+        migrate: add_ambient_light,
+    },
+    Migration {
+        from: SaveVersion(2, 3, 0),
+        to: SaveVersion(2, 4, 0),
+        migrate: add_ranged_equipment,
+    },
+    Migration {
+        from: SaveVersion(2, 4, 0),
+        to: SaveVersion(2, 5, 0),
+        migrate: add_status_effects,
+    },
+    Migration {
+        from: SaveVersion(2, 5, 0),
+        to: SaveVersion(2, 6, 0),
+        migrate: add_evasion,
+    },
+    Migration {
+        from: SaveVersion(2, 6, 0),
+        to: SaveVersion(2, 7, 0),
+        migrate: add_ai_state,
+    },
+    Migration {
+        from: SaveVersion(2, 7, 0),
+        to: SaveVersion(2, 8, 0),
+        migrate: add_ruleset,
+    },
+];
+
+/// Summary of a save, readable without loading the world
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SaveHeader {
+    pub character_name: String,
+    pub player_level: u32,
+    pub dungeon_floor: u32,
+    pub tick: i32,
+    /// Milliseconds since the unix epoch, 0 if unknown
+    pub timestamp: u64,
+    pub seed: u64,
+    pub version: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SaveFile {
+    header: SaveHeader,
+    world: Value,
+}
+
+/// Reads the header of a [SaveFile] and skips the world
+#[derive(Deserialize)]
+struct SaveFileHeader {
+    header: SaveHeader,
+}
+
+/// Encode a persisted world, see [WorldSer](crate::WorldSer)
+pub fn encode_save(world: Value, timestamp: u64) -> String {
+    let header = read_header(&world, SAVE_VERSION, timestamp);
+    let mut result = Vec::<u8>::with_capacity(36000);
+    ciborium::into_writer(&SaveFile { header, world }, &mut result).expect("failed to serialize");
+    debug!("cbor size {}", result.len());
+    BASE64_ENGINE.encode(result)
+}
+
+/// Decode the persisted world and bring it up to [SAVE_VERSION]
+pub fn decode_save(pl: &str) -> Result<Value, LoadError> {
+    let bytes = BASE64_ENGINE
+        .decode(pl)
+        .map_err(|err| LoadError::Decode(err.to_string()))?;
+    let mut world = decode_world(&bytes)?;
+    migrate(&mut world)?;
+    Ok(world)
+}
+
+/// The persisted world at the version it was saved with
+fn decode_world(bytes: &[u8]) -> Result<Value, LoadError> {
+    let save: Value =
+        ciborium::from_reader(bytes).map_err(|err| LoadError::Decode(err.to_string()))?;
+    if entry(&save, VERSION_KEY).is_some() {
+        // saves from before the header was introduced
+        return Ok(save);
+    }
+    let file: SaveFile = save
+        .deserialized()
+        .map_err(|err| LoadError::Decode(err.to_string()))?;
+    Ok(file.world)
+}
+
+pub fn peek_save(pl: &str) -> Result<SaveHeader, LoadError> {
+    let bytes = BASE64_ENGINE
+        .decode(pl)
+        .map_err(|err| LoadError::Decode(err.to_string()))?;
+    if let Ok(SaveFileHeader { header }) = ciborium::from_reader(bytes.as_slice()) {
+        return Ok(header);
+    }
+    let mut world = decode_world(&bytes)?;
+    // report the version the save was written with, not the one it was migrated to
+    let version = save_version(&world)?;
+    migrate(&mut world)?;
+    Ok(read_header(&world, version, 0))
+}
+
+/// `world` must be at [SAVE_VERSION], `version` is the one the save was written with
+fn read_header(world: &Value, version: SaveVersion, timestamp: u64) -> SaveHeader {
+    let floor = resource::<DungeonFloor>(world).map(|f| f.current);
+    let tick = resource::<GameTick>(world).map(|t| t.0);
+    let seed = resource::<GameRng>(world).map(|rng| rng.seed);
+    let name = resource::<CharacterName>(world).map(|n| n.0);
+    // only the player levels up
+    let level = entry(world, &format!("Component_{}", type_name::<Level>()))
+        .and_then(|list| list.as_array()?.first()?.as_array()?.get(1))
+        .and_then(|level| level.deserialized::<Level>().ok())
+        .map(|level| level.current_level);
+    SaveHeader {
+        character_name: name.unwrap_or_default(),
+        player_level: level.unwrap_or_default(),
+        dungeon_floor: floor.unwrap_or_default(),
+        tick: tick.unwrap_or_default(),
+        timestamp,
+        seed: seed.unwrap_or_default(),
+        version: version.to_string(),
+    }
+}
+
+fn resource<T: serde::de::DeserializeOwned>(world: &Value) -> Option<T> {
+    entry(world, &format!("Resource_{}", type_name::<T>()))?
+        .deserialized()
+        .ok()
+}
+
+/// Bring the save up to [SAVE_VERSION]
+pub fn migrate(save: &mut Value) -> Result<(), LoadError> {
+    let found = save_version(save)?;
+    if found > SAVE_VERSION {
+        return Err(LoadError::TooNew {
+            found,
+            supported: SAVE_VERSION,
+        });
+    }
+    let mut version = found;
+    while version < SAVE_VERSION {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|m| m.from == version)
+            .ok_or(LoadError::NoMigration(version))?;
+        (migration.migrate)(save).map_err(|reason| LoadError::Migration {
+            from: version,
+            reason,
+        })?;
+        debug!(from = %migration.from, to = %migration.to, "Migrated save");
+        version = migration.to;
+    }
+    insert_entry(save, VERSION_KEY, Value::Text(SAVE_VERSION.to_string()));
+    Ok(())
+}
+
+fn save_version(save: &Value) -> Result<SaveVersion, LoadError> {
+    entry(save, VERSION_KEY)
+        .and_then(|v| v.as_text())
+        .and_then(SaveVersion::parse)
+        .ok_or(LoadError::MissingVersion)
+}
+
+fn entry<'a>(save: &'a Value, key: &str) -> Option<&'a Value> {
+    save.as_map()?
+        .iter()
+        .find(|(k, _)| k.as_text() == Some(key))
+        .map(|(_, v)| v)
+}
+
+fn entry_mut<'a>(save: &'a mut Value, key: &str) -> Option<&'a mut Value> {
+    save.as_map_mut()?
+        .iter_mut()
+        .find(|(k, _)| k.as_text() == Some(key))
+        .map(|(_, v)| v)
+}
+
+fn insert_entry(save: &mut Value, key: &str, value: Value) {
+    let Some(map) = save.as_map_mut() else {
+        return;
+    };
+    match map.iter_mut().find(|(k, _)| k.as_text() == Some(key)) {
+        Some((_, v)) => *v = value,
+        None => map.push((Value::Text(key.to_owned()), value)),
+    }
+}
+
+fn remove_entry(save: &mut Value, key: &str) -> Option<Value> {
+    let map = save.as_map_mut()?;
+    let i = map.iter().position(|(k, _)| k.as_text() == Some(key))?;
+    Some(map.remove(i).1)
+}
+
+/// Remove a persisted component, returns its `(entity, value)` pairs
+fn take_components<T: serde::de::DeserializeOwned>(
+    save: &mut Value,
+    key: &str,
+) -> Result<Vec<(Value, T)>, String> {
+    let Some(Value::Array(list)) = remove_entry(save, key) else {
+        return Ok(Vec::new());
+    };
+    list.into_iter()
+        .map(|pair| {
+            let Value::Array(pair) = pair else {
+                return Err(format!("{key} entry is not a pair"));
+            };
+            let [entity, value]: [Value; 2] = pair
+                .try_into()
+                .map_err(|_| format!("{key} entry is not a pair"))?;
+            let value = value.deserialized().map_err(|err| err.to_string())?;
+            Ok((entity, value))
+        })
+        .collect()
+}
+
+/// Iterate over the persisted values of a component
+fn components_mut<'a>(save: &'a mut Value, key: &str) -> impl Iterator<Item = &'a mut Value> {
+    entry_mut(save, key)
+        .and_then(|list| list.as_array_mut())
+        .into_iter()
+        .flatten()
+        .filter_map(|pair| pair.as_array_mut()?.get_mut(1))
+}
+
+fn add_game_rng(save: &mut Value) -> Result<(), String> {
+    let rng = Value::serialized(&GameRng::from_entropy()).map_err(|err| err.to_string())?;
+    insert_entry(save, "Resource_rogue_boi_core::components::GameRng", rng);
+    Ok(())
+}
+
+fn add_character_name(save: &mut Value) -> Result<(), String> {
+    let name = Value::serialized(&CharacterName::default()).map_err(|err| err.to_string())?;
+    insert_entry(
+        save,
+        "Resource_rogue_boi_core::components::CharacterName",
+        name,
+    );
+    Ok(())
+}
+
+/// Floors of older saves have no light sources, keep them fully lit
+fn add_ambient_light(save: &mut Value) -> Result<(), String> {
+    let light = Value::serialized(&AmbientLight(u8::MAX)).map_err(|err| err.to_string())?;
+    insert_entry(
+        save,
+        "Resource_rogue_boi_core::components::AmbientLight",
+        light,
+    );
+    Ok(())
+}
+
+/// Adds the ranged equipment slot and gives the player the base ranged stats new characters have
+fn add_ranged_equipment(save: &mut Value) -> Result<(), String> {
+    for equipment in components_mut(save, "Component_rogue_boi_core::components::Equipment") {
+        let map = equipment
+            .as_map_mut()
+            .ok_or_else(|| "Equipment is not a map".to_owned())?;
+        map.push((Value::Text("ranged".to_owned()), Value::Null));
+    }
+
+    let player = entry(save, "Component_rogue_boi_core::components::StuffTag")
+        .and_then(|tags| tags.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|pair| pair.as_array())
+        .find(|pair| pair.get(1).and_then(|tag| tag.as_text()) == Some("Player"))
+        .and_then(|pair| pair.first().cloned());
+
+    if let Some(player) = player {
+        let ranged = Value::serialized(&Ranged {
+            power: 0,
+            range: 0,
+            skill: 3,
+        })
+        .map_err(|err| err.to_string())?;
+        let key = "Component_rogue_boi_core::components::Ranged";
+        if entry(save, key).is_none() {
+            insert_entry(save, key, Value::Array(Vec::new()));
+        }
+        entry_mut(save, key)
+            .and_then(|list| list.as_array_mut())
+            .ok_or_else(|| "Ranged components are not a list".to_owned())?
+            .push(Value::Array(vec![player, ranged]));
+    }
+
+    let key = "Component_rogue_boi_core::components::Ammo";
+    if entry(save, key).is_none() {
+        insert_entry(save, key, Value::Array(Vec::new()));
+    }
+    Ok(())
+}
+
+/// Poison and confusion used to be separate components, merge them into [StatusEffects]
+fn add_status_effects(save: &mut Value) -> Result<(), String> {
+    #[derive(Deserialize)]
+    struct Poisoned {
+        duration: i32,
+        power: i32,
+    }
+    #[derive(Deserialize)]
+    struct ConfusedAi {
+        duration: i32,
+    }
+
+    let poisoned =
+        take_components::<Poisoned>(save, "Component_rogue_boi_core::components::Poisoned")?
+            .into_iter()
+            .map(|(id, p)| (id, StatusKind::Poison, p.duration, p.power));
+    let confused =
+        take_components::<ConfusedAi>(save, "Component_rogue_boi_core::components::ConfusedAi")?
+            .into_iter()
+            .map(|(id, c)| (id, StatusKind::Confusion, c.duration, 0));
+
+    let mut effects: Vec<(Value, StatusEffects)> = Vec::new();
+    for (id, kind, duration, power) in poisoned.chain(confused) {
+        if duration <= 0 {
+            continue;
+        }
+        let effect = StatusEffect::new(kind, duration as u32, power);
+        match effects.iter_mut().find(|(e, _)| *e == id) {
+            Some((_, fx)) => fx.add(effect),
+            None => effects.push((
+                id,
+                StatusEffects {
+                    effects: vec![effect],
+                },
+            )),
+        }
+    }
+    let list = effects
+        .into_iter()
+        .map(|(id, fx)| {
+            let fx = Value::serialized(&fx).map_err(|err| err.to_string())?;
+            Ok(Value::Array(vec![id, fx]))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    insert_entry(
+        save,
+        "Component_rogue_boi_core::status_effects::StatusEffects",
+        Value::Array(list),
+    );
+    Ok(())
+}
+
+/// Defense gained evasion, take it from the prototypes of the entity and of its equipped armor
+fn add_evasion(save: &mut Value) -> Result<(), String> {
+    let tags: Vec<(Value, StuffTag)> =
+        entry(save, "Component_rogue_boi_core::components::StuffTag")
+            .and_then(|tags| tags.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|pair| pair.as_array())
+            .filter_map(|pair| {
+                let tag = pair.get(1)?.deserialized().ok()?;
+                Some((pair.first()?.clone(), tag))
+            })
+            .collect();
+    let evasion_of = |id: &Value| -> i32 {
+        tags.iter()
+            .find(|(e, _)| e == id)
+            .and_then(|(_, tag)| STUFF_PROTOTYPES.get(tag)?.defense)
+            .map(|defense| defense.evasion)
+            .unwrap_or(0)
+    };
+    let armors: Vec<(Value, Value)> =
+        entry(save, "Component_rogue_boi_core::components::Equipment")
+            .and_then(|list| list.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|pair| pair.as_array())
+            .filter_map(|pair| {
+                let id = pair.first()?.clone();
+                let armor = entry(pair.get(1)?, "armor")?;
+                (!armor.is_null()).then(|| (id, armor.clone()))
+            })
+            .collect();
+
+    let Some(list) = entry_mut(save, "Component_rogue_boi_core::components::Defense") else {
+        return Ok(());
+    };
+    for pair in list
+        .as_array_mut()
+        .ok_or_else(|| "Defense components are not a list".to_owned())?
+    {
+        let [id, defense] = pair
+            .as_array_mut()
+            .map(Vec::as_mut_slice)
+            .ok_or_else(|| "Defense entry is not a pair".to_owned())?
+        else {
+            return Err("Defense entry is not a pair".to_owned());
+        };
+        let mut evasion = evasion_of(&*id);
+        if let Some((_, armor)) = armors.iter().find(|(e, _)| e == &*id) {
+            evasion += evasion_of(armor);
+        }
+        defense
+            .as_map_mut()
+            .ok_or_else(|| "Defense is not a map".to_owned())?
+            .push((
+                Value::Text("evasion".to_owned()),
+                Value::Integer(evasion.into()),
+            ));
+    }
+    Ok(())
+}
+
+/// Monsters of older saves keep the stateless behaviour: guard their post and hunt on sight
+fn add_ai_state(save: &mut Value) -> Result<(), String> {
+    let idle = Value::serialized(&AiState::Idle).map_err(|err| err.to_string())?;
+    let list = entry(save, "Component_rogue_boi_core::components::StuffTag")
+        .and_then(|tags| tags.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|pair| pair.as_array())
+        .filter_map(|pair| {
+            let tag: StuffTag = pair.get(1)?.deserialized().ok()?;
+            STUFF_PROTOTYPES.get(&tag)?.ai_behaviour?;
+            Some(Value::Array(vec![pair.first()?.clone(), idle.clone()]))
+        })
+        .collect();
+    insert_entry(
+        save,
+        "Component_rogue_boi_core::components::AiState",
+        Value::Array(list),
+    );
+    Ok(())
+}
+
+/// Older runs were played with the default rules
+fn add_ruleset(save: &mut Value) -> Result<(), String> {
+    let ruleset = Value::serialized(&Ruleset::default()).map_err(|err| err.to_string())?;
+    insert_entry(
+        save,
+        "Resource_rogue_boi_core::components::Ruleset",
+        ruleset,
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::de::DeserializeOwned;
+
+    use super::*;
+    use crate::components::{Defense, Equipment};
+
+    /// Saved by version 2.0.0 while standing in a shop, also used by the e2e tests
+    const SHOP_SAVE: &[u8] = include_bytes!("../../test/shop_test_data.bin");
+
+    fn components<T: DeserializeOwned>(world: &Value) -> Vec<T> {
+        entry(world, &format!("Component_{}", type_name::<T>()))
+            .and_then(|list| list.as_array())
+            .into_iter()
+            .flatten()
+            .map(|pair| {
+                let value = pair.as_array().and_then(|pair| pair.get(1)).unwrap();
+                value.deserialized().unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn oldest_save_is_migrated_to_the_current_version() {
+        let world = decode_save(&BASE64_ENGINE.encode(SHOP_SAVE)).unwrap();
+
+        let version = entry(&world, VERSION_KEY).and_then(|v| v.as_text());
+        assert_eq!(version, Some(SAVE_VERSION.to_string().as_str()));
+        assert!(resource::<GameRng>(&world).is_some());
+        assert!(resource::<CharacterName>(&world).is_some());
+        assert_eq!(resource::<AmbientLight>(&world).map(|l| l.0), Some(u8::MAX));
+        assert_eq!(resource::<Ruleset>(&world), Some(Ruleset::default()));
+
+        let tags = components::<StuffTag>(&world);
+        assert!(tags.contains(&StuffTag::Player));
+        assert!(tags.contains(&StuffTag::Shop));
+        // the player got the ranged slot and stats
+        assert!(!components::<Equipment>(&world).is_empty());
+        assert!(!components::<Ranged>(&world).is_empty());
+        // fails to deserialize without evasion
+        assert!(!components::<Defense>(&world).is_empty());
+        let _ = components::<StatusEffects>(&world);
+        let monsters = tags
+            .iter()
+            .filter(|tag| STUFF_PROTOTYPES[*tag].ai_behaviour.is_some())
+            .count();
+        assert_eq!(components::<AiState>(&world).len(), monsters);
+    }
+
+    #[test]
+    fn migrated_save_loads() {
+        let mut session = crate::GameSession::new();
+        session.load(&BASE64_ENGINE.encode(SHOP_SAVE)).unwrap();
+        assert!(session.output().player.is_some());
+    }
+
+    #[test]
+    fn legacy_save_header_keeps_its_version() {
+        let header = peek_save(&BASE64_ENGINE.encode(SHOP_SAVE)).unwrap();
+        assert_eq!(header.version, SaveVersion(2, 0, 0).to_string());
+        assert_eq!(header.timestamp, 0);
+    }
+
+    #[test]
+    fn newer_save_is_rejected() {
+        let world = Value::Map(vec![(
+            Value::Text(VERSION_KEY.to_owned()),
+            Value::Text("99.0.0".to_owned()),
+        )]);
+        let mut pl = Vec::new();
+        ciborium::into_writer(&world, &mut pl).unwrap();
+
+        let result = decode_save(&BASE64_ENGINE.encode(pl));
+        assert!(
+            matches!(
+                result,
+                Err(LoadError::TooNew {
+                    found: SaveVersion(99, 0, 0),
+                    supported: SAVE_VERSION,
+                })
+            ),
+            "{result:?}"
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use serde_derive::Serialize;
+use tracing::warn;
+
+use super::{LoadError, SaveHeader, peek_save};
+use crate::{CoreError, GameSession, HashMap};
+
+const SLOT_PREFIX: &str = "rogue-boi-slot:";
+
+/// Key-value store holding the encoded saves
+pub trait SlotStorage {
+    fn read(&self, key: &str) -> Option<String>;
+    fn write(&mut self, key: &str, value: &str) -> Result<(), String>;
+    fn remove(&mut self, key: &str);
+    fn keys(&self) -> Vec<String>;
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct MemoryStorage(pub HashMap<String, String>);
+
+impl SlotStorage for MemoryStorage {
+    fn read(&self, key: &str) -> Option<String> {
+        self.0.get(key).cloned()
+    }
+
+    fn write(&mut self, key: &str, value: &str) -> Result<(), String> {
+        self.0.insert(key.to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) {
+        self.0.remove(key);
+    }
+
+    fn keys(&self) -> Vec<String> {
+        self.0.keys().cloned().collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SlotInfo {
+    pub slot: String,
+    pub header: SaveHeader,
+}
+
+/// Named saves on top of a [SlotStorage]
+pub struct SaveSlots<S> {
+    storage: S,
+}
+
+impl<S: SlotStorage> SaveSlots<S> {
+    pub fn new(storage: S) -> Self {
+        Self { storage }
+    }
+
+    /// Readable slots, most recent first
+    pub fn list(&self) -> Vec<SlotInfo> {
+        let mut result = self
+            .storage
+            .keys()
+            .into_iter()
+            .filter_map(|key| {
+                let slot = key.strip_prefix(SLOT_PREFIX)?.to_owned();
+                match self.peek(&slot) {
+                    Ok(header) => Some(SlotInfo { slot, header }),
+                    Err(err) => {
+                        warn!(slot, ?err, "Failed to read save slot");
+                        None
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+        result.sort_by(|a, b| b.header.timestamp.cmp(&a.header.timestamp));
+        result
+    }
+
+    pub fn peek(&self, slot: &str) -> Result<SaveHeader, LoadError> {
+        let pl = self.read(slot)?;
+        peek_save(&pl)
+    }
+
+    pub fn save(
+        &mut self,
+        session: &GameSession,
+        slot: &str,
+        timestamp: u64,
+    ) -> Result<(), CoreError> {
+        let pl = session.save(timestamp);
+        self.storage
+            .write(&slot_key(slot), &pl)
+            .map_err(CoreError::Storage)
+    }
+
+    pub fn load(&self, session: &mut GameSession, slot: &str) -> Result<(), CoreError> {
+        let pl = self.read(slot)?;
+        session.load(&pl)
+    }
+
+    pub fn delete(&mut self, slot: &str) {
+        self.storage.remove(&slot_key(slot));
+    }
+
+    fn read(&self, slot: &str) -> Result<String, LoadError> {
+        self.storage
+            .read(&slot_key(slot))
+            .ok_or_else(|| LoadError::EmptySlot(slot.to_owned()))
+    }
+}
+
+fn slot_key(slot: &str) -> String {
+    format!("{SLOT_PREFIX}{slot}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SAVE_VERSION;
+
+    fn session(seed: u64) -> GameSession {
+        let mut session = GameSession::new();
+        session.new_game_with_seed(seed);
+        session
+    }
+
+    #[test]
+    fn slots_are_saved_listed_and_peeked() {
+        let mut storage = MemoryStorage::default();
+        storage.write("settings", "not a save").unwrap();
+        storage.write(&slot_key("broken"), "not a save").unwrap();
+        let mut slots = SaveSlots::new(storage);
+        slots.save(&session(1), "first", 10).unwrap();
+        slots.save(&session(2), "second", 20).unwrap();
+
+        // unrelated keys and unreadable slots are skipped
+        let list = slots.list();
+        let names: Vec<_> = list.iter().map(|info| info.slot.as_str()).collect();
+        assert_eq!(names, ["second", "first"]);
+
+        let header = slots.peek("first").unwrap();
+        assert_eq!(header.timestamp, 10);
+        assert_eq!(header.seed, 1);
+        assert_eq!(header.dungeon_floor, 1);
+        assert_eq!(header.version, SAVE_VERSION.to_string());
+    }
+
+    #[test]
+    fn slots_are_loaded_and_deleted() {
+        let mut slots = SaveSlots::new(MemoryStorage::default());
+        slots.save(&session(3), "slot", 0).unwrap();
+
+        let mut loaded = GameSession::new();
+        slots.load(&mut loaded, "slot").unwrap();
+        assert_eq!(loaded.seed(), 3);
+
+        slots.delete("slot");
+        assert!(slots.list().is_empty());
+        assert!(matches!(slots.peek("slot"), Err(LoadError::EmptySlot(_))));
+        assert!(matches!(
+            slots.load(&mut loaded, "slot"),
+            Err(CoreError::Load(LoadError::EmptySlot(_)))
+        ));
+    }
+}
@@ -0,0 +1,815 @@
+//! Platform independent front-end of the simulation.
+//!
+//! The wasm `Core` is a thin adapter over [GameSession], which can also be driven natively, e.g.
+//! from tests or scripts.
+use anyhow::Context as _;
+use cecs::prelude::*;
+use rand::RngExt as _;
+use tracing::debug;
+
+use crate::{
+    CoreError, EquipmentDesc, HashMap, InputEvent, ItemDesc, ItemPropsQ, Keymap, MapGenParams,
+    PlayerActions, RenderedOutput, Stuff, WorldDe, WorldSer,
+    archetypes::{self, init_entity},
+    colors::{self, WHITE},
+    components::*,
+    default_world,
+    grid::Grid,
+    init_world, init_world_systems, init_world_transient_resources, map_gen,
+    math::Vec2,
+    replay::{Replay, ReplayAction, ReplayEntry, ReplayError},
+    save::{self, LoadError},
+    systems::{
+        self, handle_click, perform_drop_item, regenerate_dungeon, update_output, update_unequip,
+    },
+    to_item_desc,
+};
+
+/// Items in the player's inventory at the start of a new game
+const STARTING_ITEMS: &[StuffTag] = &[StuffTag::Lantern];
+
+/// Owns a game world and exposes the player facing operations on it
+pub struct GameSession {
+    world: World,
+    /// Number of ticks in which the world consumed the player's actions
+    steps: u64,
+    /// Actions since the start of the current game, [None] after loading a save
+    recording: Option<Replay>,
+    /// Rules of the next new game
+    ruleset: Ruleset,
+    /// Kept between games and loads
+    keymap: Keymap,
+}
+
+impl Default for GameSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GameSession {
+    pub fn new() -> Self {
+        Self {
+            world: default_world(),
+            steps: 0,
+            recording: None,
+            ruleset: Ruleset::default(),
+            keymap: Keymap::default(),
+        }
+    }
+
+    /// Seed of the current run
+    pub fn seed(&self) -> u64 {
+        self.world.get_resource::<GameRng>().unwrap().seed
+    }
+
+    pub fn character_name(&self) -> &str {
+        &self.world.get_resource::<CharacterName>().unwrap().0
+    }
+
+    pub fn set_character_name(&mut self, name: impl Into<String>) {
+        self.world.insert_resource(CharacterName(name.into()));
+    }
+
+    /// Rules of the current game
+    pub fn ruleset(&self) -> Ruleset {
+        *self.world.get_resource::<Ruleset>().unwrap()
+    }
+
+    /// Rules of the next new game, the current game keeps its rules
+    pub fn set_ruleset(&mut self, ruleset: Ruleset) {
+        self.ruleset = ruleset;
+    }
+
+    pub fn keymap(&self) -> &Keymap {
+        &self.keymap
+    }
+
+    /// Conflicting keymaps are rejected
+    pub fn set_keymap(&mut self, keymap: Keymap) -> Result<(), CoreError> {
+        keymap.validate()?;
+        self.keymap = keymap.clone();
+        self.apply_keymap(keymap);
+        Ok(())
+    }
+
+    /// Recorded, so playback translates the keys the same way
+    fn apply_keymap(&mut self, keymap: Keymap) {
+        self.record(ReplayAction::SetKeymap(keymap.clone()));
+        self.world.insert_resource(keymap);
+    }
+
+    pub fn world(&self) -> &World {
+        &self.world
+    }
+
+    pub fn world_mut(&mut self) -> &mut World {
+        &mut self.world
+    }
+
+    /// Start a new game with a random seed
+    pub fn restart(&mut self) {
+        self.new_game_with_seed(rand::rng().random());
+    }
+
+    /// Start a new game, the same seed and inputs produce the same run
+    pub fn new_game_with_seed(&mut self, seed: u64) {
+        self.start_game(seed, self.ruleset);
+    }
+
+    fn start_game(&mut self, seed: u64, ruleset: Ruleset) {
+        let name = self.character_name().to_owned();
+        // start from a fresh world, so entity ids are reproducible as well
+        self.world = default_world();
+        self.world.insert_resource(CharacterName(name));
+        self.steps = 0;
+        self.recording = Some(Replay::new(seed, ruleset));
+        // the keys are translated by the core, playback needs the same keymap
+        self.apply_keymap(self.keymap.clone());
+        let world = &mut self.world;
+
+        world.insert_resource(ruleset);
+        world.insert_resource(DeltaTime(0));
+        world.insert_resource(GameTick::default());
+        world.insert_resource(DungeonFloor::default());
+        world.insert_resource(Selected::default());
+        world.insert_resource(AppMode::Game);
+        world.insert_resource(GameRng::from_seed(seed));
+
+        let log = world.get_resource_mut::<LogHistory>().unwrap();
+        log.push(WHITE, "Hello wanderer!");
+
+        world.run_system(regenerate_dungeon).unwrap();
+        for tag in STARTING_ITEMS.iter().copied() {
+            // make room for the new item
+            world.reserve_entities(1);
+            world
+                .run_system(
+                    move |mut cmd: Commands,
+                          mut grid: ResMut<Grid<Stuff>>,
+                          mut q_player: Query<&mut Inventory, With<PlayerTag>>| {
+                        let Some(inventory) = q_player.single_mut() else {
+                            return;
+                        };
+                        let cmd = init_entity(Vec2::ZERO, tag, &mut cmd, &mut grid);
+                        let Ok(id) = cmd.remove::<Pos>().id() else {
+                            unreachable!("Failed to spawn item");
+                        };
+                        inventory.add(id).unwrap();
+                    },
+                )
+                .unwrap();
+        }
+        self.tick(10000);
+    }
+
+    /// Generate a dungeon without altering any session state
+    ///
+    /// Returns the icons of the generated entities, keyed by their `x;y` position
+    pub fn generate_dungeon(
+        params: MapGenParams,
+    ) -> Result<HashMap<String, &'static str>, CoreError> {
+        if params.dims.x < map_gen::MIN_MAP_SIZE || params.dims.y < map_gen::MIN_MAP_SIZE {
+            return Err(CoreError::InvalidMapDims(params.dims));
+        }
+        let mut world = World::new(params.dims.x as u32 * params.dims.y as u32);
+        init_world(params.dims, &mut world);
+        world.insert_resource(DungeonFloor {
+            current: params.level,
+            desired: params.level,
+        });
+        let mut props = map_gen::MapGenProps::from_level(params.level);
+        if let Some(generator) = params.generator {
+            props.generator = generator;
+        }
+        world.insert_resource(props);
+        if let Some(seed) = params.seed {
+            world.insert_resource(GameRng::from_seed(seed));
+        }
+        world.run_system(map_gen::generate_map).unwrap();
+
+        let map = world.run_view_system(move |tags: Query<(EntityId, &Icon, &Pos)>| {
+            tags.iter()
+                .map(|(_id, icon, pos)| (format!("{};{}", pos.0.x, pos.0.y), icon.0))
+                .collect::<HashMap<_, _>>()
+        });
+        Ok(map)
+    }
+
+    pub fn tick(&mut self, dt_ms: i32) {
+        self.world.insert_resource(DeltaTime(dt_ms));
+        self.world.tick();
+        if self.world.get_resource::<ShouldTick>().unwrap().0 {
+            self.steps += 1;
+        }
+    }
+
+    fn record(&mut self, action: ReplayAction) {
+        let Some(recording) = self.recording.as_mut() else {
+            return;
+        };
+        let tick = self.world.get_resource::<GameTick>().unwrap().0;
+        recording.entries.push(ReplayEntry {
+            step: self.steps,
+            tick,
+            action,
+        });
+    }
+
+    /// Recording of the current game, if it was started in this session
+    pub fn replay(&self) -> Option<Replay> {
+        self.recording.clone().map(|mut replay| {
+            replay.steps = self.steps;
+            replay
+        })
+    }
+
+    /// Start a new game from the replay's seed and re-apply its actions
+    ///
+    /// Steps are forced, the recorded frame timing is not reproduced.
+    pub fn play_replay(&mut self, replay: &Replay) -> Result<(), CoreError> {
+        self.start_game(replay.seed, replay.ruleset);
+        let result = self.apply_entries(replay);
+        // the game continues with the player's keys, not the ones of the replay's author
+        if self.world.get_resource::<Keymap>() != Some(&self.keymap) {
+            self.apply_keymap(self.keymap.clone());
+        }
+        result
+    }
+
+    fn apply_entries(&mut self, replay: &Replay) -> Result<(), CoreError> {
+        for entry in replay.entries.iter() {
+            self.advance_to_step(entry.step)?;
+            self.apply(entry.action.clone());
+        }
+        self.advance_to_step(replay.steps)?;
+        Ok(())
+    }
+
+    fn advance_to_step(&mut self, step: u64) -> Result<(), ReplayError> {
+        let dt = self.world.get_resource::<TickInMs>().unwrap().0;
+        while self.steps < step {
+            let before = self.steps;
+            self.tick(dt);
+            if self.steps == before {
+                return Err(ReplayError::Desync { step: before });
+            }
+        }
+        if self.steps != step {
+            return Err(ReplayError::Desync { step: self.steps });
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, action: ReplayAction) {
+        // failed actions are recorded as well, they fail the same way during playback
+        let result = match action {
+            ReplayAction::Input(event) => {
+                self.push_event(event);
+                Ok(())
+            }
+            ReplayAction::UseItem(id) => self.use_item(id),
+            ReplayAction::UnequipItem(id) => self.unequip_item(id),
+            ReplayAction::DropItem(id) => self.drop_item(id),
+            ReplayAction::Wait => {
+                self.wait();
+                Ok(())
+            }
+            ReplayAction::SetTarget(id) => self.set_target(id),
+            ReplayAction::Select(id) => self.set_selection(id),
+            ReplayAction::Click(pos) => {
+                self.click(pos);
+                Ok(())
+            }
+            ReplayAction::CancelItemUse => {
+                self.cancel_item_use();
+                Ok(())
+            }
+            ReplayAction::SetLevelupStat(stat) => {
+                self.set_levelup_stat(stat);
+                Ok(())
+            }
+            ReplayAction::BuyItem(idx) => self.buy_item(idx),
+            ReplayAction::SellItem(id) => self.sell_item(id),
+            ReplayAction::Fire => {
+                self.fire();
+                Ok(())
+            }
+            ReplayAction::AutoExplore => {
+                self.auto_explore();
+                Ok(())
+            }
+            ReplayAction::Travel(goal) => {
+                self.travel(goal);
+                Ok(())
+            }
+            ReplayAction::SetKeymap(keymap) => {
+                // only the world is changed, the player's keymap is restored after playback
+                self.apply_keymap(keymap);
+                Ok(())
+            }
+        };
+        if let Err(err) = result {
+            debug!(%err, "Replayed action failed");
+        }
+    }
+
+    fn ensure_valid(&self, id: EntityId) -> Result<(), CoreError> {
+        if !self.world.is_id_valid(id) {
+            return Err(CoreError::InvalidId(id));
+        }
+        Ok(())
+    }
+
+    pub fn push_event(&mut self, event: InputEvent) {
+        self.record(ReplayAction::Input(event.clone()));
+        let mut inputs = ResMut::<Vec<InputEvent>>::new(&self.world);
+        inputs.push(event);
+    }
+
+    pub fn output(&mut self) -> &RenderedOutput {
+        if self.world.get_resource::<Output>().unwrap().0.is_none() {
+            self.world.run_system(update_output).unwrap();
+        }
+        self.world
+            .get_resource::<Output>()
+            .unwrap()
+            .0
+            .as_ref()
+            .expect("update_output always produces an output")
+    }
+
+    pub fn equipment(&self) -> Option<EquipmentDesc> {
+        let item_props = ItemPropsQ::new(&self.world);
+        Query::<&Equipment, With<PlayerTag>>::new(&self.world)
+            .iter()
+            .next()
+            .map(|equipment| {
+                let desc = |id: Option<EntityId>| {
+                    id.and_then(|id| Some(to_item_desc(id, item_props.fetch(id)?)))
+                };
+                EquipmentDesc {
+                    weapon: desc(equipment.weapon),
+                    armor: desc(equipment.armor),
+                    ranged: desc(equipment.ranged),
+                }
+            })
+    }
+
+    pub fn inventory(&self) -> Option<Vec<ItemDesc>> {
+        let item_props = ItemPropsQ::new(&self.world);
+        Query::<&Inventory, With<PlayerTag>>::new(&self.world)
+            .iter()
+            .next()
+            .map(|inv| {
+                inv.iter()
+                    .filter_map(|id| Some(to_item_desc(id, item_props.fetch(id)?)))
+                    .collect::<Vec<_>>()
+            })
+    }
+
+    pub fn use_item(&mut self, id: EntityId) -> Result<(), CoreError> {
+        self.ensure_valid(id)?;
+        self.record(ReplayAction::UseItem(id));
+        self.world
+            .run_system(|mut cmd: Commands| {
+                cmd.entity(id).insert_bundle((UseItem,));
+            })
+            .unwrap();
+        Ok(())
+    }
+
+    pub fn unequip_item(&mut self, id: EntityId) -> Result<(), CoreError> {
+        self.ensure_valid(id)?;
+        self.record(ReplayAction::UnequipItem(id));
+        self.world
+            .run_system(|mut cmd: Commands| {
+                cmd.entity(id).insert_bundle((Unequip,));
+            })
+            .unwrap();
+        Ok(())
+    }
+
+    pub fn drop_item(&mut self, id: EntityId) -> Result<(), CoreError> {
+        self.ensure_valid(id)?;
+        self.record(ReplayAction::DropItem(id));
+
+        self.world
+            .run_system(
+                |mut cmd: Commands,
+                 mut q: Query<(&Pos, &mut Inventory), With<PlayerTag>>,
+                 q_item: Query<&Name>,
+                 mut log: ResMut<LogHistory>,
+                 mut actions: ResMut<PlayerActions>,
+                 mut rng: ResMut<GameRng>,
+                 grid: Res<Grid<Stuff>>| {
+                    // remove item from inventory and add a position
+                    // TODO: random empty nearby position intead of the player's?
+                    let Some((pos, inv)) = q.single_mut() else {
+                        return Err(CoreError::NoPlayer);
+                    };
+                    let Some(item) = inv.remove(id) else {
+                        return Err(CoreError::NotInInventory(id));
+                    };
+                    if let Some(name) = q_item.fetch(item) {
+                        perform_drop_item(&mut *rng, cmd.entity(id), pos, name, &mut log, &grid);
+                    }
+
+                    actions.insert_empty();
+                    Ok(())
+                },
+            )
+            .unwrap()
+    }
+
+    pub fn wait(&mut self) {
+        self.record(ReplayAction::Wait);
+        self.stop_travel();
+        self.world
+            .get_resource_mut::<PlayerActions>()
+            .unwrap()
+            .insert_wait();
+    }
+
+    /// Shoot the equipped ranged weapon, asks for a target first
+    pub fn fire(&mut self) {
+        self.record(ReplayAction::Fire);
+        self.stop_travel();
+        self.world
+            .get_resource_mut::<PlayerActions>()
+            .unwrap()
+            .insert_fire();
+    }
+
+    /// Keep walking toward unexplored cells until something interesting happens
+    pub fn auto_explore(&mut self) {
+        self.record(ReplayAction::AutoExplore);
+        self.world
+            .get_resource_mut::<Travel>()
+            .unwrap()
+            .start(TravelGoal::Explore);
+    }
+
+    /// Walk to the goal one step per tick, stops on the same events as [Self::auto_explore]
+    pub fn travel(&mut self, goal: TravelGoal) {
+        self.record(ReplayAction::Travel(goal));
+        self.world.get_resource_mut::<Travel>().unwrap().start(goal);
+    }
+
+    /// Wait until the player is fully healed or a monster shows up
+    pub fn rest(&mut self) {
+        self.travel(TravelGoal::Rest);
+    }
+
+    fn stop_travel(&mut self) {
+        self.world.get_resource_mut::<Travel>().unwrap().stop();
+    }
+
+    pub fn set_target(&mut self, id: EntityId) -> Result<(), CoreError> {
+        debug!("set_target {}", id);
+        self.ensure_valid(id)?;
+        self.record(ReplayAction::SetTarget(id));
+        self.world
+            .get_resource_mut::<PlayerActions>()
+            .unwrap()
+            .set_target(id);
+        Ok(())
+    }
+
+    pub fn set_selection(&mut self, id: EntityId) -> Result<(), CoreError> {
+        debug!("set_selection {}", id);
+        self.ensure_valid(id)?;
+        // keymap actions use the selected item, so playback has to select it too
+        self.record(ReplayAction::Select(id));
+        self.world.get_resource_mut::<Selected>().unwrap().0 = Some(id);
+        self.world.run_system(update_output).unwrap();
+        Ok(())
+    }
+
+    /// Select the entity, or target the position at the given grid cell
+    pub fn click(&mut self, pos: Vec2) {
+        self.record(ReplayAction::Click(pos));
+        self.world.insert_resource(ClickPosition(Some(pos)));
+        self.world.run_system(handle_click).unwrap();
+        self.world.run_system(update_output).unwrap();
+    }
+
+    /// [None] if the entity no longer exists, e.g. a selected monster that died
+    pub fn fetch_entity(&mut self, id: EntityId) -> Option<serde_json::Value> {
+        if !self.world.is_id_valid(id) {
+            return None;
+        }
+        self.world
+            .run_system(move |tags: Query<&StuffTag>, q| {
+                let tag = *tags.fetch(id)?;
+                Some(archetypes::stuff_to_json(id, tag, &q))
+            })
+            .unwrap()
+    }
+
+    pub fn cancel_item_use(&mut self) {
+        self.record(ReplayAction::CancelItemUse);
+        let world = &mut self.world;
+        world
+            .run_system(
+                |mut cmd: Commands,
+                 q: Query<EntityId, With<UseItem>>,
+                 q_fire: Query<EntityId, With<Fire>>| {
+                    q.iter().for_each(|id| {
+                        cmd.entity(id).remove::<UseItem>();
+                    });
+                    q_fire.iter().for_each(|id| {
+                        cmd.entity(id).remove::<Fire>();
+                    });
+                },
+            )
+            .unwrap();
+        let mode = world.get_resource_mut::<AppMode>().unwrap();
+        if matches!(*mode, AppMode::Targeting) {
+            *mode = AppMode::Game;
+        }
+        let log = world.get_resource_mut::<LogHistory>().unwrap();
+        log.push(WHITE, "Cancel item use");
+    }
+
+    /// `timestamp` is stored in the save's header, in milliseconds since the unix epoch
+    pub fn save(&self, timestamp: u64) -> String {
+        let ser = WorldSer { world: &self.world };
+        let world = ciborium::Value::serialized(&ser).expect("failed to serialize");
+
+        let encoded = save::encode_save(world, timestamp);
+        debug!("encoded size {}", encoded.len());
+
+        encoded
+    }
+
+    pub fn load(&mut self, pl: &str) -> Result<(), CoreError> {
+        debug!("• loading");
+        let result: Result<(), LoadError> = (|| {
+            let save = save::decode_save(pl)?;
+            let WorldDe { mut world } = save
+                .deserialized()
+                .map_err(|err| LoadError::World(err.to_string()))?;
+
+            let dims = *world
+                .get_resource::<WorldDims>()
+                .context("world has no dims")?;
+            init_world_transient_resources(dims.0, &mut world);
+            world.insert_resource(self.keymap.clone());
+
+            // commands need to be applied between systems, so run them one at a time instead of
+            // iun a SystemStage
+            world
+                .run_system(archetypes::insert_transient_components)
+                .context("Failed to insert transient components")?;
+            world
+                .run_system(systems::init_grids)
+                .context("Failed to init grids")?;
+            world
+                .run_system(systems::update_camera_pos)
+                .context("Failed to update camera")?;
+            world
+                .run_system(systems::update_light_map)
+                .context("Failed to update light map")?;
+            world
+                .run_system(systems::update_fov)
+                .context("Failed to update fov")?;
+            init_world_systems(&mut world);
+
+            self.world = world;
+            // the save has no record of the actions leading up to it
+            self.steps = 0;
+            self.recording = None;
+            self.tick(0);
+            Ok(())
+        })();
+        debug!(?result, "✓ loading");
+        Ok(result?)
+    }
+
+    pub fn set_levelup_stat(&mut self, stat: DesiredStat) {
+        self.record(ReplayAction::SetLevelupStat(stat));
+        self.world.insert_resource(stat);
+    }
+
+    pub fn buy_item(&mut self, item_idx: usize) -> Result<(), CoreError> {
+        self.record(ReplayAction::BuyItem(item_idx));
+        let world = &mut self.world;
+        // make room for the new item
+        world.reserve_entities(1);
+
+        world
+            .run_system(
+                |mut q_shop: Query<&mut Shop, With<MarkActive>>,
+                 mut cmd: Commands,
+                 mut grid: ResMut<Grid<Stuff>>,
+                 mut q_player: Query<(&mut Inventory, &mut CoinPouch), With<PlayerTag>>,
+                 mut log: ResMut<LogHistory>| {
+                    let Some(shop) = q_shop.single_mut() else {
+                        return Err(CoreError::NotInShop);
+                    };
+                    let Some((inventory, coins)) = q_player.single_mut() else {
+                        return Err(CoreError::NoPlayer);
+                    };
+                    if inventory.is_full() {
+                        // TODO: place the item on the ground instead
+                        log.push(colors::IMPOSSIBLE, "Inventory is full");
+                        return Err(CoreError::InventoryFull);
+                    }
+                    match shop.items.get(item_idx).and_then(|x| x.as_ref()) {
+                        Some(item) => {
+                            if coins.0 < item.cost as u32 {
+                                log.push(colors::IMPOSSIBLE, "Not enough coins");
+                                return Err(CoreError::InsufficientCoins {
+                                    cost: item.cost as u32,
+                                });
+                            }
+                            coins.0 -= item.cost as u32;
+                            log.push(colors::WHITE, format!("Purchase {:?}", item.tag));
+
+                            let cmd = init_entity(Vec2::ZERO, item.tag, &mut cmd, &mut grid);
+                            let Ok(id) = cmd.remove::<Pos>().id() else {
+                                unreachable!("Failed to spawn item");
+                            };
+                            inventory.add(id).unwrap();
+
+                            shop.items.get_mut(item_idx).unwrap().take();
+                            Ok(())
+                        }
+                        None => Err(CoreError::InvalidShopIndex(item_idx)),
+                    }
+                },
+            )
+            .unwrap()
+    }
+
+    pub fn sell_item(&mut self, id: EntityId) -> Result<(), CoreError> {
+        self.record(ReplayAction::SellItem(id));
+        let world = &mut self.world;
+
+        // check for eligibility
+        // if item is equipped, then unequip it
+        // then get its value and delete it
+
+        world
+            .run_system(
+                |mut cmd: Commands,
+                 q_player: Query<(&Inventory, &Equipment), With<PlayerTag>>,
+                 q_value: Query<&CoinValue>| {
+                    let Some((inventory, equipment)) = q_player.single() else {
+                        return Err(CoreError::NoPlayer);
+                    };
+                    let in_inventory = inventory.items.contains(&id);
+                    if !in_inventory && !equipment.contains(id) {
+                        return Err(CoreError::NotInInventory(id));
+                    }
+                    if q_value.fetch(id).is_none() {
+                        return Err(CoreError::Unsellable(id));
+                    }
+                    if !in_inventory {
+                        cmd.entity(id).insert(Unequip);
+                    }
+                    Ok(())
+                },
+            )
+            .unwrap()?;
+        world.run_system(update_unequip).unwrap();
+        world
+            .run_system(
+                |mut cmd: Commands,
+                 mut q_player: Query<&mut CoinPouch, With<PlayerTag>>,
+                 mut log: ResMut<LogHistory>,
+                 q_item: Query<(&Name, &CoinValue)>| {
+                    let (item_name, item_value) =
+                        q_item.fetch(id).ok_or(CoreError::Unsellable(id))?;
+                    let Some(coins) = q_player.single_mut() else {
+                        return Err(CoreError::NoPlayer);
+                    };
+                    coins.0 += item_value.0 as u32;
+                    cmd.delete(id);
+                    log.push(
+                        WHITE,
+                        format!("Sell {} for {} coins", item_name.0, item_value.0),
+                    );
+                    Ok(())
+                },
+            )
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEED: u64 = 0xbeef;
+
+    fn key_down(key: &str) -> InputEvent {
+        InputEvent::KeyDown {
+            key: key.to_owned(),
+            ctrl: false,
+            shift: false,
+            alt: false,
+        }
+    }
+
+    fn play(session: &mut GameSession, keys: &[&str]) {
+        for key in keys {
+            session.push_event(key_down(key));
+            session.tick(100);
+        }
+    }
+
+    fn player_pos(session: &GameSession) -> Option<Vec2> {
+        Query::<&Pos, With<PlayerTag>>::new(session.world())
+            .single()
+            .map(|pos| pos.0)
+    }
+
+    /// Every entity with its position, in a stable order
+    fn entities(session: &GameSession) -> Vec<(EntityId, StuffTag, Vec2)> {
+        let mut list = Query::<(EntityId, &StuffTag, &Pos)>::new(session.world())
+            .iter()
+            .map(|(id, tag, pos)| (id, *tag, pos.0))
+            .collect::<Vec<_>>();
+        list.sort_by_key(|(_, tag, pos)| (pos.y, pos.x, *tag as u8));
+        list
+    }
+
+    #[test]
+    fn same_seed_and_inputs_produce_the_same_game() {
+        let keys = ["d", "d", "s", "a", " ", "w", "s", "d", "e"];
+        let mut a = GameSession::new();
+        let mut b = GameSession::new();
+        a.new_game_with_seed(SEED);
+        b.new_game_with_seed(SEED);
+        let map = entities(&a);
+        assert_eq!(map, entities(&b));
+
+        play(&mut a, &keys);
+        play(&mut b, &keys);
+        assert_eq!(entities(&a), entities(&b));
+        assert_eq!(
+            serde_json::to_value(a.output()).unwrap(),
+            serde_json::to_value(b.output()).unwrap()
+        );
+
+        let mut c = GameSession::new();
+        c.new_game_with_seed(SEED + 1);
+        assert_ne!(map, entities(&c));
+    }
+
+    #[test]
+    fn headless_session_plays_saves_and_loads() {
+        let mut session = GameSession::new();
+        session.new_game_with_seed(SEED);
+        assert!(player_pos(&session).is_some());
+
+        play(&mut session, &["d", "s", "a", "w", " "]);
+        assert_eq!(session.steps, 5);
+        let output = session.output().clone();
+        assert!(output.player.is_some());
+        assert_eq!(output.dungeon_level, 1);
+
+        let save = session.save(0);
+        let mut loaded = GameSession::new();
+        loaded.load(&save).unwrap();
+        assert_eq!(player_pos(&loaded), player_pos(&session));
+        assert_eq!(
+            loaded.world().get_resource::<GameTick>().unwrap().0,
+            session.world().get_resource::<GameTick>().unwrap().0
+        );
+
+        // the loaded game keeps running
+        play(&mut loaded, &[" "]);
+        assert_eq!(loaded.steps, 1);
+    }
+
+    #[test]
+    fn smallest_maps_are_generated() {
+        let kinds = [
+            map_gen::MapGeneratorKind::Rooms,
+            map_gen::MapGeneratorKind::Bsp,
+            map_gen::MapGeneratorKind::Caves,
+            map_gen::MapGeneratorKind::Drunkard,
+        ];
+        for generator in kinds {
+            for seed in 0..16 {
+                let map = GameSession::generate_dungeon(MapGenParams {
+                    dims: Vec2::splat(map_gen::MIN_MAP_SIZE),
+                    seed: Some(seed),
+                    generator: Some(generator),
+                    ..Default::default()
+                })
+                .unwrap();
+                assert!(!map.is_empty());
+            }
+        }
+
+        let too_small = GameSession::generate_dungeon(MapGenParams {
+            dims: Vec2::new(map_gen::MIN_MAP_SIZE - 1, 64),
+            ..Default::default()
+        });
+        assert!(matches!(too_small, Err(CoreError::InvalidMapDims(_))));
+    }
+}
@@ -0,0 +1,183 @@
+//! Timed effects on creatures, e.g. poison or slow
+//!
+//! The behaviour of an effect is described by its entry in the registry, see [StatusKind::desc].
+//! Effects that only change hp every turn need no code besides their registry entry.
+use cecs::{entity_id::EntityId, prelude::Commands};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::components::{DamageType, Resistances};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum StatusKind {
+    Poison,
+    Slow,
+    Confusion,
+    Burning,
+    Regeneration,
+}
+
+/// How a new effect combines with an active one of the same kind
+#[derive(Debug, Clone, Copy)]
+pub enum Stacking {
+    /// Durations add up, the stronger power is kept
+    Extend,
+    /// The longer duration and the stronger power are kept
+    Refresh,
+}
+
+#[derive(Debug)]
+pub struct StatusDesc {
+    pub name: &'static str,
+    /// Change of hp every turn, multiplied by the power of the effect
+    pub hp_per_turn: i32,
+    /// Type of the damage dealt every turn, entities immune to it can not be affected
+    pub damage: Option<DamageType>,
+    pub stacking: Stacking,
+    /// Logged when the effect wears off, `{}` is replaced by the name of the entity
+    pub expiry: &'static str,
+}
+
+impl StatusKind {
+    pub fn desc(self) -> &'static StatusDesc {
+        match self {
+            StatusKind::Poison => &StatusDesc {
+                name: "Poison",
+                hp_per_turn: -1,
+                damage: Some(DamageType::Poison),
+                stacking: Stacking::Extend,
+                expiry: "{} is no longer poisoned",
+            },
+            StatusKind::Slow => &StatusDesc {
+                name: "Slow",
+                hp_per_turn: 0,
+                damage: None,
+                stacking: Stacking::Extend,
+                expiry: "{} is no longer slowed",
+            },
+            StatusKind::Confusion => &StatusDesc {
+                name: "Confusion",
+                hp_per_turn: 0,
+                damage: None,
+                stacking: Stacking::Extend,
+                expiry: "{} is no longer confused!",
+            },
+            StatusKind::Burning => &StatusDesc {
+                name: "Burning",
+                hp_per_turn: -1,
+                damage: Some(DamageType::Fire),
+                stacking: Stacking::Refresh,
+                expiry: "The flames on {} die out",
+            },
+            StatusKind::Regeneration => &StatusDesc {
+                name: "Regeneration",
+                hp_per_turn: 1,
+                damage: None,
+                stacking: Stacking::Refresh,
+                expiry: "{} stops regenerating",
+            },
+        }
+    }
+
+    pub fn is_immune(self, resistances: &Resistances) -> bool {
+        self.desc()
+            .damage
+            .is_some_and(|ty| resistances.is_immune(ty))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct StatusEffect {
+    pub kind: StatusKind,
+    /// Remaining turns
+    pub duration: u32,
+    pub power: i32,
+}
+
+impl StatusEffect {
+    pub fn new(kind: StatusKind, duration: u32, power: i32) -> Self {
+        Self {
+            kind,
+            duration,
+            power,
+        }
+    }
+}
+
+/// Active effects of an entity, at most one per kind
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StatusEffects {
+    pub effects: Vec<StatusEffect>,
+}
+
+impl StatusEffects {
+    pub fn get(&self, kind: StatusKind) -> Option<&StatusEffect> {
+        self.effects.iter().find(|e| e.kind == kind)
+    }
+
+    pub fn has(&self, kind: StatusKind) -> bool {
+        self.get(kind).is_some()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.effects.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &StatusEffect> + '_ {
+        self.effects.iter()
+    }
+
+    /// Combine the effect with an active one of the same kind, see [Stacking]
+    pub fn add(&mut self, effect: StatusEffect) {
+        let Some(current) = self.effects.iter_mut().find(|e| e.kind == effect.kind) else {
+            self.effects.push(effect);
+            return;
+        };
+        match effect.kind.desc().stacking {
+            Stacking::Extend => {
+                current.duration += effect.duration;
+                current.power = current.power.max(effect.power);
+            }
+            Stacking::Refresh => {
+                current.duration = current.duration.max(effect.duration);
+                current.power = current.power.max(effect.power);
+            }
+        }
+    }
+
+    /// Count down the durations, returns the effects that wore off
+    pub fn tick(&mut self) -> Vec<StatusKind> {
+        let mut expired = Vec::new();
+        self.effects.retain_mut(|e| {
+            e.duration = e.duration.saturating_sub(1);
+            if e.duration == 0 {
+                expired.push(e.kind);
+            }
+            e.duration > 0
+        });
+        expired
+    }
+}
+
+/// Apply the effect to the entity, unless it is immune
+///
+/// Returns whether the effect took hold
+pub fn apply_status_effect(
+    cmd: &mut Commands,
+    id: EntityId,
+    resistances: Option<&Resistances>,
+    effects: Option<&mut StatusEffects>,
+    effect: StatusEffect,
+) -> bool {
+    if resistances.is_some_and(|r| effect.kind.is_immune(r)) {
+        return false;
+    }
+    match effects {
+        Some(effects) => effects.add(effect),
+        None => {
+            cmd.entity(id).insert(StatusEffects {
+                effects: vec![effect],
+            });
+        }
+    }
+    true
+}
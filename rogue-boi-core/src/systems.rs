@@ -1,16 +1,21 @@
 use crate::{
-    InputEvent, PlayerActions, PlayerOutput, RenderedOutput, ShopEntryOutput, ShopOutput, Stuff,
-    archetypes::icon,
+    Action, HashMap, InputEvent, Keymap, PlayerActions, PlayerOutput, RenderedOutput,
+    ShopEntryOutput, ShopOutput, Stuff,
+    archetypes::{icon, init_entity},
     colors::*,
+    combat::{Attack, RollOutcome, attack_roll, compute_damage, skill_check},
     components::*,
+    fov,
     game_config::{get_color, get_icon},
     grid::Grid,
     map_gen,
-    math::{Vec2, remap_f64, walk_square},
-    pathfinder::find_path,
+    math::Vec2,
+    pathfinder::{OCCUPIED_COST, find_path, find_path_over},
 };
+use arrayvec::ArrayVec;
 use cecs::{commands::EntityCommands, prelude::*};
 use rand::{RngExt, prelude::IndexedRandom, seq::SliceRandom};
+use std::collections::VecDeque;
 use tracing::{debug, info, warn};
 
 pub fn init_world_systems(world: &mut World) {
@@ -18,7 +23,8 @@ pub fn init_world_systems(world: &mut World) {
         SystemStage::new("inputs")
             .with_system(set_player_id)
             .with_system(update_input_events)
-            .with_system(update_should_tick)
+            .with_system(update_travel.after(update_input_events))
+            .with_system(update_should_tick.after(update_travel))
             .with_system(handle_targeting.after(update_should_tick))
             .with_system(player_prepare.after(update_should_tick))
             .with_system(handle_levelup),
@@ -38,6 +44,8 @@ pub fn init_world_systems(world: &mut World) {
                             .with_system(update_player_world_interact)
                             .with_system(update_camera_pos)
                             .with_system(update_unequip)
+                            .with_system(update_drop_item)
+                            .with_system(update_fire)
                             .with_system(cmd_flush_system) // interact may insert a new equipment use
                             .with_system(update_equipment_use.after(cmd_flush_system)),
                     )
@@ -65,24 +73,33 @@ pub fn init_world_systems(world: &mut World) {
             .with_should_run(should_tick)
             .with_should_run(should_update_world)
             .with_should_run(is_ingame)
-            .with_system(update_poison)
-            .with_system(update_slowed)
-            .with_system(update_ai_hp.after(update_poison))
+            .with_system(update_status_effects)
+            .with_system(update_regen.after(update_status_effects))
+            .with_system(update_ai_hp.after(update_status_effects))
             .with_system(cmd_flush_system.after(update_ai_hp))
-            .with_system(update_ai_move)
+            // the player has already moved in this tick
+            .with_system(update_light_map)
+            .with_system(update_fov.after(update_light_map))
+            .with_system(update_noise.after(update_fov))
+            .with_system(update_ai_state.after(update_noise))
+            .with_system(update_player_maps.after(update_fov))
+            .with_system(
+                update_ai_move
+                    .after(update_ai_state)
+                    .after(update_player_maps),
+            )
             .with_system(update_melee_ai)
-            .with_system(update_confusion)
+            .with_system(update_ranged_ai.after(update_fov))
             .with_system(update_player_hp)
-            .with_system(update_grid)
-            .with_system(update_fov),
-    );
-    world.add_stage(
-        SystemStage::new("render")
-            .with_system(perform_move)
-            .with_system(update_output.after(perform_move))
-            .with_system(render_onto_canvas.after(perform_move))
-            .with_system(clean_inputs),
+            .with_system(update_grid),
     );
+    let render_stage = SystemStage::new("render")
+        .with_system(perform_move)
+        .with_system(update_output.after(perform_move))
+        .with_system(clean_inputs);
+    #[cfg(feature = "web")]
+    let render_stage = render_stage.with_system(crate::web::render_onto_canvas.after(perform_move));
+    world.add_stage(render_stage);
     world.add_stage(
         SystemStage::new("post_render")
             .with_should_run(should_tick)
@@ -118,19 +135,83 @@ fn set_player_id(mut id: ResMut<PlayerId>, q: Query<EntityId, With<PlayerTag>>)
     id.0 = q.single();
 }
 
-fn update_input_events(inputs: Res<Vec<InputEvent>>, mut actions: ResMut<PlayerActions>) {
+/// Translate the pressed keys into player actions using the [Keymap]
+fn update_input_events(
+    mut inputs: ResMut<Vec<InputEvent>>,
+    keymap: Res<Keymap>,
+    mut actions: ResMut<PlayerActions>,
+    mut travel: ResMut<Travel>,
+    ruleset: Res<Ruleset>,
+    selected: Res<Selected>,
+    q_inventory: Query<&Inventory, With<PlayerTag>>,
+    q_use: Query<EntityId, With<UseItem>>,
+    q_fire: Query<EntityId, With<Fire>>,
+    mut app_mode: ResMut<AppMode>,
+    mut log: ResMut<LogHistory>,
+    mut cmd: Commands,
+) {
+    let selected_item = selected.0.filter(|id| {
+        q_inventory
+            .single()
+            .is_some_and(|inv| inv.items.contains(id))
+    });
     let mut delta = Vec2::new(0, 0);
-    for event in &inputs[..] {
-        match event {
-            InputEvent::KeyDown { key } if key == "w" || key == "ArrowUp" => delta.y = -1,
-            InputEvent::KeyDown { key } if key == "s" || key == "ArrowDown" => delta.y = 1,
-            InputEvent::KeyDown { key } if key == "a" || key == "ArrowLeft" => delta.x = -1,
-            InputEvent::KeyDown { key } if key == "d" || key == "ArrowRight" => delta.x = 1,
-            InputEvent::KeyDown { key } if key == "e" => actions.insert_interact(),
+    for action in inputs.iter().filter_map(|event| keymap.action(event)) {
+        // any action takes over from travelling
+        travel.stop();
+        if let Some(d) = action.direction() {
+            if d.x != 0 && d.y != 0 && !ruleset.diagonal_movement {
+                continue;
+            }
+            // pressing two directions in the same frame combines them
+            if d.x != 0 {
+                delta.x = d.x;
+            }
+            if d.y != 0 {
+                delta.y = d.y;
+            }
+            continue;
+        }
+        match action {
+            Action::Wait => actions.insert_wait(),
+            Action::Interact => actions.insert_interact(),
+            Action::Fire => actions.insert_fire(),
+            Action::UseSelected => {
+                if let Some(id) = selected_item {
+                    cmd.entity(id).insert(UseItem);
+                }
+            }
+            Action::DropSelected => {
+                if let Some(id) = selected_item {
+                    cmd.entity(id).insert(DropItem);
+                }
+            }
+            Action::CancelItemUse => {
+                for id in q_use.iter() {
+                    cmd.entity(id).remove::<UseItem>();
+                }
+                for id in q_fire.iter() {
+                    cmd.entity(id).remove::<Fire>();
+                }
+                if matches!(*app_mode, AppMode::Targeting) {
+                    *app_mode = AppMode::Game;
+                }
+                log.push(WHITE, "Cancel item use");
+            }
+            Action::AutoExplore => travel.start(TravelGoal::Explore),
+            Action::TravelToStairs => travel.start(TravelGoal::Stairs),
+            Action::Rest => travel.start(TravelGoal::Rest),
             _ => {}
         }
     }
-    if delta.x != 0 && delta.y != 0 {
+    // inputs are kept until the next tick so moves can be combined,
+    // the other actions must only be performed once
+    inputs.retain(|event| {
+        keymap
+            .action(event)
+            .is_some_and(|action| action.direction().is_some())
+    });
+    if delta.x != 0 && delta.y != 0 && !ruleset.diagonal_movement {
         delta.x = 0;
     }
     if delta != Vec2::ZERO {
@@ -138,6 +219,165 @@ fn update_input_events(inputs: Res<Vec<InputEvent>>, mut actions: ResMut<PlayerA
     }
 }
 
+/// Take the next step of the player's travel once the previous step has been performed
+fn update_travel(
+    mut travel: ResMut<Travel>,
+    mut actions: ResMut<PlayerActions>,
+    app_mode: Res<AppMode>,
+    player_id: Res<PlayerId>,
+    q_player: Query<(&Pos, &Hp), With<PlayerTag>>,
+    q_monsters: Query<(&Pos, Option<&Name>), With<Ai>>,
+    q_items: Query<(EntityId, &Pos, Option<&Name>), With<Item>>,
+    q_ai: Query<&(), With<Ai>>,
+    q_stuff: Query<(&StuffTag, &Pos)>,
+    q_walk: Query<&Walkable>,
+    grid: Res<Grid<Stuff>>,
+    explored: Res<Explored>,
+    visible: Res<Visible>,
+    ruleset: Res<Ruleset>,
+    mut log: ResMut<LogHistory>,
+) {
+    let travel = &mut *travel;
+    let Some(goal) = travel.goal else {
+        return;
+    };
+    if !actions.is_empty() {
+        // the previous step has not been performed yet
+        return;
+    }
+    let Some((Pos(player_pos), hp)) = player_id.get(&q_player) else {
+        travel.stop();
+        return;
+    };
+    if *app_mode != AppMode::Game {
+        travel.stop();
+        return;
+    }
+    let is_visible = |pos: Vec2| visible.0.at(pos.x, pos.y).copied().unwrap_or(false);
+    if let Some((_, name)) = q_monsters.iter().find(|(Pos(pos), _)| is_visible(*pos)) {
+        let name = name.map(|Name(n)| n.as_str()).unwrap_or("A monster");
+        log.push(WHITE, format!("{name} comes into view"));
+        travel.stop();
+        return;
+    }
+    if travel.last_hp.is_some_and(|last| hp.current < last) {
+        log.push(WHITE, "You are hurt, stop travelling");
+        travel.stop();
+        return;
+    }
+    // items in view when the travel starts do not interrupt it
+    let first_step = travel.last_hp.is_none();
+    let mut new_item = None;
+    for (id, _, name) in q_items.iter().filter(|(_, Pos(pos), _)| is_visible(*pos)) {
+        if travel.known_items.insert(id) && !first_step {
+            new_item = Some(name);
+        }
+    }
+    if let Some(name) = new_item {
+        let name = name.map(|Name(n)| n.as_str()).unwrap_or("an item");
+        log.push(WHITE, format!("You see {name}"));
+        travel.stop();
+        return;
+    }
+    travel.last_hp = Some(hp.current);
+
+    // opening a door costs a turn
+    let entity_cost = |id: EntityId| match q_stuff.fetch(id) {
+        Some((StuffTag::Wall | StuffTag::Torch, _)) => None,
+        Some((StuffTag::Door, _)) => Some(1),
+        _ if q_ai.contains(id) => Some(OCCUPIED_COST),
+        _ => Some(0),
+    };
+    let passable = |pos: Vec2| {
+        grid.at(pos.x, pos.y)
+            .is_some_and(|stuff| stuff.is_none_or(|id| entity_cost(id).is_some()))
+    };
+    let is_explored = |pos: Vec2| explored.0.at(pos.x, pos.y).copied().unwrap_or(false);
+    // never attack while travelling
+    let occupied = |pos: Vec2| {
+        grid.at(pos.x, pos.y)
+            .copied()
+            .flatten()
+            .is_some_and(|id| q_ai.contains(id))
+    };
+    let nearest_known = |tag: StuffTag| {
+        q_stuff
+            .iter()
+            .filter(|(t, Pos(pos))| **t == tag && is_explored(*pos))
+            .map(|(_, Pos(pos))| *pos)
+            .min_by_key(|pos| ruleset.distance(*player_pos, *pos))
+    };
+
+    if goal == TravelGoal::Rest {
+        if hp.full() {
+            log.push(WHITE, "You feel rested");
+            travel.stop();
+        } else {
+            actions.insert_wait();
+        }
+        return;
+    }
+
+    let destination = match goal {
+        TravelGoal::Explore | TravelGoal::Rest => None,
+        TravelGoal::To(pos) => Some(pos),
+        TravelGoal::Stairs => nearest_known(StuffTag::Stairs),
+        TravelGoal::Shop => nearest_known(StuffTag::Shop),
+    };
+    let step = match (goal, destination) {
+        (TravelGoal::Explore, _) => {
+            // unexplored walls are never reached, only explored by looking at them
+            let seeds = explored
+                .0
+                .iter()
+                .filter(|(pos, explored)| !**explored && passable(*pos))
+                .map(|(pos, _)| (pos, 0));
+            travel.map.compute(seeds, &grid, *ruleset, entity_cost);
+            travel
+                .map
+                .downhill(*player_pos, *ruleset, |pos| !occupied(pos))
+                .ok_or("Nothing left to explore")
+        }
+        (TravelGoal::Stairs, None) => Err("You have not found the stairs yet"),
+        // only the shop is left without a destination
+        (_, None) => Err("You have not found a shop yet"),
+        (_, Some(to)) if to == *player_pos => {
+            travel.stop();
+            return;
+        }
+        (_, Some(to)) => {
+            travel.path.clear();
+            // the player can't plan a route through unknown territory
+            let found = is_explored(to)
+                && passable(to)
+                && find_path_over(
+                    *player_pos,
+                    to,
+                    &grid,
+                    &q_walk,
+                    *ruleset,
+                    |pos| is_explored(pos) && passable(pos),
+                    &mut travel.path,
+                );
+            // the path is in reverse order and stops next to `to`
+            found
+                .then(|| travel.path.last().copied().unwrap_or(to))
+                .ok_or("Can't find a way there")
+        }
+    };
+    match step {
+        Ok(step) if !occupied(step) => actions.insert_move(step - *player_pos),
+        Ok(_) => {
+            log.push(WHITE, "Something is in the way");
+            travel.stop();
+        }
+        Err(reason) => {
+            log.push(WHITE, reason);
+            travel.stop();
+        }
+    }
+}
+
 fn equip_item(id: EntityId, equipment: &mut Option<EntityId>, inventory: &mut Inventory) {
     // move old weapon back to the inventory
     match equipment.take() {
@@ -177,34 +417,38 @@ fn clear_consumable(
 
 fn use_poison_scroll(
     mut cmd: Commands,
-    mut target_query: Query<(Option<&mut Poisoned>, Option<&Name>)>,
+    mut target_query: Query<(
+        Option<&mut StatusEffects>,
+        Option<&Name>,
+        Option<&Resistances>,
+    )>,
     item_query: Query<(EntityId, &Ranged, &Targeting), (With<MarkActive>, With<PoisionAttack>)>,
     mut log: ResMut<LogHistory>,
     mut should_run: ResMut<ShouldUpdateWorld>,
+    mut rng: ResMut<GameRng>,
 ) {
     for (item_id, range, Targeting(target_id)) in item_query.iter() {
         let target_id = *target_id;
         debug!("Use PoisonScroll");
-        let Some((target_poison, target_name)) = target_query.fetch_mut(target_id) else {
+        let Some((target_effects, target_name, resistances)) = target_query.fetch_mut(target_id)
+        else {
             log.push(IMPOSSIBLE, "Invalid target");
             should_run.0 = false;
             return;
         };
 
-        if skill_check(range.skill) {
+        if skill_check(&mut *rng, range.skill) {
             // TODO: config duration
             let duration = 5;
             debug!("Poision Bolt hits {} for {} turns!", target_id, duration);
-            if let Some(poision) = target_poison {
-                poision.duration += duration;
-            } else {
-                cmd.entity(target_id).insert(Poisoned {
-                    duration,
-                    power: range.power,
-                });
-            }
+            let effect = StatusEffect::new(StatusKind::Poison, duration, range.power);
+            let hit = apply_status_effect(&mut cmd, target_id, resistances, target_effects, effect);
             if let Some(Name(name)) = target_name {
-                log.push(WHITE, &format!("{} suffers from poison!", name));
+                if hit {
+                    log.push(WHITE, &format!("{} suffers from poison!", name));
+                } else {
+                    log.push(WHITE, &format!("{} is immune to poison!", name));
+                }
             }
         } else {
             log.push(WHITE, "Poison Bolt misses!");
@@ -215,34 +459,38 @@ fn use_poison_scroll(
 
 fn use_slow_scroll(
     mut cmd: Commands,
-    mut target_query: Query<(Option<&mut Slowed>, Option<&Name>)>,
+    mut target_query: Query<(
+        Option<&mut StatusEffects>,
+        Option<&Name>,
+        Option<&Resistances>,
+    )>,
     item_query: Query<(EntityId, &Ranged, &Targeting), (With<MarkActive>, With<Slow>)>,
     mut log: ResMut<LogHistory>,
     mut should_run: ResMut<ShouldUpdateWorld>,
+    mut rng: ResMut<GameRng>,
 ) {
     for (item_id, range, Targeting(target_id)) in item_query.iter() {
         let target_id = *target_id;
         debug!("Use SlowScroll");
-        let Some((target_poison, target_name)) = target_query.fetch_mut(target_id) else {
+        let Some((target_effects, target_name, resistances)) = target_query.fetch_mut(target_id)
+        else {
             log.push(IMPOSSIBLE, "Invalid target");
             should_run.0 = false;
             return;
         };
 
-        if skill_check(range.skill) {
+        if skill_check(&mut *rng, range.skill) {
             // TODO: config duration
             let duration = 5;
             debug!("Slow Bolt hits {} for {} turns!", target_id, duration);
-            if let Some(poision) = target_poison {
-                poision.duration += duration;
-            } else {
-                cmd.entity(target_id).insert(Slowed {
-                    duration,
-                    power: range.power.try_into().unwrap_or(2).clamp(2, 6),
-                });
-            }
+            let effect = StatusEffect::new(StatusKind::Slow, duration, range.power.clamp(2, 6));
+            let hit = apply_status_effect(&mut cmd, target_id, resistances, target_effects, effect);
             if let Some(Name(name)) = target_name {
-                log.push(WHITE, &format!("{} suffers from slow!", name));
+                if hit {
+                    log.push(WHITE, &format!("{} suffers from slow!", name));
+                } else {
+                    log.push(WHITE, &format!("{} is immune to slow!", name));
+                }
             }
         } else {
             log.push(WHITE, "Slow Bolt misses!");
@@ -276,34 +524,41 @@ fn use_hp_potion(
 fn use_confusion_scroll(
     mut cmd: Commands,
     item_query: Query<(EntityId, &Ranged, &Targeting), (With<MarkActive>, With<ConfusionBolt>)>,
-    mut target_query: Query<(Option<&mut ConfusedAi>, Option<&Name>)>,
+    mut target_query: Query<(
+        Option<&mut StatusEffects>,
+        Option<&Name>,
+        Option<&Resistances>,
+    )>,
     mut log: ResMut<LogHistory>,
     mut should_run: ResMut<ShouldUpdateWorld>,
+    mut rng: ResMut<GameRng>,
 ) {
     for (item_id, range, target) in item_query.iter() {
         let target_id = target.0;
         debug!("Use ConfusionScroll");
-        let Some((target_confusion, target_name)) = target_query.fetch_mut(target_id) else {
+        let Some((target_effects, target_name, resistances)) = target_query.fetch_mut(target_id)
+        else {
             log.push(IMPOSSIBLE, "Invalid target");
             should_run.0 = false;
             return;
         };
-        if skill_check(range.skill) {
-            let duration = range.power;
+        if skill_check(&mut *rng, range.skill) {
+            let duration = range.power.max(0) as u32;
             debug!("Confusion Bolt hits {} for {} turns!", target_id, duration);
-            if let Some(confusion) = target_confusion {
-                confusion.duration += duration;
-            } else {
-                cmd.entity(target_id).insert(ConfusedAi { duration });
-            }
+            let effect = StatusEffect::new(StatusKind::Confusion, duration, 0);
+            let hit = apply_status_effect(&mut cmd, target_id, resistances, target_effects, effect);
             if let Some(Name(name)) = target_name {
-                log.push(
-                    WHITE,
-                    &format!(
-                        "The eyes of the {} look vacant, as it starts to stumble around!",
-                        name
-                    ),
-                );
+                if hit {
+                    log.push(
+                        WHITE,
+                        &format!(
+                            "The eyes of the {} look vacant, as it starts to stumble around!",
+                            name
+                        ),
+                    );
+                } else {
+                    log.push(WHITE, &format!("{} is immune to confusion!", name));
+                }
             }
         } else {
             log.push(WHITE, "Confusion Bolt misses!");
@@ -338,14 +593,21 @@ fn use_ward_scroll(
 fn use_lightning_scroll(
     mut cmd: Commands,
     item_query: Query<(EntityId, &Ranged, &Targeting), (With<MarkActive>, With<LightningBolt>)>,
-    mut target_query: Query<(&mut Hp, Option<&Name>)>,
+    mut target_query: Query<(
+        &mut Hp,
+        Option<&Name>,
+        Option<&mut Defense>,
+        Option<&Resistances>,
+    )>,
     mut log: ResMut<LogHistory>,
     mut should_run: ResMut<ShouldUpdateWorld>,
+    mut rng: ResMut<GameRng>,
 ) {
     for (item_id, range, target) in item_query.iter() {
         let target_id = target.0;
         debug!("Use lightning scroll {}", item_id);
-        let (target_hp, target_name) = match target_query.fetch_mut(target_id) {
+        let (target_hp, target_name, defense, resistances) = match target_query.fetch_mut(target_id)
+        {
             Some(x) => x,
             None => {
                 log.push(INVALID, "Invalid target");
@@ -353,14 +615,22 @@ fn use_lightning_scroll(
                 return;
             }
         };
-        if skill_check(range.skill) {
-            let dmg = range.power;
+        let evasion = defense.as_ref().map(|d| d.evasion).unwrap_or(0);
+        let outcome = attack_roll(&mut *rng, range.skill, evasion, None);
+        if outcome.is_hit() {
+            let attack = Attack::new(range.power, DamageType::Lightning);
+            let dmg = compute_damage(&attack, outcome, defense, resistances);
             target_hp.current -= dmg;
             debug!("Lightning Bolt hits {} for {} damage!", target_id, dmg);
             if let Some(Name(name)) = target_name {
                 log.push(
                     WHITE,
-                    &format!("Lightning Bolt hits {} for {} damage!", name, dmg),
+                    &format!(
+                        "Lightning Bolt hits {} for {} damage!{}",
+                        name,
+                        dmg,
+                        outcome.log_suffix()
+                    ),
                 );
             }
         } else {
@@ -373,14 +643,21 @@ fn use_lightning_scroll(
 fn use_fireball(
     mut cmd: Commands,
     item_query: Query<(EntityId, &Ranged, &Aoe, &TargetingPos), (With<MarkActive>, With<FireBall>)>,
-    mut target_query: Query<(&mut Hp, Option<&Name>)>,
+    mut target_query: Query<(
+        &mut Hp,
+        Option<&Name>,
+        Option<&mut Defense>,
+        Option<&Resistances>,
+    )>,
     mut log: ResMut<LogHistory>,
     mut should_run: ResMut<ShouldUpdateWorld>,
     mut app_mode: ResMut<AppMode>,
     grid: Res<Grid<Stuff>>,
+    mut noises: ResMut<Noises>,
+    ruleset: Res<Ruleset>,
 ) {
     for (item_id, range, aoe, target_pos) in item_query.iter() {
-        if target_pos.src.chebyshev(target_pos.dst) > range.range {
+        if ruleset.distance(target_pos.src, target_pos.dst) > range.range {
             log.push(INVALID, "Target is too far away. Try again");
             *app_mode = AppMode::TargetingPosition;
             should_run.0 = false;
@@ -390,21 +667,25 @@ fn use_fireball(
             PLAYER_ATTACK,
             format!("Hurl a fire ball at {}", target_pos.dst),
         );
+        noises.push(target_pos.dst, EXPLOSION_NOISE);
         let radius = Vec2::splat(aoe.radius as i32);
         let power = range.power;
         grid.scan_range(
             [target_pos.dst - radius, target_pos.dst + radius],
             |_pos, id| {
                 if let Some(id) = id {
-                    if let Some((hp, name)) = target_query.fetch_mut(*id) {
+                    if let Some((hp, name, defense, resistances)) = target_query.fetch_mut(*id) {
                         // TODO skill check?
-                        hp.current -= power;
+                        let attack = Attack::new(power, DamageType::Fire);
+                        let damage =
+                            compute_damage(&attack, RollOutcome::Hit, defense, resistances);
+                        hp.current -= damage;
                         if let Some(Name(name)) = name {
                             log.push(
                                 PLAYER_ATTACK,
                                 format!(
                                     "{} is engulfed in a fiery explosion, taking {} damage",
-                                    name, power
+                                    name, damage
                                 ),
                             );
                         }
@@ -424,8 +705,9 @@ pub fn update_unequip(
     player_id: Res<PlayerId>,
     item: Query<(EntityId, &EquipmentType, &Name), With<Unequip>>,
     mut log: ResMut<LogHistory>,
-    mut stats_query: Query<(&mut Melee, &mut Defense)>,
+    mut stats_query: Query<(&mut Melee, &mut Defense, &mut Ranged)>,
     grid: Res<Grid<Stuff>>,
+    mut rng: ResMut<GameRng>,
 ) {
     let Some((equipment, inventory, pos)) = player_id.get_mut(&mut player_query) else {
         return;
@@ -454,29 +736,155 @@ pub fn update_unequip(
                 let item_power = *q.fetch(id).unwrap();
                 let player_power = player_id.get_mut(&mut q).unwrap();
 
+                *player_power -= item_power;
+            }
+            EquipmentType::Ranged => {
+                assert_eq!(Some(id), equipment.ranged);
+                equipment.ranged.take();
+
+                let mut q: Query<&mut Ranged> = stats_query.subset();
+                let item_power = *q.fetch(id).unwrap();
+                let player_power = player_id.get_mut(&mut q).unwrap();
+
                 *player_power -= item_power;
             }
         }
 
         if inventory.add(id).is_err() {
-            perform_drop_item(cmd, pos, name, &mut log, &grid);
+            perform_drop_item(&mut *rng, cmd, pos, name, &mut log, &grid);
+        }
+    }
+}
+
+/// Shoot the equipped ranged weapon at the target, spending one piece of ammunition
+fn update_fire(
+    actions: Res<PlayerActions>,
+    mut cmd: Commands,
+    mut q_player: Query<(&Pos, &Ranged, &Equipment, &mut Inventory), With<PlayerTag>>,
+    player_id: Res<PlayerId>,
+    q_launcher: Query<(&Launcher, Has<Fire>, Option<&AttackMods>)>,
+    mut q_ammo: Query<(&StuffTag, &mut Ammo)>,
+    mut q_target: Query<
+        (
+            &Pos,
+            &mut Hp,
+            &mut Defense,
+            Option<&Name>,
+            Option<&Resistances>,
+        ),
+        With<Ai>,
+    >,
+    blockers: Query<&(), Or<With<Opaque>, With<Ai>>>,
+    mut grid: ResMut<Grid<Stuff>>,
+    mut should_run: ResMut<ShouldUpdateWorld>,
+    mut app_mode: ResMut<AppMode>,
+    mut log: ResMut<LogHistory>,
+    mut rng: ResMut<GameRng>,
+    mut noises: ResMut<Noises>,
+    ruleset: Res<Ruleset>,
+) {
+    let Some((Pos(player_pos), ranged, equipment, inventory)) = player_id.get_mut(&mut q_player)
+    else {
+        return;
+    };
+    let launcher = equipment.ranged.and_then(|id| {
+        q_launcher
+            .fetch(id)
+            .map(|(launcher, firing, mods)| (id, launcher, firing, mods))
+    });
+    let (launcher_id, launcher, mods) = match launcher {
+        Some((id, launcher, firing, mods)) if firing || actions.fire() => (id, launcher, mods),
+        Some(_) => return,
+        None => {
+            if actions.fire() {
+                log.push(IMPOSSIBLE, "You have no ranged weapon equipped");
+                should_run.0 = false;
+            }
+            return;
         }
+    };
+    let Some(ammo_id) = inventory.iter().find(|id| {
+        q_ammo
+            .fetch(*id)
+            .is_some_and(|(tag, _)| *tag == launcher.ammo)
+    }) else {
+        log.push(IMPOSSIBLE, "You are out of ammunition");
+        should_run.0 = false;
+        cmd.entity(launcher_id).remove::<Fire>();
+        return;
+    };
+    let Some(target_id) = actions.target() else {
+        log.push(NEEDS_TARGET, "Select a target");
+        debug!("Fire has no target!");
+        should_run.0 = false;
+        *app_mode = AppMode::Targeting;
+        cmd.entity(launcher_id).insert(Fire);
+        return;
+    };
+    let Some((Pos(target_pos), hp, defense, name, resistances)) = q_target.fetch_mut(target_id)
+    else {
+        log.push(IMPOSSIBLE, "Invalid target");
+        should_run.0 = false;
+        return;
+    };
+    if ruleset.distance(*target_pos, *player_pos) > ranged.range {
+        log.push(IMPOSSIBLE, "Target is too far away");
+        should_run.0 = false;
+        return;
+    }
+    if walk_grid_on_segment(*player_pos, *target_pos, &grid, |id| blockers.contains(id)).is_some() {
+        log.push(IMPOSSIBLE, "Something is in the line of fire");
+        should_run.0 = false;
+        return;
+    }
+    cmd.entity(launcher_id).remove::<Fire>();
+
+    let name = name.map(|n| n.0.as_str()).unwrap_or("the target");
+    let outcome = attack_roll(&mut *rng, ranged.skill, defense.evasion, mods);
+    if outcome.is_hit() {
+        let attack = Attack::physical(ranged.power).with_mods(mods);
+        let damage = compute_damage(&attack, outcome, Some(defense), resistances);
+        hp.current -= damage;
+        debug!("shoot enemy {}: {:?}", target_id, hp);
+        log.push(
+            PLAYER_ATTACK,
+            format!(
+                "Shoot {} for {} damage{}",
+                name,
+                damage,
+                outcome.log_suffix()
+            ),
+        );
+    } else {
+        log.push(PLAYER_ATTACK, "Your shot misses");
+    }
+    noises.push(*target_pos, COMBAT_NOISE);
+
+    let (&ammo_tag, ammo) = q_ammo.fetch_mut(ammo_id).unwrap();
+    ammo.count -= 1;
+    if ammo.count == 0 {
+        inventory.remove(ammo_id);
+        cmd.delete(ammo_id);
+    }
+    // the spent projectile lands next to the target and may be picked up again
+    let target_pos = *target_pos;
+    if let Some(pos) = unoccupied_neightbour(&mut *rng, target_pos, &grid) {
+        init_entity(pos, ammo_tag, &mut cmd, &mut grid).insert(Ammo { count: 1 });
     }
 }
 
 /// Try to find a random neighbour that's onuccupied.
 /// Prefers closer tiles to diagonal tiles
-pub fn unoccupied_neightbour(pos: Vec2, grid: &Grid<Stuff>) -> Option<Vec2> {
-    let mut rng = rand::rng();
+pub fn unoccupied_neightbour(rng: &mut impl RngExt, pos: Vec2, grid: &Grid<Stuff>) -> Option<Vec2> {
     let mut n = pos.neighbours();
     n.sort_unstable_by_key(|a| a.manhatten(pos));
-    n[0..4].shuffle(&mut rng);
+    n[0..4].shuffle(rng);
     for p in &n[0..4] {
         if grid.is_free(p.x, p.y) {
             return Some(*p);
         }
     }
-    n[4..].shuffle(&mut rng);
+    n[4..].shuffle(rng);
     for p in &n[4..] {
         if grid.is_free(p.x, p.y) {
             return Some(*p);
@@ -485,7 +893,29 @@ pub fn unoccupied_neightbour(pos: Vec2, grid: &Grid<Stuff>) -> Option<Vec2> {
     None
 }
 
+fn update_drop_item(
+    mut cmd: Commands,
+    mut q_player: Query<(&Pos, &mut Inventory), With<PlayerTag>>,
+    player_id: Res<PlayerId>,
+    q_item: Query<(EntityId, &Name), With<DropItem>>,
+    mut log: ResMut<LogHistory>,
+    mut rng: ResMut<GameRng>,
+    grid: Res<Grid<Stuff>>,
+) {
+    let Some((pos, inventory)) = player_id.get_mut(&mut q_player) else {
+        return;
+    };
+    for (id, name) in q_item.iter() {
+        let cmd = cmd.entity(id);
+        cmd.remove::<DropItem>();
+        if inventory.remove(id).is_some() {
+            perform_drop_item(&mut *rng, cmd, pos, name, &mut log, &grid);
+        }
+    }
+}
+
 pub fn perform_drop_item(
+    rng: &mut impl RngExt,
     cmd: &mut EntityCommands,
     pos: &Pos,
     Name(name): &Name,
@@ -493,7 +923,7 @@ pub fn perform_drop_item(
     grid: &Grid<Stuff>,
 ) {
     // as a fallback, drop on self position if none was found
-    let drop_pos = unoccupied_neightbour(pos.0, grid).unwrap_or(pos.0);
+    let drop_pos = unoccupied_neightbour(rng, pos.0, grid).unwrap_or(pos.0);
     log.push(WHITE, format!("Drop {name}"));
     cmd.insert(Pos(drop_pos));
 }
@@ -521,6 +951,7 @@ fn update_consumable_use(
     target_query: Query<&Pos>,
     target_pos: Res<TargetPos>,
     mut log: ResMut<LogHistory>,
+    ruleset: Res<Ruleset>,
 ) {
     let Some(player_pos) = player_id.get_mut(&mut player_query) else {
         return;
@@ -541,7 +972,7 @@ fn update_consumable_use(
                     return;
                 };
                 if let Some(range) = range {
-                    if target_pos.0.chebyshev(player_pos.0) > range.range {
+                    if ruleset.distance(target_pos.0, player_pos.0) > range.range {
                         log.push(IMPOSSIBLE, "Target is too far away");
                         should_run.0 = false;
                         return;
@@ -556,7 +987,7 @@ fn update_consumable_use(
     for (id, range) in q.q1().iter() {
         match target_pos.pos {
             Some(target_pos) => {
-                if target_pos.chebyshev(player_pos.0) > range.range {
+                if ruleset.distance(target_pos, player_pos.0) > range.range {
                     log.push(INVALID, "Target is too far away. Try again");
                     *app_mode = AppMode::TargetingPosition;
                     should_run.0 = false;
@@ -588,7 +1019,7 @@ fn update_equipment_use(
     mut player_query: Query<(EntityId, &mut Inventory, &mut Equipment), With<PlayerTag>>,
     player_id: Res<PlayerId>,
     q: Query<(EntityId, &EquipmentType), With<UseItem>>,
-    mut item_query: Query<(&mut Melee, &mut Defense)>,
+    mut item_query: Query<(&mut Melee, &mut Defense, &mut Ranged)>,
 ) {
     let Some((player_id, inventory, equipment)) = player_id.get_mut(&mut player_query) else {
         return;
@@ -627,6 +1058,21 @@ fn update_equipment_use(
                     *player_defense -= old_defense;
                 }
 
+                equip_item(id, old_id, inventory);
+            }
+            EquipmentType::Ranged => {
+                let old_id = &mut equipment.ranged;
+
+                // update ranged stats
+                let mut q: Query<&mut Ranged> = item_query.subset();
+                let new_ranged = *q.fetch(id).unwrap();
+                let old_ranged = old_id.and_then(|id| q.fetch(id).copied());
+                let player_ranged = q.fetch_mut(player_id).unwrap();
+                *player_ranged += new_ranged;
+                if let Some(old_ranged) = old_ranged {
+                    *player_ranged -= old_ranged;
+                }
+
                 equip_item(id, old_id, inventory);
             }
         }
@@ -644,6 +1090,7 @@ fn update_player_world_interact(
         Option<&Name>,
         Has<Shop>,
     )>,
+    mut q_ammo: Query<(&StuffTag, &mut Ammo)>,
     grid: Res<Grid<Stuff>>,
     mut should_run: ResMut<ShouldUpdateWorld>,
     actions: Res<PlayerActions>,
@@ -682,8 +1129,27 @@ fn update_player_world_interact(
                         equip = true;
                     }
                 }
+                Some(EquipmentType::Ranged) => {
+                    if equipment.ranged.is_none() {
+                        equip = true;
+                    }
+                }
                 None => {}
             }
+            // ammunition is merged into an existing stack of the same kind
+            if let Some((tag, ammo)) = q_ammo.fetch(stuff_id).map(|(t, a)| (*t, *a)) {
+                let stack = inventory.iter().find(|id| {
+                    *id != stuff_id && q_ammo.fetch(*id).is_some_and(|(t, _)| *t == tag)
+                });
+                if let Some(stack) = stack {
+                    let (_, stack_ammo) = q_ammo.fetch_mut(stack).unwrap();
+                    stack_ammo.count += ammo.count;
+                    cmd.delete(stuff_id);
+                    let Name(name) = name.unwrap();
+                    log.push(WHITE, format!("Picked up {} {}", ammo.count, name));
+                    return;
+                }
+            }
             match inventory.add(stuff_id) {
                 Ok(_) => {
                     let cmd = cmd.entity(stuff_id);
@@ -722,33 +1188,26 @@ fn leave_shop(q: Query<&(), (With<MarkActive>, With<Shop>)>, mut app_mode: ResMu
     }
 }
 
-fn compute_melee_damage(power: i32, defense: &mut Defense) -> i32 {
-    debug!(?defense, ?power, "compute_melee_damage");
-    if defense.ward > 0 {
-        defense.ward -= 1;
-        return 0;
-    }
-    // all damage must be at least 1
-    (power - defense.melee_defense).max(1)
-}
-
 fn handle_player_move(
     actions: Res<PlayerActions>,
-    mut player_q: Query<(&Melee, &mut Pos), With<PlayerTag>>,
+    mut player_q: Query<(&Melee, &Equipment, &mut Pos), With<PlayerTag>>,
     player_id: Res<PlayerId>,
     stuff_tags: Query<&StuffTag>,
-    mut enemy_q: Query<(&mut Hp, &mut Defense)>,
+    q_mods: Query<&AttackMods>,
+    mut enemy_q: Query<(&mut Hp, &mut Defense, Option<&Resistances>)>,
     mut grid: ResMut<Grid<Stuff>>,
     mut should_run: ResMut<ShouldUpdateWorld>,
     names: Query<&Name>,
     mut log: ResMut<LogHistory>,
     mut cmd: Commands,
+    mut rng: ResMut<GameRng>,
+    mut noises: ResMut<Noises>,
 ) {
     let Some(delta) = actions.move_action() else {
         return;
     };
     debug!(?delta, "Handling player move");
-    let Some((power, pos)) = player_id.get_mut(&mut player_q) else {
+    let Some((power, equipment, pos)) = player_id.get_mut(&mut player_q) else {
         return;
     };
     let pos = &mut pos.0;
@@ -766,8 +1225,9 @@ fn handle_player_move(
                 // TODO: ability to close the door?
                 // would need some persistent state then, instead of deleting
                 cmd.delete(stuff_id);
+                noises.push(new_pos, DOOR_NOISE);
             }
-            StuffTag::Wall => {
+            StuffTag::Wall | StuffTag::Torch => {
                 warn!("Can't move into wall");
                 should_run.0 = false;
             }
@@ -777,22 +1237,34 @@ fn handle_player_move(
             | StuffTag::Orc
             | StuffTag::Warlord
             | StuffTag::Zombie
-            | StuffTag::Minotaur => {
-                if skill_check(power.skill) {
-                    let (hp, defense) = enemy_q.fetch_mut(stuff_id).expect("Enemy has no hp");
-                    let damage = compute_melee_damage(power.power, defense);
+            | StuffTag::Minotaur
+            | StuffTag::GoblinArcher
+            | StuffTag::Shaman => {
+                let (hp, defense, resistances) =
+                    enemy_q.fetch_mut(stuff_id).expect("Enemy has no hp");
+                let mods = equipment.weapon.and_then(|id| q_mods.fetch(id));
+                let outcome = attack_roll(&mut *rng, power.skill, defense.evasion, mods);
+                if outcome.is_hit() {
+                    let attack = Attack::physical(power.power).with_mods(mods);
+                    let damage = compute_damage(&attack, outcome, Some(defense), resistances);
                     hp.current -= damage;
                     debug!("kick enemy {}: {:?}", stuff_id, hp);
                     if let Some(Name(name)) = names.fetch(stuff_id) {
                         log.push(
                             PLAYER_ATTACK,
-                            format!("Bonk {} for {} damage", name, damage),
+                            format!(
+                                "Bonk {} for {} damage{}",
+                                name,
+                                damage,
+                                outcome.log_suffix()
+                            ),
                         );
                     }
                 } else {
                     debug!("miss enemy {}", stuff_id);
                     log.push(PLAYER_ATTACK, "Your attack misses");
                 }
+                noises.push(new_pos, COMBAT_NOISE);
             }
             StuffTag::LightningScroll
             | StuffTag::PoisonScroll
@@ -809,6 +1281,11 @@ fn handle_player_move(
             | StuffTag::Tombstone
             | StuffTag::Shop
             | StuffTag::SlowScroll
+            | StuffTag::Lantern
+            | StuffTag::Bow
+            | StuffTag::Sling
+            | StuffTag::Arrows
+            | StuffTag::SlingStones
             | StuffTag::Stairs => {
                 grid_step(pos, new_pos, &mut grid);
             }
@@ -827,12 +1304,129 @@ fn grid_step(pos: &mut Vec2, new_pos: Vec2, grid: &mut Grid<Stuff>) {
     *pos = new_pos;
 }
 
+/// recompute visible area, cells have to be in line of sight and lit
+pub fn update_fov(
+    q: Query<&Pos, With<PlayerTag>>,
+    grid: Res<Grid<Stuff>>,
+    mut explored: ResMut<Explored>,
+    mut visible: ResMut<Visible>,
+    mut line_of_sight: ResMut<LineOfSight>,
+    visibility: Res<Visibility>,
+    light_map: Res<LightMap>,
+    ambient: Res<AmbientLight>,
+    opaque: Query<&(), With<Opaque>>,
+    player_id: Res<PlayerId>,
+) {
+    if let Some(player_pos) = player_id.get(&q) {
+        let visible = &mut visible.0;
+        let line_of_sight = &mut line_of_sight.0;
+        visible.fill(false);
+        line_of_sight.fill(false);
+        fov::compute_fov(
+            player_pos.0,
+            visibility.radius,
+            visibility.circular,
+            |pos| is_opaque(&grid, &opaque, pos),
+            |pos| {
+                if let Some(in_sight) = line_of_sight.at_mut(pos.x, pos.y) {
+                    *in_sight = true;
+                }
+                let lit = pos == player_pos.0 || is_lit(pos, &light_map, *ambient);
+                if lit && let Some(visible) = visible.at_mut(pos.x, pos.y) {
+                    *visible = true;
+                }
+            },
+        );
+        explored.0.or_eq(visible);
+    }
+}
+
+/// Is the cell at `pos` lit, by the ambient light or by a light source
+fn is_lit(pos: Vec2, light_map: &LightMap, ambient: AmbientLight) -> bool {
+    ambient.0 > 0
+        || light_map
+            .0
+            .at(pos.x, pos.y)
+            .is_some_and(|light| light.intensity > 0)
+}
+
+/// The brightest of the player's own light and the lights it carries
+fn player_light(
+    own: Option<&LightSource>,
+    inventory: &Inventory,
+    lights: &Query<&LightSource>,
+) -> Option<LightSource> {
+    inventory
+        .iter()
+        .filter_map(|id| lights.fetch(id).copied())
+        .chain(own.copied())
+        .max_by_key(|light| light.radius)
+}
+
+/// recompute the light emitted by the light sources on the map
+pub fn update_light_map(
+    grid: Res<Grid<Stuff>>,
+    opaque: Query<&(), With<Opaque>>,
+    sources: Query<(&Pos, &LightSource), WithOut<PlayerTag>>,
+    q_player: Query<(&Pos, Option<&LightSource>, &Inventory), With<PlayerTag>>,
+    lights: Query<&LightSource>,
+    player_id: Res<PlayerId>,
+    mut light_map: ResMut<LightMap>,
+) {
+    let light_map = &mut light_map.0;
+    if light_map.dims() != grid.dims() {
+        *light_map = Grid::new(grid.dims());
+    } else {
+        light_map.fill(Light::default());
+    }
+    for (pos, source) in sources.iter() {
+        cast_light(&grid, &opaque, light_map, pos.0, *source);
+    }
+    if let Some((pos, own, inventory)) = player_id.get(&q_player)
+        && let Some(source) = player_light(own, inventory, &lights)
+    {
+        cast_light(&grid, &opaque, light_map, pos.0, source);
+    }
+}
+
+fn cast_light(
+    grid: &Grid<Stuff>,
+    opaque: &Query<&(), With<Opaque>>,
+    light_map: &mut Grid<Light>,
+    origin: Vec2,
+    source: LightSource,
+) {
+    let falloff = (source.radius + 1) as f32;
+    fov::compute_fov(
+        origin,
+        source.radius,
+        true,
+        |pos| is_opaque(grid, opaque, pos),
+        |pos| {
+            let Some(light) = light_map.at_mut(pos.x, pos.y) else {
+                return;
+            };
+            let d = pos - origin;
+            let distance = ((d.x * d.x + d.y * d.y) as f32).sqrt();
+            let intensity = (255.0 * (1.0 - distance / falloff)).max(1.0) as u8;
+            if intensity > light.intensity {
+                *light = Light {
+                    intensity,
+                    color: Some(source.color),
+                };
+            }
+        },
+    );
+}
+
 /// return wether the segment hits something and where
+///
+/// the endpoints are not checked
 fn walk_grid_on_segment(
     from: Vec2,
     to: Vec2,
     grid: &Grid<Stuff>,
-    opaque: &Query<&(), With<Opaque>>,
+    blocks: impl Fn(EntityId) -> bool,
 ) -> Option<Vec2> {
     let dx = to.x - from.x;
     let dy = to.y - from.y;
@@ -849,11 +1443,10 @@ fn walk_grid_on_segment(
     // skip the first pos
     step(&mut p, &mut ix, &mut iy, nx, ny, sign_x, sign_y);
     while ix < nx || iy < ny {
-        // if there is an entity at this position and the entity is opaque
         if grid
             .at(p.x, p.y)
             .and_then(|x| x.as_ref())
-            .map(|id| opaque.contains(*id))
+            .map(|id| blocks(*id))
             .unwrap_or(false)
         {
             return Some(p);
@@ -875,69 +1468,24 @@ fn step(p: &mut Vec2, ix: &mut f32, iy: &mut f32, nx: f32, ny: f32, sign_x: i32,
     }
 }
 
-fn set_visible(
+/// the target is in range and no wall or creature is in the way
+fn has_line_of_fire(
+    from: Vec2,
+    to: Vec2,
+    ranged: &Ranged,
     grid: &Grid<Stuff>,
-    visible: &mut Grid<bool>,
-    opaque: &Query<&(), With<Opaque>>,
-    player_pos: Vec2,
-    radius: i32,
-) {
-    visible.splat_set([Vec2::ZERO, visible.dims()], false);
-    // walk the visible range
-    walk_square(-Vec2::splat(radius), Vec2::splat(radius))
-        .map(|d| player_pos + d)
-        .for_each(|limit| {
-            if walk_grid_on_segment(player_pos, limit, grid, opaque).is_none() {
-                if let Some(visible) = visible.at_mut(limit.x, limit.y) {
-                    *visible = true;
-                }
-            }
-        });
+    blockers: &Query<&(), Or<With<Opaque>, With<Ai>>>,
+    ruleset: Ruleset,
+) -> bool {
+    ruleset.distance(from, to) <= ranged.range
+        && walk_grid_on_segment(from, to, grid, |id| blockers.contains(id)).is_none()
 }
 
-/// go over the visible range and if an item is adjacent to a visible empty tile, then set that to
-/// visible as well
-fn flood_vizibility(grid: &Grid<Stuff>, visible: &mut Grid<bool>, player_pos: Vec2, radius: i32) {
-    let _s = tracing::debug_span!("flood").entered();
-
-    let mut to_update = smallvec::SmallVec::<[_; 64]>::new();
-    walk_square(-Vec2::splat(radius), Vec2::splat(radius))
-        .map(|d| player_pos + d)
-        .for_each(|pos| {
-            if visible.at(pos.x, pos.y).copied().unwrap_or(false) {
-                return;
-            }
-            walk_square(-Vec2::ONE, Vec2::ONE)
-                .map(|d| pos + d)
-                .for_each(|new_pos| {
-                    if visible.at(new_pos.x, new_pos.y).copied().unwrap_or(false)
-                        && grid[new_pos].is_none()
-                    {
-                        to_update.push(pos);
-                    }
-                });
-        });
-    for pos in to_update {
-        visible[pos] = true;
-    }
-}
-
-/// recompute visible area
-pub fn update_fov(
-    q: Query<&Pos, With<PlayerTag>>,
-    grid: Res<Grid<Stuff>>,
-    mut explored: ResMut<Explored>,
-    mut visible: ResMut<Visible>,
-    viewport: Res<Visibility>,
-    opaque: Query<&(), With<Opaque>>,
-    player_id: Res<PlayerId>,
-) {
-    let radius = viewport.0.x.max(viewport.0.y);
-    if let Some(player_pos) = player_id.get(&q) {
-        set_visible(&grid, &mut visible.0, &opaque, player_pos.0, radius);
-        visible.0[player_pos.0] = true;
-        flood_vizibility(&grid, &mut visible.0, player_pos.0, radius);
-        explored.0.or_eq(&visible.0);
+/// out of bounds positions are opaque
+fn is_opaque(grid: &Grid<Stuff>, opaque: &Query<&(), With<Opaque>>, pos: Vec2) -> bool {
+    match grid.at(pos.x, pos.y) {
+        Some(stuff) => stuff.map(|id| opaque.contains(id)).unwrap_or(false),
+        None => true,
     }
 }
 
@@ -979,171 +1527,649 @@ fn perform_move(mut q: Query<(&mut Pos, &mut Velocity)>, mut grid: ResMut<Grid<S
     }
 }
 
-fn update_confusion(
-    mut cmd: Commands,
-    mut confused: Query<(EntityId, Option<&Name>, &mut ConfusedAi)>,
-    mut log: ResMut<LogHistory>,
+/// How far monsters notice hostile creatures, reduced by the stealth of the creature
+const AI_SIGHT_RANGE: i32 = 8;
+
+type HostileQuery<'a> =
+    Query<'a, (EntityId, &'a Pos, &'a Faction, Option<&'a Equipment>), With<Hp>>;
+
+/// Stealth of a creature and its equipment
+fn total_stealth(id: EntityId, equipment: Option<&Equipment>, q_stealth: &Query<&Stealth>) -> i32 {
+    let equipped = equipment
+        .into_iter()
+        .flat_map(|e| [e.weapon, e.armor, e.ranged])
+        .flatten();
+    std::iter::once(id)
+        .chain(equipped)
+        .filter_map(|id| q_stealth.fetch(id))
+        .map(|s| s.0)
+        .sum()
+}
+
+/// Nearest hostile creature the AI at `pos` can see
+///
+/// Line of sight with the player is symmetric: if the player could see the monster's cell, the
+/// monster sees the player, while the player stands in light. Monsters see each other in the dark.
+fn nearest_hostile(
+    id: EntityId,
+    pos: Vec2,
+    faction: Faction,
+    player_id: Option<EntityId>,
+    q_hostiles: &HostileQuery,
+    q_stealth: &Query<&Stealth>,
+    grid: &Grid<Stuff>,
+    opaque: &Query<&(), With<Opaque>>,
+    line_of_sight: &LineOfSight,
+    light_map: &LightMap,
+    ambient: AmbientLight,
+    ruleset: Ruleset,
+) -> Option<(EntityId, Vec2)> {
+    q_hostiles
+        .iter()
+        .filter(|(other, _, other_faction, _)| *other != id && faction.is_hostile(**other_faction))
+        .filter(|(other, Pos(other_pos), _, equipment)| {
+            let range = (AI_SIGHT_RANGE - total_stealth(*other, *equipment, q_stealth)).max(1);
+            if ruleset.distance(pos, *other_pos) > range {
+                return false;
+            }
+            if Some(*other) == player_id {
+                line_of_sight.0.at(pos.x, pos.y).copied().unwrap_or(false)
+                    && is_lit(*other_pos, light_map, ambient)
+            } else {
+                walk_grid_on_segment(pos, *other_pos, grid, |id| opaque.contains(id)).is_none()
+            }
+        })
+        // prefer the player on ties
+        .min_by_key(|(other, Pos(other_pos), _, _)| {
+            (ruleset.distance(pos, *other_pos), Some(*other) != player_id)
+        })
+        .map(|(other, Pos(other_pos), _, _)| (other, *other_pos))
+}
+
+/// Distance sounds travel
+const COMBAT_NOISE: i32 = 6;
+const DOOR_NOISE: i32 = 8;
+const EXPLOSION_NOISE: i32 = 12;
+
+/// Tiles reached by a noise and their distance from it
+///
+/// Sound goes around walls, but not through them
+fn propagate_noise(
+    noise: Noise,
+    grid: &Grid<Stuff>,
+    opaque: &Query<&(), With<Opaque>>,
+    ruleset: Ruleset,
+) -> HashMap<Vec2, i32> {
+    let mut reached = HashMap::default();
+    let mut open = VecDeque::new();
+    reached.insert(noise.pos, 0);
+    open.push_back((noise.pos, 0));
+    while let Some((pos, distance)) = open.pop_front() {
+        if distance >= noise.volume {
+            continue;
+        }
+        for d in ruleset.directions() {
+            let next = pos + *d;
+            if reached.contains_key(&next) || is_opaque(grid, opaque, next) {
+                continue;
+            }
+            reached.insert(next, distance + 1);
+            open.push_back((next, distance + 1));
+        }
+    }
+    reached
+}
+
+/// Monsters that hear a noise wake up and come to investigate
+fn update_noise(
+    mut noises: ResMut<Noises>,
+    grid: Res<Grid<Stuff>>,
+    opaque: Query<&(), With<Opaque>>,
+    mut q: Query<(EntityId, &Pos, &mut AiState), With<Ai>>,
+    ruleset: Res<Ruleset>,
 ) {
-    for (id, name, confusion) in confused.iter_mut() {
-        confusion.duration -= 1;
-        if confusion.duration <= 0 {
-            if let Some(Name(name)) = name {
-                log.push(STATUS_EFFECT, format!("{} is no longer confused!", name));
+    for noise in noises.0.drain(..) {
+        let reached = propagate_noise(noise, &grid, &opaque, *ruleset);
+        for (id, Pos(pos), state) in q.iter_mut() {
+            if *state == AiState::Fleeing || !reached.contains_key(pos) {
+                continue;
             }
-            cmd.entity(id).remove::<ConfusedAi>();
+            debug!(
+                id = tracing::field::display(id),
+                noise = tracing::field::display(noise.pos),
+                "heard a noise"
+            );
+            // monsters that see their target will go after it instead
+            *state = AiState::Hunting {
+                last_known: noise.pos,
+            };
         }
     }
 }
 
-fn update_ai_move<'a>(
-    q_player: Query<(&Pos, &LastPos), (With<Pos>, With<PlayerTag>)>,
+/// Monsters notice hostile creatures this close while asleep
+const AI_WAKE_RANGE: i32 = 2;
+
+/// Transition monsters between [AiState]s based on what they see and their hp
+fn update_ai_state(
     player_id: Res<PlayerId>,
     grid: Res<Grid<Stuff>>,
-    mut q: QuerySet<(
-        // melee
-        Query<
-            'a,
-            (
-                &mut PathCache,
-                &Pos,
-                Option<&Leash>,
-                &mut Velocity,
-                Option<&Slowed>,
-            ),
-            (With<Melee>, WithOut<ConfusedAi>),
-        >,
-        // confused
-        Query<'a, &mut Velocity, With<ConfusedAi>>,
-    )>,
-    q_walk: Query<&Walkable>,
+    mut q: Query<
+        (
+            EntityId,
+            &mut AiState,
+            &Pos,
+            &Hp,
+            &Faction,
+            Option<&Leash>,
+            Option<&AiBehaviour>,
+        ),
+        With<Ai>,
+    >,
+    q_hostiles: HostileQuery,
+    q_stealth: Query<&Stealth>,
     opaque: Query<&(), With<Opaque>>,
+    line_of_sight: Res<LineOfSight>,
+    light_map: Res<LightMap>,
+    ambient: Res<AmbientLight>,
+    ruleset: Res<Ruleset>,
 ) {
-    let Some((Pos(player_pos), LastPos(last_player_pos))) = player_id.get(&q_player) else {
-        debug!("No player on the map! Skipping melee update");
+    for (id, state, Pos(pos), hp, faction, leash, behaviour) in q.iter_mut() {
+        let behaviour = behaviour.copied().unwrap_or_default();
+        let target = nearest_hostile(
+            id,
+            *pos,
+            *faction,
+            player_id.0,
+            &q_hostiles,
+            &q_stealth,
+            &grid,
+            &opaque,
+            &line_of_sight,
+            &light_map,
+            *ambient,
+            *ruleset,
+        );
+        if *state == AiState::Asleep {
+            let disturbed = !hp.full()
+                || target.is_some_and(|(_, t)| ruleset.distance(*pos, t) <= AI_WAKE_RANGE);
+            if !disturbed {
+                continue;
+            }
+            debug!(id = tracing::field::display(id), "wakes up");
+            *state = AiState::Idle;
+        }
+        let hurt = hp.current * 100 <= hp.max * behaviour.flee_hp;
+        // the origin may be taken, close enough
+        let at_home = leash.is_none_or(|leash| ruleset.is_adjacent(leash.origin, *pos));
+        let new_state = match (target, *state) {
+            (Some(_), _) if hurt => AiState::Fleeing,
+            (Some((_, target_pos)), _) => AiState::Hunting {
+                last_known: target_pos,
+            },
+            // search the last known position before giving up
+            (None, AiState::Hunting { last_known }) if !ruleset.is_adjacent(*pos, last_known) => {
+                AiState::Hunting { last_known }
+            }
+            (None, AiState::Hunting { .. } | AiState::Fleeing) => give_up(&behaviour),
+            (None, AiState::Returning) if at_home => AiState::Idle,
+            (None, state) => state,
+        };
+        if new_state != *state {
+            debug!(
+                id = tracing::field::display(id),
+                from = ?*state,
+                to = ?new_state,
+                "ai state"
+            );
+            *state = new_state;
+        }
+    }
+}
+
+/// State of a monster that lost its target
+fn give_up(behaviour: &AiBehaviour) -> AiState {
+    match behaviour.initial {
+        AiState::Wandering => AiState::Wandering,
+        _ => AiState::Returning,
+    }
+}
+
+/// Compute the maps shared by every monster chasing or fleeing the player
+fn update_player_maps(
+    player_id: Res<PlayerId>,
+    q_player: Query<&Pos, With<PlayerTag>>,
+    grid: Res<Grid<Stuff>>,
+    q_static: Query<&(), With<StaticStuff>>,
+    q_ai: Query<&(), With<Ai>>,
+    mut maps: ResMut<PlayerMaps>,
+    ruleset: Res<Ruleset>,
+) {
+    let maps = &mut *maps;
+    let Some(Pos(player_pos)) = player_id.get(&q_player) else {
+        maps.goal = None;
         return;
     };
-    for (cache, Pos(pos), leash, vel, slow) in q.q0_mut().iter_mut() {
-        if pos.manhatten(*player_pos) > 1 {
-            if walk_grid_on_segment(*pos, *player_pos, &grid, &opaque).is_none() {
-                debug!("Player is visible, finding path");
+    // walls and doors can't be walked through by monsters
+    // other monsters will move out of the way, so they don't add cost
+    let entity_cost = |id: EntityId| {
+        if q_static.contains(id) {
+            None
+        } else if q_ai.contains(id) {
+            Some(0)
+        } else {
+            Some(OCCUPIED_COST)
+        }
+    };
+    maps.approach
+        .compute([(*player_pos, 0)], &grid, *ruleset, entity_cost);
+    maps.approach
+        .flee_map(&mut maps.flee, &grid, *ruleset, entity_cost);
+    maps.goal = Some(*player_pos);
+}
+
+fn update_ai_move(
+    player_id: Res<PlayerId>,
+    maps: Res<PlayerMaps>,
+    grid: Res<Grid<Stuff>>,
+    mut q: Query<
+        (
+            EntityId,
+            &mut PathCache,
+            &Pos,
+            Option<&Leash>,
+            &mut Velocity,
+            Option<&StatusEffects>,
+            Option<&Ranged>,
+            &Faction,
+            &mut AiState,
+            Option<&AiBehaviour>,
+        ),
+        With<Melee>,
+    >,
+    q_hostiles: HostileQuery,
+    q_stealth: Query<&Stealth>,
+    q_walk: Query<&Walkable>,
+    blockers: Query<&(), Or<With<Opaque>, With<Ai>>>,
+    opaque: Query<&(), With<Opaque>>,
+    line_of_sight: Res<LineOfSight>,
+    light_map: Res<LightMap>,
+    ambient: Res<AmbientLight>,
+    mut rng: ResMut<GameRng>,
+    ruleset: Res<Ruleset>,
+) {
+    let delta = ruleset.directions();
+    for (id, cache, Pos(pos), leash, vel, effects, ranged, faction, state, behaviour) in
+        q.iter_mut()
+    {
+        if effects.is_some_and(|e| e.has(StatusKind::Confusion)) {
+            vel.0 = *delta.choose(&mut *rng).unwrap();
+            continue;
+        }
+        let slow = effects.and_then(|e| e.get(StatusKind::Slow));
+        let slowed = |rng: &mut GameRng| {
+            slow.is_some_and(|slow| !skill_check(&mut *rng, slow.power.clamp(2, 6)))
+        };
+        match *state {
+            AiState::Asleep | AiState::Idle => {
                 cache.path.clear();
-                cache.path.push(*player_pos); // push the last pos, so entities can follow players
-                // across corridors
-                cache.path.push(*last_player_pos);
-                if !find_path(*pos, *last_player_pos, &grid, &q_walk, &mut cache.path) {
-                    // finding path failed, pop the player pos
-                    cache.path.clear();
+                continue;
+            }
+            AiState::Wandering => {
+                cache.path.clear();
+                let steps: ArrayVec<Vec2, 8> = delta
+                    .iter()
+                    .map(|d| *pos + *d)
+                    .filter(|p| grid.is_free(p.x, p.y))
+                    .collect();
+                if let Some(step) = steps.choose(&mut *rng) {
+                    cache.path.push(*step);
+                }
+            }
+            AiState::Fleeing => {
+                cache.path.clear();
+                let threat = nearest_hostile(
+                    id,
+                    *pos,
+                    *faction,
+                    player_id.0,
+                    &q_hostiles,
+                    &q_stealth,
+                    &grid,
+                    &opaque,
+                    &line_of_sight,
+                    &light_map,
+                    *ambient,
+                    *ruleset,
+                );
+                let step = match threat {
+                    Some((threat_id, _)) if Some(threat_id) == player_id.0 => {
+                        maps.flee
+                            .downhill(*pos, *ruleset, |p| grid.is_free(p.x, p.y))
+                    }
+                    Some((_, threat_pos)) => retreat_step(*pos, threat_pos, leash, &grid, *ruleset),
+                    None => None,
+                };
+                if let Some(step) = step {
+                    cache.path.push(step);
                 }
-                // if the distance to the player is 1
-                // there is a bug in pathfinding that returns the current pos as the last
-                while cache.path.last() == Some(pos) {
-                    cache.path.pop();
+            }
+            AiState::Hunting { last_known } => {
+                let target = nearest_hostile(
+                    id,
+                    *pos,
+                    *faction,
+                    player_id.0,
+                    &q_hostiles,
+                    &q_stealth,
+                    &grid,
+                    &opaque,
+                    &line_of_sight,
+                    &light_map,
+                    *ambient,
+                    *ruleset,
+                );
+                if let Some((_, target_pos)) = target {
+                    if ruleset.is_adjacent(*pos, target_pos) {
+                        // in melee range, fight
+                        cache.path.clear();
+                        continue;
+                    }
+                    if let Some(ranged) = ranged
+                        && has_line_of_fire(*pos, target_pos, ranged, &grid, &blockers, *ruleset)
+                    {
+                        // hold the position and shoot, back off if the target gets too close
+                        cache.path.clear();
+                        if ruleset.distance(*pos, target_pos) < RANGED_KEEP_DISTANCE
+                            && !slowed(&mut *rng)
+                            && let Some(new_pos) =
+                                retreat_step(*pos, target_pos, leash, &grid, *ruleset)
+                        {
+                            vel.0 = new_pos - *pos;
+                        }
+                        continue;
+                    }
                 }
-            } else if cache.path.is_empty() {
-                // if the enemy has a leash and the player is not visible, return to the origin
-                if let Some(leash) = leash {
+                cache.path.clear();
+                if maps.goal == Some(last_known) {
+                    // chasing the player, use the shared map
+                    if let Some(step) = maps
+                        .approach
+                        .downhill(*pos, *ruleset, |p| grid.is_free(p.x, p.y))
+                    {
+                        cache.path.push(step);
+                    }
+                } else if !find_path(*pos, last_known, &grid, &q_walk, *ruleset, &mut cache.path) {
+                    debug!("Can't reach the last known target position, giving up");
                     cache.path.clear();
-                    find_path(*pos, leash.origin, &grid, &q_walk, &mut cache.path);
+                    if target.is_none() {
+                        *state = give_up(&behaviour.copied().unwrap_or_default());
+                    }
                 }
             }
-            if let Some(mut new_pos) = cache.path.pop() {
-                if let Some(slow) = slow
-                    && !skill_check(slow.power.clamp(2, 6) as i32)
+            AiState::Returning => {
+                if cache.path.is_empty()
+                    && let Some(leash) = leash
+                    && !find_path(
+                        *pos,
+                        leash.origin,
+                        &grid,
+                        &q_walk,
+                        *ruleset,
+                        &mut cache.path,
+                    )
                 {
-                    debug!("Enemy is slowed. Move failed.");
-                    cache.path.push(new_pos);
-                    break;
-                }
-
-                if let Some(leash) = leash {
-                    // if at the end of leash, don't move
-                    if new_pos.manhatten(leash.origin) > leash.radius {
-                        cache.path.clear();
-                        new_pos = *pos;
-                    }
+                    // can't get home, stay put
+                    cache.path.clear();
+                    *state = AiState::Idle;
                 }
+            }
+        }
+        if let Some(mut new_pos) = cache.path.pop() {
+            if slowed(&mut *rng) {
+                debug!("Enemy is slowed. Move failed.");
+                cache.path.push(new_pos);
+                continue;
+            }
 
-                if grid[new_pos].is_some() {
-                    // taken
+            if let Some(leash) = leash {
+                // if at the end of leash, don't move
+                if ruleset.distance(new_pos, leash.origin) > leash.radius {
                     cache.path.clear();
-                } else {
-                    vel.0 = new_pos - *pos;
+                    new_pos = *pos;
                 }
             }
-        } else {
-            cache.path.clear();
+
+            if grid[new_pos].is_some() {
+                // taken
+                cache.path.clear();
+            } else {
+                vel.0 = new_pos - *pos;
+            }
         }
     }
+}
+
+/// Ranged enemies back off if their target is closer than this
+const RANGED_KEEP_DISTANCE: i32 = 3;
 
-    let delta = [Vec2::X, -Vec2::X, Vec2::Y, -Vec2::Y];
-    let mut rng = rand::rng();
-    for vel in q.q1_mut().iter_mut() {
-        vel.0 = *delta.choose(&mut rng).unwrap();
+/// free neighbour that is further away from the target
+fn retreat_step(
+    pos: Vec2,
+    target_pos: Vec2,
+    leash: Option<&Leash>,
+    grid: &Grid<Stuff>,
+    ruleset: Ruleset,
+) -> Option<Vec2> {
+    let distance = |p: Vec2| ruleset.distance(p, target_pos);
+    ruleset
+        .directions()
+        .iter()
+        .map(|d| pos + *d)
+        .filter(|p| grid.at(p.x, p.y).is_some_and(|stuff| stuff.is_none()))
+        .filter(|p| leash.is_none_or(|leash| ruleset.distance(*p, leash.origin) <= leash.radius))
+        .filter(|p| distance(*p) > distance(pos))
+        .max_by_key(|p| distance(*p))
+}
+
+fn update_ranged_ai(
+    mut q_target: Query<(
+        &mut Hp,
+        Option<&mut Defense>,
+        Option<&Name>,
+        Option<&Resistances>,
+    )>,
+    player_id: Res<PlayerId>,
+    q_enemy: Query<
+        (
+            EntityId,
+            Option<&Name>,
+            &Ranged,
+            &Pos,
+            Option<&StatusEffects>,
+            Option<&AttackMods>,
+            &Faction,
+            &AiState,
+        ),
+        With<Ai>,
+    >,
+    q_hostiles: HostileQuery,
+    q_stealth: Query<&Stealth>,
+    blockers: Query<&(), Or<With<Opaque>, With<Ai>>>,
+    opaque: Query<&(), With<Opaque>>,
+    grid: Res<Grid<Stuff>>,
+    line_of_sight: Res<LineOfSight>,
+    light_map: Res<LightMap>,
+    ambient: Res<AmbientLight>,
+    mut log: ResMut<LogHistory>,
+    mut rng: ResMut<GameRng>,
+    mut noises: ResMut<Noises>,
+    ruleset: Res<Ruleset>,
+) {
+    for (id, name, ranged, Pos(pos), effects, mods, faction, state) in q_enemy.iter() {
+        if effects.is_some_and(|e| e.has(StatusKind::Confusion))
+            || !matches!(state, AiState::Hunting { .. })
+        {
+            continue;
+        }
+        let Some((target_id, target_pos)) = nearest_hostile(
+            id,
+            *pos,
+            *faction,
+            player_id.0,
+            &q_hostiles,
+            &q_stealth,
+            &grid,
+            &opaque,
+            &line_of_sight,
+            &light_map,
+            *ambient,
+            *ruleset,
+        ) else {
+            continue;
+        };
+        // adjacent enemies fight in melee
+        if ruleset.is_adjacent(*pos, target_pos)
+            || !has_line_of_fire(*pos, target_pos, ranged, &grid, &blockers, *ruleset)
+        {
+            continue;
+        }
+        let Some((target_hp, defense, target_name, resistances)) = q_target.fetch_mut(target_id)
+        else {
+            continue;
+        };
+        noises.push(target_pos, COMBAT_NOISE);
+        let name = name
+            .map(|name| name.0.clone())
+            .unwrap_or_else(|| id.to_string());
+        let target_name = if Some(target_id) == player_id.0 {
+            "you"
+        } else {
+            target_name.map(|n| n.0.as_str()).unwrap_or("")
+        };
+        let evasion = defense.as_ref().map(|d| d.evasion).unwrap_or(0);
+        let outcome = attack_roll(&mut *rng, ranged.skill, evasion, mods);
+        if !outcome.is_hit() {
+            log.push(
+                ENEMY_ATTACK,
+                format!("{} shoots at {} and misses", name, target_name),
+            );
+            continue;
+        }
+        // armor and wards protect against projectiles too
+        let attack = Attack::physical(ranged.power).with_mods(mods);
+        let damage = compute_damage(&attack, outcome, defense, resistances);
+        target_hp.current -= damage;
+        debug!(
+            id = tracing::field::display(id),
+            target_id = tracing::field::display(target_id),
+            damage,
+            "ranged hit"
+        );
+        log.push(
+            ENEMY_ATTACK,
+            format!(
+                "{} shoots {} for {} damage{}",
+                name,
+                target_name,
+                damage,
+                outcome.log_suffix()
+            ),
+        );
     }
 }
 
 fn update_melee_ai(
-    mut q_player: Query<(EntityId, &Pos, &mut Defense), (With<Hp>, With<PlayerTag>)>,
+    mut q_target: Query<(
+        &mut Hp,
+        Option<&mut Defense>,
+        Option<&Name>,
+        Option<&Resistances>,
+    )>,
     player_id: Res<PlayerId>,
-    mut q_target: Query<(&mut Hp, Option<&Name>)>,
+    q_faction: Query<&Faction, With<Hp>>,
     mut q_enemy: Query<
         (
             EntityId,
             Option<&Name>,
             &Melee,
             &Pos,
-            Option<&ConfusedAi>,
+            Option<&StatusEffects>,
             Option<&Velocity>,
+            Option<&AttackMods>,
+            &Faction,
+            &AiState,
         ),
         With<Ai>,
     >,
     grid: Res<Grid<Stuff>>,
     mut log: ResMut<LogHistory>,
+    mut rng: ResMut<GameRng>,
+    mut noises: ResMut<Noises>,
+    ruleset: Res<Ruleset>,
 ) {
-    let Some((player_id, Pos(player_pos), player_defense)) = player_id.get_mut(&mut q_player)
-    else {
-        debug!("No player on the map! Skipping melee update");
-        return;
-    };
-
-    for (id, name, Melee { power, skill }, Pos(pos), confused, vel) in q_enemy.iter_mut() {
+    for (id, name, Melee { power, skill }, Pos(pos), effects, vel, mods, faction, state) in
+        q_enemy.iter_mut()
+    {
         let name = name
             .map(|name| name.0.clone())
             .unwrap_or_else(|| id.to_string());
-        let mut target = None;
-        if confused.is_some() {
-            if let Some(vel) = vel {
-                let target_pos = *pos + vel.0;
-                if let Some(t_id) = grid[target_pos] {
-                    if let Some(t) = q_target.fetch_mut(t_id) {
-                        target = Some((t.0, t.1.map(|n| n.0.as_str()), t_id));
-                    }
-                }
-            }
-        } else if pos.manhatten(*player_pos) <= 1 {
-            if let Some(t) = q_target.fetch_mut(player_id) {
-                target = Some((t.0, Some("you"), player_id));
-            }
-        }
+        let target_id = if effects.is_some_and(|e| e.has(StatusKind::Confusion)) {
+            // confused monsters hit whatever they stumble into
+            vel.and_then(|vel| grid[*pos + vel.0])
+        } else if !matches!(state, AiState::Hunting { .. }) {
+            None
+        } else {
+            ruleset
+                .directions()
+                .iter()
+                .filter_map(|d| grid[*pos + *d])
+                .filter(|t_id| {
+                    q_faction
+                        .fetch(*t_id)
+                        .is_some_and(|t_faction| faction.is_hostile(*t_faction))
+                })
+                // prefer the player
+                .min_by_key(|t_id| Some(*t_id) != player_id.0)
+        };
 
-        if let Some((target_hp, target_name, target_id)) = target {
-            if !skill_check(*skill) {
-                log.push(ENEMY_ATTACK, format!("{} misses", name));
-                continue;
-            }
-            let damage = compute_melee_damage(*power, player_defense);
-            target_hp.current -= damage;
-            let target_name = target_name.unwrap_or("");
-            debug!(
-                id = tracing::field::display(id),
-                target_id = tracing::field::display(target_id),
-                "melee hit"
-            );
-            log.push(
-                ENEMY_ATTACK,
-                format!("{} hits {} for {} damage", name, target_name, damage),
-            );
+        let Some(target_id) = target_id else {
+            continue;
+        };
+        let Some((target_hp, defense, target_name, resistances)) = q_target.fetch_mut(target_id)
+        else {
+            continue;
+        };
+        noises.push(*pos, COMBAT_NOISE);
+        let evasion = defense.as_ref().map(|d| d.evasion).unwrap_or(0);
+        let outcome = attack_roll(&mut *rng, *skill, evasion, mods);
+        if !outcome.is_hit() {
+            log.push(ENEMY_ATTACK, format!("{} misses", name));
+            continue;
         }
+        let attack = Attack::physical(*power).with_mods(mods);
+        let damage = compute_damage(&attack, outcome, defense, resistances);
+        target_hp.current -= damage;
+        let target_name = if Some(target_id) == player_id.0 {
+            "you"
+        } else {
+            target_name.map(|n| n.0.as_str()).unwrap_or("")
+        };
+        debug!(
+            id = tracing::field::display(id),
+            target_id = tracing::field::display(target_id),
+            "melee hit"
+        );
+        log.push(
+            ENEMY_ATTACK,
+            format!(
+                "{} hits {} for {} damage{}",
+                name,
+                target_name,
+                damage,
+                outcome.log_suffix()
+            ),
+        );
     }
 }
 
@@ -1198,12 +2224,6 @@ fn update_ai_hp(
     }
 }
 
-/// Throw a 1D6, if result is <= skill then the check passes
-fn skill_check(skill: i32) -> bool {
-    let mut rng = rand::rng();
-    rng.random_range(1..=6) <= skill
-}
-
 fn update_tick(mut t: ResMut<GameTick>) {
     t.0 += 1;
 }
@@ -1236,7 +2256,21 @@ pub fn update_camera_pos(
 }
 
 pub fn update_output(
-    q_player: Query<(&Pos, &Hp, &Melee, &Level, &Defense, &CoinPouch), With<PlayerTag>>,
+    q_player: Query<
+        (
+            &Pos,
+            &Hp,
+            &Melee,
+            &Ranged,
+            &Level,
+            &Defense,
+            &CoinPouch,
+            &Inventory,
+            Option<&LightSource>,
+        ),
+        With<PlayerTag>,
+    >,
+    lights: Query<&LightSource>,
     player_id: Res<PlayerId>,
     mut output_cache: ResMut<Output>,
     selected: Res<Selected>,
@@ -1247,22 +2281,23 @@ pub fn update_output(
 ) {
     let _span = tracing::span!(tracing::Level::DEBUG, "update_output").entered();
 
-    let player = player_id
-        .get(&q_player)
-        .map(|(pos, hp, attack, level, defense, pouch)| PlayerOutput {
+    let player = player_id.get(&q_player).map(
+        |(pos, hp, attack, ranged, level, defense, pouch, inventory, light)| PlayerOutput {
             level: level.current_level,
             current_xp: level.current_xp,
             needed_xp: level.experience_to_next_level(),
             player_hp: *hp,
             player_attack: attack.power,
+            player_ranged_attack: ranged.power,
             player_pos: pos.0,
             defense: *defense,
             coins: *pouch,
-        });
-    let mut log = Vec::with_capacity(128);
-    for line in history.items.iter() {
-        log.push(line.as_str());
-    }
+            light_radius: player_light(light, inventory, &lights)
+                .map(|light| light.radius)
+                .unwrap_or(0),
+        },
+    );
+    let log = history.items.iter().cloned().collect();
     let targeting = matches!(*app_mode, AppMode::Targeting);
 
     let mut shop = None;
@@ -1297,7 +2332,7 @@ pub fn update_output(
         targeting,
         shop,
     };
-    output_cache.0 = serde_wasm_bindgen::to_value(&result).unwrap();
+    output_cache.0 = Some(result);
 }
 
 fn is_ingame(app_mode: Res<AppMode>) -> bool {
@@ -1321,6 +2356,7 @@ fn player_prepare(
     mut should_update_player: ResMut<ShouldUpdatePlayer>,
     actions: Res<PlayerActions>,
     should_tick: Res<ShouldTick>,
+    travel: Res<Travel>,
     mut log: ResMut<LogHistory>,
     player_id: Res<PlayerId>,
 ) {
@@ -1333,151 +2369,45 @@ fn player_prepare(
     should_update.0 = true;
     should_update_player.0 = player_id.0.is_some();
     if should_update_player.0 && actions.wait() {
-        log.push(WHITE, "Waiting...");
-        should_update_player.0 = false;
-    }
-}
-
-fn canvas_cell_size(width: f64, height: f64, viewport: Vec2) -> f64 {
-    height.min(width) / (viewport.y * 2) as f64
-}
-
-pub fn render_onto_canvas(
-    mut res: ResMut<RenderResources>,
-    grid: Res<Grid<Stuff>>,
-    viewport: Res<Viewport>,
-    camera_pos: Res<CameraPos>,
-    visible: Res<Visible>,
-    explored: Res<Explored>,
-    stuff: Query<(Option<&StaticVisibility>, &Icon, Option<&Color>)>,
-    icons: Res<IconCollection>,
-) {
-    res.update_dims();
-    let width = res.width as f64;
-    let height = res.height as f64;
-    let Some(ctx) = res.ctx.as_mut() else {
-        debug!("No rendering context, skipping render");
-        return;
-    };
-
-    let min = camera_pos.0 - viewport.0;
-    let max = camera_pos.0 + viewport.0;
-
-    let cell_size = canvas_cell_size(width, height, viewport.0);
-    let icon_scale = cell_size / 512.0;
-
-    let black = "black";
-    let darkgrey = "darkgrey";
-    let white = "white";
-
-    ctx.set_fill_style_str("gray");
-    ctx.fill_rect(0.0, 0.0, width, height);
-
-    for y in min.y.max(0)..(max.y + 1).min(grid.height()) {
-        for x in min.x.max(0)..(max.x + 1).min(grid.width()) {
-            let pos = Vec2::new(x, y);
-            let explored = explored.0[pos];
-
-            if !explored {
-                continue;
-            }
-
-            let visible = visible.0[pos];
-
-            let render_pos = pos - min;
-            let render_x = render_pos.x as f64 * cell_size;
-            let render_y = render_pos.y as f64 * cell_size;
-
-            if visible {
-                ctx.set_fill_style_str(&black);
-            } else {
-                ctx.set_fill_style_str(&darkgrey);
-            }
-            // either the icon background or the empty space
-            ctx.fill_rect(render_x, render_y, cell_size, cell_size);
-
-            match grid[pos].and_then(|id| stuff.fetch(id).map(|x| (id, x))) {
-                Some((_id, (static_vis_tag, icon, color)))
-                    if visible || static_vis_tag.is_some() =>
-                {
-                    ctx.fill_rect(render_x, render_y, cell_size, cell_size);
-                    match icons.0.get(icon.0) {
-                        Some(icon) => {
-                            match color {
-                                Some(Color(color)) => {
-                                    ctx.set_fill_style_str(color);
-                                }
-                                None => {
-                                    ctx.set_fill_style_str(white);
-                                }
-                            }
-                            ctx.save();
-                            ctx.translate(render_x, render_y).unwrap();
-                            ctx.scale(icon_scale, icon_scale).unwrap();
-                            ctx.fill_with_path_2d(icon);
-                            ctx.restore();
-                        }
-                        None => {
-                            debug!("Failed to fetch icon");
-                            // if icon can not be fetched
-                            if let Some(Color(color)) = color {
-                                ctx.set_fill_style_str(color);
-                            }
-                            ctx.fill_rect(render_x, render_y, cell_size, cell_size);
-                        }
-                    }
-                }
-                _ => {}
-            }
+        if travel.goal != Some(TravelGoal::Rest) {
+            log.push(WHITE, "Waiting...");
         }
+        should_update_player.0 = false;
     }
 }
 
 pub fn handle_click(
     mut target: ResMut<Selected>,
     mut target_pos: ResMut<TargetPos>,
+    mut travel: ResMut<Travel>,
     mode: Res<AppMode>,
     grid: Res<Grid<Stuff>>,
-    viewport: Res<Viewport>,
-    camera_pos: Res<CameraPos>,
     visible: Res<Visible>,
-    res: Res<RenderResources>,
+    explored: Res<Explored>,
     click: Res<ClickPosition>,
 ) {
-    if click.0.is_none() {
+    let Some(pos) = click.0 else {
         return;
-    }
-    let width = res.width as f64;
-    let height = res.height as f64;
-    let cell_size = canvas_cell_size(width, height, viewport.0);
-
-    let [x, y] = click.0.unwrap();
-
-    let pos = Vec2::new(
-        remap_f64(0.0, width, 0.0, viewport.0.x as f64 * 2.0, x) as i32,
-        remap_f64(0.0, height, 0.0, viewport.0.y as f64 * 2.0, y) as i32,
-    ) + camera_pos.0
-        - viewport.0;
-
-    debug!(
-        pos = tracing::field::debug(pos),
-        camera_pos = tracing::field::debug(camera_pos.0),
-        viewport = tracing::field::debug(viewport.0),
-        cell_size = tracing::field::debug(cell_size),
-        "clicked on grid position",
-    );
+    };
     if matches!(*mode, AppMode::TargetingPosition) {
         target_pos.pos = Some(pos);
         debug!("targeting position {}", pos);
     }
-    if !visible.0.at(pos.x, pos.y).unwrap_or(&false) {
-        target.0 = None;
-        return;
-    }
-
-    let result = grid[pos];
+    let result = if *visible.0.at(pos.x, pos.y).unwrap_or(&false) {
+        grid[pos]
+    } else {
+        None
+    };
     target.0 = result;
     debug!("targeting entity {:?}", result);
+
+    // clicking a known cell with nothing to select walks there
+    if result.is_none()
+        && matches!(*mode, AppMode::Game)
+        && *explored.0.at(pos.x, pos.y).unwrap_or(&false)
+    {
+        travel.start(TravelGoal::To(pos));
+    }
 }
 
 fn record_last_pos(mut q: Query<(&mut LastPos, &Pos)>) {
@@ -1512,7 +2442,7 @@ fn update_should_tick(
     mut should_tick: ResMut<ShouldTick>,
     actions: Res<PlayerActions>,
     tick_time: Res<TickInMs>,
-    q_item_use: Query<&(), Or<With<UseItem>, With<Unequip>>>,
+    q_item_use: Query<&(), Or<With<UseItem>, Or<With<Unequip>, Or<With<Fire>, With<DropItem>>>>>,
     _app_mode: Res<AppMode>,
 ) {
     time.0 += dt.0;
@@ -1588,6 +2518,10 @@ pub fn regenerate_dungeon(mut access: WorldAccess) {
 
     // reset some resources
     world.insert_resource(Visible(Grid::new(dims)));
+    world.insert_resource(LineOfSight(Grid::new(dims)));
+    world.insert_resource(LightMap(Grid::new(dims)));
+    // floors are lit by their light sources only
+    world.insert_resource(AmbientLight::default());
     world.insert_resource(Explored(Grid::new(dims)));
     world.insert_resource(WorldDims(dims));
     world.insert_resource(DungeonFloor {
@@ -1610,7 +2544,8 @@ pub fn regenerate_dungeon(mut access: WorldAccess) {
             SystemStage::new("initial-post-process")
                 .with_system(update_camera_pos)
                 .with_system(update_grid)
-                .with_system(update_fov)
+                .with_system(update_light_map.after(update_grid))
+                .with_system(update_fov.after(update_light_map))
                 .with_system(update_output)
                 .build(),
         )
@@ -1620,12 +2555,15 @@ pub fn regenerate_dungeon(mut access: WorldAccess) {
 fn handle_levelup(
     mut app_mode: ResMut<AppMode>,
     mut stat: Option<ResMut<DesiredStat>>,
-    mut player_q: Query<(&mut Hp, &mut Melee, &mut Level, &mut Defense), With<PlayerTag>>,
+    mut player_q: Query<
+        (&mut Hp, &mut Melee, &mut Level, &mut Defense, &mut Ranged),
+        With<PlayerTag>,
+    >,
     player_id: Res<PlayerId>,
     mut log: ResMut<LogHistory>,
     mut cmd: Commands,
 ) {
-    if let Some((hp, melee, level, defense)) = player_id.get_mut(&mut player_q) {
+    if let Some((hp, melee, level, defense, ranged)) = player_id.get_mut(&mut player_q) {
         if !level.needs_levelup() {
             return;
         }
@@ -1646,6 +2584,9 @@ fn handle_levelup(
                     DesiredStat::Attack => {
                         melee.power += 1;
                     }
+                    DesiredStat::RangedAttack => {
+                        ranged.power += 1;
+                    }
                     DesiredStat::Hp => {
                         let amount = 3;
                         hp.current += amount;
@@ -1668,49 +2609,98 @@ fn handle_levelup(
     }
 }
 
-fn update_poison(
-    mut cmd: Commands,
+/// Regen gained with every level after the first
+const REGEN_PER_LEVEL: i32 = 1;
+
+/// Natural healing, poison stops it
+fn update_regen(
+    tick: Res<GameTick>,
     mut q: Query<(
         EntityId,
         &mut Hp,
-        &mut Poisoned,
-        Option<&mut Name>,
-        Has<PlayerTag>,
+        Option<&Equipment>,
+        Option<&Level>,
+        Option<&StatusEffects>,
     )>,
-    mut log: ResMut<LogHistory>,
+    q_regen: Query<&Regen>,
 ) {
-    for (id, hp, poison, name, player) in q.iter_mut() {
-        if poison.duration <= 0 {
-            cmd.entity(id).remove::<Poisoned>();
+    for (id, hp, equipment, level, effects) in q.iter_mut() {
+        if hp.current <= 0 || hp.full() || effects.is_some_and(|fx| fx.has(StatusKind::Poison)) {
             continue;
         }
-        if let Some(name) = name {
-            let color = if player { ENEMY_ATTACK } else { PLAYER_ATTACK };
-            log.push(
-                color,
-                format!("{} is hit for {} damage by poison", name.0, poison.power),
-            );
+        let equipped = equipment
+            .into_iter()
+            .flat_map(|e| [e.weapon, e.armor, e.ranged])
+            .flatten();
+        let level_bonus = level
+            .map(|l| (l.current_level as i32 - 1) * REGEN_PER_LEVEL)
+            .unwrap_or(0);
+        let rate = std::iter::once(id)
+            .chain(equipped)
+            .filter_map(|id| q_regen.fetch(id))
+            .map(|r| r.0)
+            .sum::<i32>()
+            + level_bonus;
+        if rate <= 0 {
+            continue;
         }
-        poison.duration -= 1;
-        // TODO: poison resistance
-        hp.current -= poison.power;
+        // whole hp gained since the previous tick, so no fractions have to be stored
+        let t = tick.0;
+        let amount = t * rate / 100 - (t - 1) * rate / 100;
+        hp.current = (hp.current + amount).min(hp.max);
     }
 }
 
-fn update_slowed(
+fn update_status_effects(
     mut cmd: Commands,
-    mut q: Query<(EntityId, &mut Slowed, Option<&mut Name>, Has<PlayerTag>)>,
+    mut q: Query<(
+        EntityId,
+        &mut StatusEffects,
+        Option<&mut Hp>,
+        Option<&Name>,
+        Option<&Resistances>,
+        Has<PlayerTag>,
+    )>,
     mut log: ResMut<LogHistory>,
 ) {
-    for (id, slow, name, player) in q.iter_mut() {
-        if slow.duration <= 0 {
-            cmd.entity(id).remove::<Slowed>();
-            if let Some(name) = name {
-                let color = if player { PLAYER_ATTACK } else { ENEMY_ATTACK };
-                log.push(color, format!("{} no longer slowed", name.0));
+    for (id, effects, mut hp, name, resistances, player) in q.iter_mut() {
+        for effect in effects.iter() {
+            let desc = effect.kind.desc();
+            let change = desc.hp_per_turn * effect.power;
+            let Some(hp) = hp.as_mut() else {
+                continue;
+            };
+            if change < 0 {
+                let ty = desc.damage.unwrap_or(DamageType::Physical);
+                let attack = Attack::new(-change, ty);
+                let damage = compute_damage(&attack, RollOutcome::Hit, None, resistances);
+                hp.current -= damage;
+                if let Some(Name(name)) = name {
+                    let color = if player { ENEMY_ATTACK } else { PLAYER_ATTACK };
+                    log.push(
+                        color,
+                        format!(
+                            "{} is hit for {} damage by {}",
+                            name,
+                            damage,
+                            desc.name.to_lowercase()
+                        ),
+                    );
+                }
+            } else if change > 0 && !hp.full() {
+                hp.current = (hp.current + change).min(hp.max);
+                if let Some(Name(name)) = name {
+                    log.push(HEAL, format!("{} regenerates {} hp", name, change));
+                }
             }
-            continue;
         }
-        slow.duration -= 1;
+        for kind in effects.tick() {
+            if let Some(Name(name)) = name {
+                log.push(STATUS_EFFECT, kind.desc().expiry.replace("{}", name));
+            }
+        }
+        if effects.is_empty() {
+            cmd.entity(id).remove::<StatusEffects>();
+        }
     }
 }
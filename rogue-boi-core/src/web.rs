@@ -0,0 +1,639 @@
+//! wasm-bindgen front-end, rendering onto a HTML canvas
+use std::{cell::RefCell, rc::Rc};
+
+use cecs::prelude::*;
+use icons::ICONS;
+use serde::{Serialize, de::DeserializeOwned};
+use tracing::debug;
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    Action, CoreError, GameSession, HashMap, InputEvent, Keymap, KeymapPreset, MapGenParams,
+    Replay, SaveSlots, SlotStorage, Stuff,
+    components::*,
+    grid::Grid,
+    math::{Vec2, remap_f64},
+    systems, utils,
+};
+
+/// State object
+#[wasm_bindgen]
+pub struct Core {
+    session: Rc<RefCell<GameSession>>,
+}
+
+#[wasm_bindgen(start)]
+pub fn start() {
+    utils::set_panic_hook();
+    tracing_wasm::set_as_global_default_with_config(
+        tracing_wasm::WASMLayerConfigBuilder::new()
+            .set_max_level(tracing::Level::DEBUG)
+            .build(),
+    );
+}
+
+#[wasm_bindgen(js_name = "initCore")]
+pub fn init_core() -> Core {
+    let session = Rc::new(RefCell::new(GameSession::new()));
+    Core { session }
+}
+
+#[derive(Default, Clone)]
+pub struct IconCollection(pub HashMap<&'static str, web_sys::Path2d>);
+
+#[derive(Default, Clone)]
+pub struct RenderResources {
+    pub canvas: Option<web_sys::HtmlCanvasElement>,
+    pub ctx: Option<web_sys::CanvasRenderingContext2d>,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl RenderResources {
+    pub fn update_dims(&mut self) {
+        let dims = self
+            .canvas
+            .as_ref()
+            .map(|canvas| (canvas.width(), canvas.height()));
+        let (width, height) = dims.unwrap_or_default();
+        self.width = width;
+        self.height = height;
+    }
+}
+
+fn compute_icons() -> IconCollection {
+    let inner = icons::ICONS_SVG
+        .iter()
+        .map(|(k, svg)| (*k, web_sys::Path2d::new_with_path_string(svg).unwrap()))
+        .collect();
+    IconCollection(inner)
+}
+
+/// Thrown as a `{ code, message }` object
+impl From<CoreError> for JsValue {
+    fn from(err: CoreError) -> Self {
+        serde_wasm_bindgen::to_value(&err).unwrap_or_else(|_| JsValue::from_str(&err.to_string()))
+    }
+}
+
+fn from_js<T: DeserializeOwned>(value: JsValue, what: &'static str) -> Result<T, CoreError> {
+    serde_wasm_bindgen::from_value(value).map_err(|err| CoreError::Deserialize {
+        what,
+        reason: err.to_string(),
+    })
+}
+
+impl Core {
+    /// Keep rendering onto the same canvas when the session replaces its world
+    fn with_new_world<R>(&mut self, f: impl FnOnce(&mut GameSession) -> R) -> R {
+        let mut session = self.session.borrow_mut();
+        let world = session.world();
+        let resources = world.get_resource::<RenderResources>().cloned();
+        let icons = world.get_resource::<IconCollection>().cloned();
+        let result = f(&mut session);
+        let world = session.world_mut();
+        if let Some(resources) = resources {
+            world.insert_resource(resources);
+        }
+        if let Some(icons) = icons {
+            world.insert_resource(icons);
+        }
+        result
+    }
+}
+
+#[wasm_bindgen]
+impl Core {
+    pub fn restart(&mut self) {
+        self.with_new_world(|session| session.restart());
+    }
+
+    #[wasm_bindgen(js_name = "newGameWithSeed")]
+    pub fn new_game_with_seed(&mut self, seed: u64) {
+        self.with_new_world(|session| session.new_game_with_seed(seed));
+    }
+
+    /// Export the recording of the current game
+    pub fn replay(&self) -> Option<String> {
+        self.session.borrow().replay().map(|replay| replay.encode())
+    }
+
+    /// Start a new game and re-drive it with the actions of the replay
+    #[wasm_bindgen(js_name = "playReplay")]
+    pub fn play_replay(&mut self, replay: String) -> Result<(), JsValue> {
+        let replay = Replay::decode(&replay).map_err(CoreError::from)?;
+        self.with_new_world(|session| session.play_replay(&replay))?;
+        Ok(())
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.session.borrow().seed()
+    }
+
+    /// Generate a dungeon without altering the world state
+    #[wasm_bindgen(js_name = "generateDungeon")]
+    pub fn generate_dungeon(&self, params: JsValue) -> Result<JsValue, JsValue> {
+        let params: Option<MapGenParams> = from_js(params, "map generation params")?;
+        let map = GameSession::generate_dungeon(params.unwrap_or_default())?;
+        Ok(serde_wasm_bindgen::to_value(&map).unwrap())
+    }
+
+    /// return the name of the icons (without the extension!)
+    pub fn icons(&self) -> JsValue {
+        let entries: Vec<_> = ICONS.iter().map(|(k, _x)| k).collect();
+        serde_wasm_bindgen::to_value(&entries).unwrap()
+    }
+
+    pub fn tick(&mut self, dt_ms: i32) {
+        self.session.borrow_mut().tick(dt_ms);
+    }
+
+    #[wasm_bindgen(js_name = "pushEvent")]
+    pub fn push_event(&mut self, event: JsValue) -> Result<(), JsValue> {
+        let event: InputEvent = from_js(event, "input event")?;
+        self.session.borrow_mut().push_event(event);
+        Ok(())
+    }
+
+    pub fn keymap(&self) -> JsValue {
+        let serializer = serde_wasm_bindgen::Serializer::json_compatible();
+        self.session
+            .borrow()
+            .keymap()
+            .serialize(&serializer)
+            .unwrap()
+    }
+
+    /// Fails if a key is bound to multiple actions
+    #[wasm_bindgen(js_name = "setKeymap")]
+    pub fn set_keymap(&mut self, keymap: JsValue) -> Result<(), JsValue> {
+        let keymap: Keymap = from_js(keymap, "keymap")?;
+        self.session.borrow_mut().set_keymap(keymap)?;
+        Ok(())
+    }
+
+    /// Encoded keymap, to be stored by the front-end
+    #[wasm_bindgen(js_name = "saveKeymap")]
+    pub fn save_keymap(&self) -> String {
+        self.session.borrow().keymap().encode()
+    }
+
+    #[wasm_bindgen(js_name = "loadKeymap")]
+    pub fn load_keymap(&mut self, pl: String) -> Result<(), JsValue> {
+        let keymap = Keymap::decode(&pl).map_err(CoreError::from)?;
+        self.session.borrow_mut().set_keymap(keymap)?;
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = "keymapPreset")]
+    pub fn keymap_preset(&self, preset: JsValue) -> Result<JsValue, JsValue> {
+        let preset: KeymapPreset = from_js(preset, "keymap preset")?;
+        let serializer = serde_wasm_bindgen::Serializer::json_compatible();
+        Ok(Keymap::preset(preset).serialize(&serializer).unwrap())
+    }
+
+    /// Messages of the keys bound to multiple actions
+    #[wasm_bindgen(js_name = "keymapConflicts")]
+    pub fn keymap_conflicts(&self, keymap: JsValue) -> Result<JsValue, JsValue> {
+        let keymap: Keymap = from_js(keymap, "keymap")?;
+        let conflicts: Vec<_> = keymap
+            .conflicts()
+            .iter()
+            .map(|conflict| conflict.to_string())
+            .collect();
+        Ok(serde_wasm_bindgen::to_value(&conflicts).unwrap())
+    }
+
+    /// Every action that can be bound to a key
+    pub fn actions(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&Action::ALL).unwrap()
+    }
+
+    #[wasm_bindgen(js_name = "getOutput")]
+    pub fn get_output(&self) -> JsValue {
+        let mut session = self.session.borrow_mut();
+        serde_wasm_bindgen::to_value(session.output()).unwrap()
+    }
+
+    #[wasm_bindgen(js_name = "getEquipment")]
+    pub fn get_equipment(&self) -> JsValue {
+        match self.session.borrow().equipment() {
+            // the UI expects a Map, go through json to preserve that
+            Some(equipment) => {
+                serde_wasm_bindgen::to_value(&serde_json::to_value(equipment).unwrap()).unwrap()
+            }
+            None => JsValue::null(),
+        }
+    }
+
+    #[wasm_bindgen(js_name = "getInventory")]
+    pub fn get_inventory(&self) -> JsValue {
+        let inventory = self.session.borrow().inventory();
+        serde_wasm_bindgen::to_value(&inventory).unwrap()
+    }
+
+    #[wasm_bindgen(js_name = "useItem")]
+    pub fn use_item(&mut self, id: JsValue) -> Result<(), JsValue> {
+        let id: EntityId = from_js(id, "entity id")?;
+        self.session.borrow_mut().use_item(id)?;
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = "unequipItem")]
+    pub fn unequip_item(&mut self, id: JsValue) -> Result<(), JsValue> {
+        let id: EntityId = from_js(id, "entity id")?;
+        self.session.borrow_mut().unequip_item(id)?;
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = "dropItem")]
+    pub fn drop_item(&mut self, id: JsValue) -> Result<(), JsValue> {
+        let id: EntityId = from_js(id, "entity id")?;
+        self.session.borrow_mut().drop_item(id)?;
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn wait(&mut self) {
+        self.session.borrow_mut().wait();
+    }
+
+    #[wasm_bindgen]
+    pub fn fire(&mut self) {
+        self.session.borrow_mut().fire();
+    }
+
+    #[wasm_bindgen(js_name = "autoExplore")]
+    pub fn auto_explore(&mut self) {
+        self.session.borrow_mut().auto_explore();
+    }
+
+    #[wasm_bindgen(js_name = "travelTo")]
+    pub fn travel_to(&mut self, x: i32, y: i32) {
+        self.session
+            .borrow_mut()
+            .travel(TravelGoal::To(Vec2::new(x, y)));
+    }
+
+    #[wasm_bindgen(js_name = "travelToStairs")]
+    pub fn travel_to_stairs(&mut self) {
+        self.session.borrow_mut().travel(TravelGoal::Stairs);
+    }
+
+    #[wasm_bindgen(js_name = "travelToShop")]
+    pub fn travel_to_shop(&mut self) {
+        self.session.borrow_mut().travel(TravelGoal::Shop);
+    }
+
+    #[wasm_bindgen]
+    pub fn rest(&mut self) {
+        self.session.borrow_mut().rest();
+    }
+
+    #[wasm_bindgen(js_name = "setTarget")]
+    pub fn set_target(&mut self, id: JsValue) -> Result<(), JsValue> {
+        let id: EntityId = from_js(id, "entity id")?;
+        self.session.borrow_mut().set_target(id)?;
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = "setSelection")]
+    pub fn set_selection(&mut self, id: JsValue) -> Result<(), JsValue> {
+        let id: EntityId = from_js(id, "entity id")?;
+        self.session.borrow_mut().set_selection(id)?;
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = "fetchEntity")]
+    pub fn fetch_entity(&self, id: JsValue) -> Result<JsValue, JsValue> {
+        let id: EntityId = from_js(id, "entity id")?;
+        let result = match self.session.borrow_mut().fetch_entity(id) {
+            Some(payload) => {
+                let serializer = serde_wasm_bindgen::Serializer::json_compatible();
+                payload.serialize(&serializer).unwrap()
+            }
+            None => JsValue::null(),
+        };
+        Ok(result)
+    }
+
+    #[wasm_bindgen(js_name = "setCanvas")]
+    pub fn set_canvas(&mut self, canvas: Option<web_sys::HtmlCanvasElement>) {
+        debug!("Setting canvas");
+
+        let dims = canvas
+            .as_ref()
+            .map(|canvas| (canvas.width(), canvas.height()));
+        let (width, height) = dims.unwrap_or_default();
+        let resources = RenderResources {
+            ctx: canvas
+                .as_ref()
+                .and_then(|c| c.get_context("2d").ok())
+                .and_then(|x| x) // unwrap the inner optional
+                .and_then(|x| x.dyn_into().ok()),
+            canvas,
+            width,
+            height,
+        };
+
+        if let Some(canvas) = resources.canvas.as_ref() {
+            let session = Rc::clone(&self.session);
+            let closure = Closure::<dyn FnMut(_)>::new(move |event: web_sys::MouseEvent| {
+                let [x, y] = [event.offset_x() as f64, event.offset_y() as f64];
+                let mut session = session.borrow_mut();
+                if let Some(pos) = canvas_to_grid(session.world(), x, y) {
+                    session.click(pos);
+                }
+            });
+            canvas
+                .add_event_listener_with_callback("mousedown", closure.as_ref().unchecked_ref())
+                .unwrap();
+            closure.forget();
+        }
+
+        let mut session = self.session.borrow_mut();
+        let w = session.world_mut();
+        w.insert_resource(resources);
+        // Path2d is a browser API, so the icons are only built once there is a canvas
+        if w.get_resource::<IconCollection>().is_none() {
+            w.insert_resource(compute_icons());
+        }
+
+        w.run_stage(
+            SystemStage::new("adhoc-render")
+                .with_system(systems::update_camera_pos)
+                .with_system(systems::update_light_map)
+                .with_system(systems::update_fov.after(systems::update_light_map))
+                .with_system(render_onto_canvas.after(systems::update_fov))
+                .build(),
+        )
+        .unwrap();
+    }
+
+    #[wasm_bindgen(js_name = "cancelItemUse")]
+    pub fn cancel_item_use(&mut self) {
+        self.session.borrow_mut().cancel_item_use();
+    }
+
+    pub fn save(&self) -> String {
+        self.session.borrow().save(now())
+    }
+
+    pub fn load(&mut self, pl: String) -> Result<(), JsValue> {
+        self.with_new_world(|session| session.load(&pl))?;
+        Ok(())
+    }
+
+    /// Read the header of an encoded save
+    #[wasm_bindgen(js_name = "peekSave")]
+    pub fn peek_save(&self, pl: String) -> Result<JsValue, JsValue> {
+        let header = crate::peek_save(&pl).map_err(CoreError::from)?;
+        Ok(serde_wasm_bindgen::to_value(&header).unwrap())
+    }
+
+    #[wasm_bindgen(js_name = "listSlots")]
+    pub fn list_slots(&self) -> Result<JsValue, JsValue> {
+        let slots = local_slots()?.list();
+        Ok(serde_wasm_bindgen::to_value(&slots).unwrap())
+    }
+
+    #[wasm_bindgen(js_name = "peekSlot")]
+    pub fn peek_slot(&self, slot: String) -> Result<JsValue, JsValue> {
+        let header = local_slots()?.peek(&slot).map_err(CoreError::from)?;
+        Ok(serde_wasm_bindgen::to_value(&header).unwrap())
+    }
+
+    #[wasm_bindgen(js_name = "saveSlot")]
+    pub fn save_slot(&self, slot: String) -> Result<(), JsValue> {
+        local_slots()?.save(&self.session.borrow(), &slot, now())?;
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = "loadSlot")]
+    pub fn load_slot(&mut self, slot: String) -> Result<(), JsValue> {
+        let slots = local_slots()?;
+        self.with_new_world(|session| slots.load(session, &slot))?;
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = "deleteSlot")]
+    pub fn delete_slot(&mut self, slot: String) -> Result<(), JsValue> {
+        local_slots()?.delete(&slot);
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = "setCharacterName")]
+    pub fn set_character_name(&mut self, name: String) {
+        self.session.borrow_mut().set_character_name(name);
+    }
+
+    /// Rules of the current game
+    pub fn ruleset(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.session.borrow().ruleset()).unwrap()
+    }
+
+    /// Rules used by the next new game
+    #[wasm_bindgen(js_name = "setRuleset")]
+    pub fn set_ruleset(&mut self, ruleset: JsValue) -> Result<(), JsValue> {
+        let ruleset: Ruleset = from_js(ruleset, "ruleset")?;
+        self.session.borrow_mut().set_ruleset(ruleset);
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = "setLevelupStat")]
+    pub fn set_levelup_stat(&mut self, stat: JsValue) -> Result<(), JsValue> {
+        let stat: DesiredStat = from_js(stat, "levelup stat")?;
+        self.session.borrow_mut().set_levelup_stat(stat);
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = "buyItem")]
+    pub fn buy_item(&mut self, item_idx: usize) -> Result<(), JsValue> {
+        self.session.borrow_mut().buy_item(item_idx)?;
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = "sellItem")]
+    pub fn sell_item(&mut self, id: JsValue) -> Result<(), JsValue> {
+        let id: EntityId = from_js(id, "entity id")?;
+        self.session.borrow_mut().sell_item(id)?;
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    js_sys::Date::now() as u64
+}
+
+/// Save slots in the browser's localStorage
+pub struct LocalStorage(web_sys::Storage);
+
+impl SlotStorage for LocalStorage {
+    fn read(&self, key: &str) -> Option<String> {
+        self.0.get_item(key).ok().flatten()
+    }
+
+    fn write(&mut self, key: &str, value: &str) -> Result<(), String> {
+        self.0
+            .set_item(key, value)
+            .map_err(|err| format!("Failed to write localStorage: {err:?}"))
+    }
+
+    fn remove(&mut self, key: &str) {
+        let _ = self.0.remove_item(key);
+    }
+
+    fn keys(&self) -> Vec<String> {
+        let len = self.0.length().unwrap_or(0);
+        (0..len)
+            .filter_map(|i| self.0.key(i).ok().flatten())
+            .collect()
+    }
+}
+
+fn local_slots() -> Result<SaveSlots<LocalStorage>, CoreError> {
+    let storage = web_sys::window()
+        .and_then(|w| w.local_storage().ok().flatten())
+        .ok_or_else(|| CoreError::Storage("localStorage is not available".to_owned()))?;
+    Ok(SaveSlots::new(LocalStorage(storage)))
+}
+
+/// Convert a click on the canvas into a grid position
+fn canvas_to_grid(world: &World, x: f64, y: f64) -> Option<Vec2> {
+    let res = world.get_resource::<RenderResources>()?;
+    let viewport = world.get_resource::<Viewport>()?;
+    let camera_pos = world.get_resource::<CameraPos>()?;
+
+    let width = res.width as f64;
+    let height = res.height as f64;
+    let cell_size = canvas_cell_size(width, height, viewport.0);
+
+    let pos = Vec2::new(
+        remap_f64(0.0, width, 0.0, viewport.0.x as f64 * 2.0, x) as i32,
+        remap_f64(0.0, height, 0.0, viewport.0.y as f64 * 2.0, y) as i32,
+    ) + camera_pos.0
+        - viewport.0;
+
+    debug!(
+        pos = tracing::field::debug(pos),
+        camera_pos = tracing::field::debug(camera_pos.0),
+        viewport = tracing::field::debug(viewport.0),
+        cell_size = tracing::field::debug(cell_size),
+        "clicked on grid position",
+    );
+    Some(pos)
+}
+
+fn canvas_cell_size(width: f64, height: f64, viewport: Vec2) -> f64 {
+    height.min(width) / (viewport.y * 2) as f64
+}
+
+/// Opacity of the light's color on fully lit cells
+const LIGHT_TINT: f64 = 0.3;
+
+pub fn render_onto_canvas(
+    res: Option<ResMut<RenderResources>>,
+    grid: Res<Grid<Stuff>>,
+    viewport: Res<Viewport>,
+    camera_pos: Res<CameraPos>,
+    visible: Res<Visible>,
+    explored: Res<Explored>,
+    light_map: Res<LightMap>,
+    stuff: Query<(Option<&StaticVisibility>, &Icon, Option<&Color>)>,
+    icons: Option<Res<IconCollection>>,
+) {
+    // sessions driven natively, e.g. in tests, have nothing to render onto
+    let (Some(mut res), Some(icons)) = (res, icons) else {
+        return;
+    };
+    res.update_dims();
+    let width = res.width as f64;
+    let height = res.height as f64;
+    let Some(ctx) = res.ctx.as_mut() else {
+        debug!("No rendering context, skipping render");
+        return;
+    };
+
+    let min = camera_pos.0 - viewport.0;
+    let max = camera_pos.0 + viewport.0;
+
+    let cell_size = canvas_cell_size(width, height, viewport.0);
+    let icon_scale = cell_size / 512.0;
+
+    let black = "black";
+    let darkgrey = "darkgrey";
+    let white = "white";
+
+    ctx.set_fill_style_str("gray");
+    ctx.fill_rect(0.0, 0.0, width, height);
+
+    for y in min.y.max(0)..(max.y + 1).min(grid.height()) {
+        for x in min.x.max(0)..(max.x + 1).min(grid.width()) {
+            let pos = Vec2::new(x, y);
+            let explored = explored.0[pos];
+
+            if !explored {
+                continue;
+            }
+
+            let visible = visible.0[pos];
+
+            let render_pos = pos - min;
+            let render_x = render_pos.x as f64 * cell_size;
+            let render_y = render_pos.y as f64 * cell_size;
+
+            if visible {
+                ctx.set_fill_style_str(&black);
+            } else {
+                ctx.set_fill_style_str(&darkgrey);
+            }
+            // either the icon background or the empty space
+            ctx.fill_rect(render_x, render_y, cell_size, cell_size);
+
+            match grid[pos].and_then(|id| stuff.fetch(id).map(|x| (id, x))) {
+                Some((_id, (static_vis_tag, icon, color)))
+                    if visible || static_vis_tag.is_some() =>
+                {
+                    ctx.fill_rect(render_x, render_y, cell_size, cell_size);
+                    match icons.0.get(icon.0) {
+                        Some(icon) => {
+                            match color {
+                                Some(Color(color)) => {
+                                    ctx.set_fill_style_str(color);
+                                }
+                                None => {
+                                    ctx.set_fill_style_str(white);
+                                }
+                            }
+                            ctx.save();
+                            ctx.translate(render_x, render_y).unwrap();
+                            ctx.scale(icon_scale, icon_scale).unwrap();
+                            ctx.fill_with_path_2d(icon);
+                            ctx.restore();
+                        }
+                        None => {
+                            debug!("Failed to fetch icon");
+                            // if icon can not be fetched
+                            if let Some(Color(color)) = color {
+                                ctx.set_fill_style_str(color);
+                            }
+                            ctx.fill_rect(render_x, render_y, cell_size, cell_size);
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            if visible {
+                // tint by the color of the light
+                let light = light_map.0[pos];
+                if let Some(color) = light.color {
+                    ctx.set_global_alpha(LIGHT_TINT * light.intensity as f64 / 255.0);
+                    ctx.set_fill_style_str(color);
+                    ctx.fill_rect(render_x, render_y, cell_size, cell_size);
+                    ctx.set_global_alpha(1.0);
+                }
+            }
+        }
+    }
+}